@@ -1,10 +1,13 @@
 extern crate ots_core;
 extern crate uniffi;
 
+use ots_core::attestation::{ChainAttestationResult, VerifierRegistry};
 use ots_core::client;
-use ots_core::client::BitcoinAttestationResult;
+use ots_core::client::{BitcoinAttestationResult, Verification};
 use ots_core::error::Error as OtsError;
+use ots_core::network::Network;
 use ots_core::opentimestamps::{ser::DigestType, DetachedTimestampFile};
+use ots_core::whitelist::UrlWhitelist;
 use std::io::Cursor;
 use std::time::Duration;
 
@@ -19,6 +22,7 @@ pub fn stamps(
         digest_type,
         calendar_urls,
         Some(Duration::from_secs(timeout.unwrap_or(5))),
+        Network::Bitcoin,
     )?;
     let mut buffers = vec![vec![]];
     for (buffer, ots) in buffers.iter_mut().zip(otss) {
@@ -36,7 +40,12 @@ pub fn info(ots: Vec<u8>) -> Result<String, OtsError> {
 pub fn upgrade(ots: Vec<u8>, calendar_urls: Option<Vec<String>>) -> Result<Vec<u8>, OtsError> {
     let cursor = Cursor::new(ots);
     let mut ots = DetachedTimestampFile::from_reader(cursor).unwrap();
-    client::upgrade(&mut ots, calendar_urls)?;
+    let outcome = client::upgrade(&mut ots, calendar_urls, &UrlWhitelist::default())?;
+    if !outcome.changed() {
+        if let Some((_, e)) = outcome.errors.into_iter().next() {
+            return Err(e);
+        }
+    }
     let mut buffer = vec![];
     ots.to_writer(&mut buffer).unwrap();
     Ok(buffer)
@@ -45,7 +54,31 @@ pub fn upgrade(ots: Vec<u8>, calendar_urls: Option<Vec<String>>) -> Result<Vec<u
 pub fn verify(ots: Vec<u8>) -> Result<BitcoinAttestationResult, OtsError> {
     let cursor = Cursor::new(ots);
     let ots = DetachedTimestampFile::from_reader(cursor).unwrap();
-    client::verify(ots, None)
+    client::verify(ots, None, Network::Bitcoin)
+}
+
+pub fn verify_file(ots: Vec<u8>, file: Vec<u8>) -> Result<BitcoinAttestationResult, OtsError> {
+    let cursor = Cursor::new(ots);
+    let ots = DetachedTimestampFile::from_reader(cursor).map_err(OtsError::InvalidOts)?;
+    client::verify_file(&ots, Cursor::new(file))?;
+    client::verify(ots, None, Network::Bitcoin)
+}
+
+pub fn verify_digest(ots: Vec<u8>, digest: Vec<u8>) -> Result<BitcoinAttestationResult, OtsError> {
+    let cursor = Cursor::new(ots);
+    let ots = DetachedTimestampFile::from_reader(cursor).map_err(OtsError::InvalidOts)?;
+    client::verify_digest(&ots, &digest)?;
+    client::verify(ots, None, Network::Bitcoin)
+}
+
+pub fn verify_chains(
+    ots: Vec<u8>,
+    litecoin_sources: Vec<String>,
+) -> Result<Verification, OtsError> {
+    let cursor = Cursor::new(ots);
+    let ots = DetachedTimestampFile::from_reader(cursor).map_err(OtsError::InvalidOts)?;
+    let registry = VerifierRegistry::with_litecoin_sources(&litecoin_sources)?;
+    client::verify_chains(ots, None, Network::Bitcoin, &registry)
 }
 
 uniffi::include_scaffolding!("ots");
@@ -1,12 +1,28 @@
 // Copyright (C) 2024 The OpenTimestamps developers
 
 use camino::Utf8PathBuf;
+use chrono_tz::Tz;
 use clap::{Args, Parser, Subcommand};
+use ots_core::network::Network;
 use std::time::Duration;
 
 #[derive(PartialEq, Clone, Debug, Parser)]
-#[command(author, version, about, long_about = None)]
+#[command(
+    author,
+    version,
+    about,
+    long_about = None,
+    after_help = "Exit codes: 0 success or verified, 1 failed, 2 bad input, 3 pending (not in Bitcoin yet, or fewer confirmations than --min_confirmations), 4 network error"
+)]
 pub struct CliOpts {
+    /// Print results of stamp, upgrade, verify and info as JSON
+    #[clap(long, global = true)]
+    pub json: bool,
+
+    /// Bitcoin network: mainnet, testnet, signet or regtest
+    #[clap(long, global = true, default_value = "mainnet", value_parser = parse_network)]
+    pub network: Network,
+
     /// The bitcoin options
     #[clap(flatten)]
     pub bitcoin: Option<BitcoinOpts>,
@@ -31,16 +47,42 @@ pub enum CliCommand {
         #[clap(name = "timeout", short, long)]
         #[arg(value_parser = parse_duration)]
         timeout: Option<Duration>,
+        /// Overwrite existing timestamp files
+        #[clap(long)]
+        force: bool,
+        /// Write a JSON receipt of the stamping next to each timestamp, as FILE.ots.json
+        #[clap(long)]
+        receipt: bool,
     },
 
     #[clap(long_about = "Upgrade remote calendar timestamps to be locally verifiable")]
     Upgrade {
-        /// Existing timestamp(s); moved to FILE.bak
+        /// Existing timestamp(s); a changed one is first copied to FILE.bak, unless --no_backup
         #[clap(name = "files", required = true, num_args = 1.., value_delimiter = ' ')]
         files: Vec<Utf8PathBuf>,
         /// Override calendars in timestamp
         #[clap(name = "calendar_url", short, long)]
         calendar: Option<Vec<String>>,
+        /// Wait until a Bitcoin attestation replaces every pending attestation
+        #[clap(long)]
+        wait: bool,
+        /// Seconds between two upgrade attempts while waiting.
+        #[clap(name = "wait_interval", long, default_value = "30")]
+        #[arg(value_parser = parse_duration)]
+        wait_interval: Duration,
+        /// Seconds after which to give up waiting, for all the files together.
+        #[clap(name = "max_wait", long)]
+        #[arg(value_parser = parse_duration)]
+        max_wait: Option<Duration>,
+        /// Report what would be upgraded without writing any file
+        #[clap(long)]
+        dry_run: bool,
+        /// Overwrite changed timestamps without copying them to FILE.bak first
+        #[clap(long)]
+        no_backup: bool,
+        /// The calendar whitelist options
+        #[clap(flatten)]
+        whitelist: WhitelistOpts,
     },
 
     #[clap(long_about = "Show information on a timestamp")]
@@ -50,17 +92,172 @@ pub enum CliCommand {
         file: Utf8PathBuf,
     },
 
-    #[clap(long_about = "Verify a timestamp")]
+    #[clap(
+        long_about = "Verify timestamps, given as files or directories searched for .ots files"
+    )]
     Verify {
-        /// Timestamp file
-        #[clap(name = "timestamp", required = true, index = 1)]
-        timestamp: Utf8PathBuf,
-        /// Specify target file explicitly
-        #[clap(name = "target", index = 2)]
+        /// Timestamp file(s) or directories, or a timestamp followed by its target file
+        #[clap(name = "timestamps", required = true, num_args = 1..)]
+        timestamps: Vec<Utf8PathBuf>,
+        /// Specify target file explicitly, for a single timestamp
+        #[clap(name = "target", short = 'f', long)]
         target: Option<Utf8PathBuf>,
-        /// Verify a (hex-encoded) digest rather than a file
-        #[clap(name = "digest", index = 3)]
+        /// Verify a (hex-encoded) digest rather than a file, for a single timestamp
+        #[clap(name = "digest", short = 'd', long, conflicts_with = "target")]
         digest: Option<String>,
+        /// Verify a timestamp made by git-extract against the git blob of the target file
+        #[clap(name = "git_blob", long, conflicts_with = "digest")]
+        git_blob: bool,
+        /// Timestamps read and hashed concurrently when verifying many
+        #[clap(name = "jobs", short = 'j', long, default_value = "4")]
+        jobs: usize,
+        /// The block header source options
+        #[clap(flatten)]
+        sources: HeaderSourceOpts,
+        /// Confirmations below which an attestation is only provisional, reported as pending
+        #[clap(name = "min_confirmations", long, default_value = "6")]
+        min_confirmations: u32,
+        /// Show times in this timezone rather than UTC, e.g. Europe/Rome
+        #[clap(name = "timezone", long)]
+        timezone: Option<Tz>,
+    },
+
+    #[clap(long_about = "Maintain a local chain of Bitcoin block headers")]
+    Headers {
+        /// Header chain file
+        #[clap(long, env = "OTS_HEADER_CHAIN", default_value = "ots_headers.dat")]
+        chain: Utf8PathBuf,
+        /// Checkpoint a new chain starts from, as HEIGHT:HASH
+        #[clap(long)]
+        checkpoint: Option<String>,
+        /// Import raw 80-byte block headers from a file
+        #[clap(long)]
+        import: Option<Utf8PathBuf>,
+        /// Height of the first imported header
+        #[clap(long, requires = "import")]
+        start_height: Option<u32>,
+        /// Download new headers from the Bitcoin node or header source
+        #[clap(long)]
+        sync: bool,
+        /// Electrum server (tcp://, ssl://) or Esplora API (https://) to sync from
+        #[clap(long, alias = "electrum")]
+        source: Option<String>,
+    },
+
+    #[clap(long_about = "Track timestamps to be upgraded in the background")]
+    Watch {
+        /// Existing timestamp(s)
+        #[clap(name = "files", required = true, num_args = 1.., value_delimiter = ' ')]
+        files: Vec<Utf8PathBuf>,
+        /// Pending timestamps store
+        #[clap(long, env = "OTS_STORE", default_value = "ots_pending.json")]
+        store: Utf8PathBuf,
+    },
+
+    #[clap(long_about = "Upgrade tracked timestamps until they are attested in Bitcoin")]
+    Daemon {
+        /// Pending timestamps store
+        #[clap(long, env = "OTS_STORE", default_value = "ots_pending.json")]
+        store: Utf8PathBuf,
+        /// Seconds between two checks of the store.
+        #[clap(name = "interval", short, long, default_value = "60")]
+        #[arg(value_parser = parse_duration)]
+        interval: Duration,
+        /// Override calendars in timestamp
+        #[clap(name = "calendar_url", short, long)]
+        calendar: Option<Vec<String>>,
+        /// Run a single round of upgrades and exit
+        #[clap(long)]
+        once: bool,
+        /// The calendar whitelist options
+        #[clap(flatten)]
+        whitelist: WhitelistOpts,
+    },
+
+    #[clap(long_about = "Show the status of tracked timestamps")]
+    Status {
+        /// Pending timestamps store
+        #[clap(long, env = "OTS_STORE", default_value = "ots_pending.json")]
+        store: Utf8PathBuf,
+    },
+
+    #[clap(
+        long_about = "Timestamp signed git commits and tags, used as git gpg.program through a wrapper script"
+    )]
+    Git {
+        /// GnuPG program to sign and verify with
+        #[clap(long, default_value = "gpg")]
+        gpg_program: String,
+        /// Create timestamp with the aid of a remote calendar. May be specified multiple times.
+        #[clap(name = "calendar_url", short, long)]
+        calendar: Option<Vec<String>>,
+        /// Timeout before giving up on a calendar.
+        #[clap(name = "timeout", short, long)]
+        #[arg(value_parser = parse_duration)]
+        timeout: Option<Duration>,
+        /// Arguments passed by git to GnuPG
+        #[clap(trailing_var_arg = true, allow_hyphen_values = true)]
+        gpg_args: Vec<String>,
+    },
+
+    #[clap(
+        long_about = "Run a calendar aggregating digests into batches timestamped by upstream calendars"
+    )]
+    Serve {
+        /// Address to listen on
+        #[clap(long, default_value = "127.0.0.1:14788")]
+        bind: String,
+        /// URL clients reach the server at, used in pending attestations. Clients only upgrade from HTTPS calendars unless they allow insecure ones, so put the server behind a TLS proxy or have clients pass --allow-insecure-calendars
+        #[clap(long)]
+        public_url: String,
+        /// Directory the queued digests and batches are persisted in
+        #[clap(long, env = "OTS_SERVER_DIR", default_value = "ots_server")]
+        data_dir: Utf8PathBuf,
+        /// Upstream calendar batch tips are submitted to. May be specified multiple times.
+        #[clap(name = "calendar_url", short, long)]
+        calendar: Option<Vec<String>>,
+        /// Timeout before giving up on an upstream calendar.
+        #[clap(name = "timeout", short, long, default_value = "5")]
+        #[arg(value_parser = parse_duration)]
+        timeout: Duration,
+        /// Seconds between two batches.
+        #[clap(name = "batch_interval", long, default_value = "1")]
+        #[arg(value_parser = parse_duration)]
+        batch_interval: Duration,
+        /// Commit batch tips to Bitcoin through the wallet of the Bitcoin node instead of upstream calendars
+        #[clap(long, conflicts_with = "calendar_url")]
+        anchor: bool,
+        /// Confirmations of an anchor transaction before its proofs are served
+        #[clap(long, default_value = "6", requires = "anchor")]
+        confirmations: u32,
+        /// Seconds between two checks for upgrades of the batch tips.
+        #[clap(name = "upgrade_interval", long, default_value = "60")]
+        #[arg(value_parser = parse_duration)]
+        upgrade_interval: Duration,
+        /// Days the proofs of a complete batch are served for
+        #[clap(name = "retention_days", long, default_value = "90")]
+        retention_days: u64,
+    },
+
+    #[clap(
+        name = "git-extract",
+        long_about = "Extract the timestamp of a file from a timestamped git commit"
+    )]
+    GitExtract {
+        /// Path within the commit tree of the file to extract
+        path: String,
+        /// Timestamped commit containing the file
+        #[clap(default_value = "HEAD")]
+        commit: String,
+        /// Git repository to read the commit from
+        #[clap(short, long, default_value = ".")]
+        repository: Utf8PathBuf,
+        /// Filename to write the timestamp to, defaults to the file name with .git.ots appended
+        #[clap(short, long)]
+        output: Option<Utf8PathBuf>,
+        /// Overwrite an existing timestamp file
+        #[clap(long)]
+        force: bool,
     },
 }
 
@@ -69,6 +266,16 @@ fn parse_duration(arg: &str) -> Result<std::time::Duration, std::num::ParseIntEr
     Ok(std::time::Duration::from_secs(seconds))
 }
 
+fn parse_network(arg: &str) -> Result<Network, String> {
+    match arg {
+        "mainnet" | "bitcoin" => Ok(Network::Bitcoin),
+        "testnet" => Ok(Network::Testnet),
+        "signet" => Ok(Network::Signet),
+        "regtest" => Ok(Network::Regtest),
+        _ => Err(format!("unknown network {}", arg)),
+    }
+}
+
 #[derive(PartialEq, Clone, Debug, Args)]
 pub struct BitcoinOpts {
     /// Bitcoin node
@@ -80,4 +287,45 @@ pub struct BitcoinOpts {
     /// Bitcoin password
     #[clap(long, global = true)]
     pub bitcoin_password: Option<String>,
+    /// Bitcoin cookie file, used instead of username and password
+    #[clap(long, global = true)]
+    pub bitcoin_cookie_file: Option<Utf8PathBuf>,
+}
+
+#[derive(PartialEq, Clone, Debug, Args)]
+pub struct WhitelistOpts {
+    /// Add a calendar to the whitelist; `*` wildcards and `.domain` suffixes are accepted. May be specified multiple times.
+    #[clap(long = "whitelist")]
+    pub whitelist: Vec<String>,
+    /// Do not include the default calendar whitelist
+    #[clap(long)]
+    pub no_default_whitelist: bool,
+    /// Contact calendars found in timestamps even if they are not whitelisted
+    #[clap(long)]
+    pub allow_unknown_calendars: bool,
+    /// Accept calendars not served over HTTPS
+    #[clap(long)]
+    pub allow_insecure_calendars: bool,
+}
+
+#[derive(PartialEq, Clone, Debug, Args)]
+pub struct HeaderSourceOpts {
+    /// Verify offline against a local header chain
+    #[clap(name = "header_chain", long)]
+    pub header_chain: Option<Utf8PathBuf>,
+    /// Verify offline against a file of raw 80-byte headers or of `HEIGHT HEADER_HEX` lines
+    #[clap(long, conflicts_with = "header_chain")]
+    pub headers: Option<Utf8PathBuf>,
+    /// Height of the first header of a raw headers file
+    #[clap(long, default_value = "0")]
+    pub headers_start_height: u32,
+    /// Cache the block headers fetched from each header source in this directory, across runs
+    #[clap(long, env = "OTS_HEADER_CACHE")]
+    pub header_cache: Option<Utf8PathBuf>,
+    /// Electrum server (tcp://, ssl://) or Esplora API (https://) to get block headers from. May be specified multiple times to require all sources to agree.
+    #[clap(name = "source", long, alias = "electrum")]
+    pub sources: Vec<String>,
+    /// Electrum server or Esplora API to verify Litecoin attestations against. May be specified multiple times.
+    #[clap(long)]
+    pub litecoin_source: Vec<String>,
 }
@@ -0,0 +1,38 @@
+// Copyright (C) 2024 The OpenTimestamps developers
+
+use camino::Utf8Path;
+use opentimestamps::DetachedTimestampFile;
+use ots_core::atomic;
+use ots_core::error::Error;
+use std::fs;
+
+/// Write a timestamp to `path` atomically, so a crash never leaves a
+/// truncated or missing `.ots` behind. Without `overwrite`, an existing
+/// proof is never replaced, even one written concurrently.
+pub(crate) fn write_ots(
+    path: &Utf8Path,
+    ots: &DetachedTimestampFile,
+    overwrite: bool,
+) -> Result<(), Error> {
+    let write = |file: &mut fs::File| ots.to_writer(file).map_err(|_| Error::IOError);
+    if overwrite {
+        return atomic::write_with(path, write);
+    }
+    atomic::write_new_with(path, write).map_err(|e| match e {
+        Error::AlreadyExists(_) => {
+            Error::AlreadyExists(format!("{} (use --force to overwrite)", path))
+        }
+        e => e,
+    })
+}
+
+/// Copy `path` to `backup`, refusing to replace an existing backup.
+pub(crate) fn backup(path: &Utf8Path, backup: &Utf8Path) -> Result<(), Error> {
+    if backup.exists() {
+        return Err(Error::AlreadyExists(backup.to_string()));
+    }
+    fs::copy(path, backup).map_err(|_| Error::IOError)?;
+    fs::File::open(backup)
+        .and_then(|file| file.sync_all())
+        .map_err(|_| Error::IOError)
+}
@@ -0,0 +1,227 @@
+// Copyright (C) 2024 The OpenTimestamps developers
+
+use log::info;
+use opentimestamps::ser::DigestType;
+use opentimestamps::DetachedTimestampFile;
+use ots_core::error::Error;
+use ots_core::git::{embed_timestamp, extract_timestamp, signed_object_digest};
+use ots_core::network::Network;
+use std::fs;
+use std::io::{ErrorKind, Read, Write};
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Run GnuPG with `args`, feeding it `input`, returning its stdout.
+fn gpg(program: &str, args: &[String], input: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .spawn()
+        .map_err(|e| Error::Generic(format!("Could not run {}: {}", program, e)))?;
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(input)
+        .map_err(|_| Error::IOError)?;
+    let output = child.wait_with_output().map_err(|_| Error::IOError)?;
+    if !output.status.success() {
+        // Forward GnuPG output, git parses it on failures too
+        std::io::stdout()
+            .write_all(&output.stdout)
+            .map_err(|_| Error::IOError)?;
+        return Err(Error::Generic(format!(
+            "{} failed: {}",
+            program, output.status
+        )));
+    }
+    Ok(output.stdout)
+}
+
+/// Options of the wrapper, besides the GnuPG arguments.
+pub(crate) struct GitOpts {
+    pub(crate) gpg_program: String,
+    pub(crate) calendar_urls: Option<Vec<String>>,
+    pub(crate) timeout: Option<Duration>,
+    pub(crate) network: Network,
+    pub(crate) bitcoin: Option<bitcoincore_rpc::Client>,
+}
+
+/// Act as `gpg.program`: sign and timestamp when git signs, verify the
+/// signature and the timestamp when git verifies, pass through otherwise.
+///
+/// As in the Python client, the timestamp is armored after the PGP signature
+/// and stripped before GnuPG verifies it.
+pub(crate) fn gpg_wrapper(opts: GitOpts, gpg_args: Vec<String>) -> Result<(), Error> {
+    let mut input = vec![];
+    let signing = is_sign(&gpg_args);
+    let verify = gpg_args.iter().position(|arg| arg == "--verify");
+    if signing || verify.is_some() {
+        std::io::stdin()
+            .read_to_end(&mut input)
+            .map_err(|_| Error::IOError)?;
+    }
+    match verify {
+        Some(index) => verify_signature(opts, gpg_args, index + 1, &input),
+        None if signing => sign(opts, &gpg_args, &input),
+        None => {
+            let status = Command::new(&opts.gpg_program)
+                .args(&gpg_args)
+                .status()
+                .map_err(|_| Error::IOError)?;
+            if !status.success() {
+                return Err(Error::Generic(format!("gpg failed: {}", status)));
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Whether git asks for a detached signature, as with `-bsau KEY`.
+fn is_sign(gpg_args: &[String]) -> bool {
+    gpg_args
+        .iter()
+        .any(|arg| arg == "--detach-sign" || (arg.starts_with("-b") && arg.contains('s')))
+}
+
+/// Sign and timestamp `object`. Failing to timestamp fails the signature, so
+/// that git does not record an object without the timestamp asked for.
+fn sign(opts: GitOpts, gpg_args: &[String], object: &[u8]) -> Result<(), Error> {
+    let signature = gpg(&opts.gpg_program, gpg_args, object)?;
+    let digest = signed_object_digest(object, &signature);
+    let timestamps = ots_core::client::stamps(
+        vec![digest],
+        DigestType::Sha256,
+        opts.calendar_urls,
+        opts.timeout,
+        opts.network,
+    )
+    .map_err(|e| Error::Generic(format!("Could not timestamp the signature: {}", e)))?;
+    let signature = embed_timestamp(&signature, &timestamps[0].timestamp)?;
+    std::io::stdout()
+        .write_all(&signature)
+        .map_err(|_| Error::IOError)
+}
+
+/// Create a new directory in the temporary directory, only accessible to
+/// the user. Creating it fails rather than reuse a file or a symlink planted
+/// under the same name, so another name is tried.
+fn private_dir() -> Result<PathBuf, Error> {
+    let mut builder = fs::DirBuilder::new();
+    #[cfg(unix)]
+    std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.subsec_nanos())
+        .unwrap_or(0);
+    for attempt in 0..100u32 {
+        let dir = std::env::temp_dir().join(format!(
+            "ots-git-{}-{:08x}",
+            std::process::id(),
+            nanos.wrapping_add(attempt)
+        ));
+        match builder.create(&dir) {
+            Ok(()) => return Ok(dir),
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+            Err(_) => return Err(Error::IOError),
+        }
+    }
+    Err(Error::IOError)
+}
+
+fn verify_signature(
+    opts: GitOpts,
+    mut gpg_args: Vec<String>,
+    signature_index: usize,
+    object: &[u8],
+) -> Result<(), Error> {
+    let signature_path = gpg_args
+        .get(signature_index)
+        .ok_or(Error::Generic("Missing signature file".to_string()))?
+        .clone();
+    let signature = fs::read(&signature_path).map_err(|_| Error::InvalidFile)?;
+    let (timestamp, pgp) = extract_timestamp(object, &signature)?;
+
+    // GnuPG would reject the timestamp within the armor, verify the bare signature
+    let dir = private_dir()?;
+    let pgp_path = dir.join("signature.sig");
+    let written = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&pgp_path)
+        .and_then(|mut file| file.write_all(&pgp));
+    let result = match written {
+        Ok(()) => {
+            gpg_args[signature_index] = pgp_path.to_string_lossy().to_string();
+            gpg(&opts.gpg_program, &gpg_args, object)
+        }
+        Err(_) => Err(Error::IOError),
+    };
+    let _ = fs::remove_dir_all(&dir);
+    std::io::stdout()
+        .write_all(&result?)
+        .map_err(|_| Error::IOError)?;
+
+    // git reads GnuPG status from stdout, report on stderr
+    let Some(timestamp) = timestamp else {
+        eprintln!("ots: No timestamp found");
+        return Ok(());
+    };
+    let ots = DetachedTimestampFile {
+        digest_type: DigestType::Sha256,
+        timestamp,
+    };
+    if ots_core::client::bitcoin_height(&ots).is_none() {
+        eprintln!(
+            "ots: Timestamp pending confirmation at {}",
+            ots_core::client::pending_uris(&ots).join(", ")
+        );
+        return Ok(());
+    }
+    let attestation = ots_core::client::verify(ots, opts.bitcoin, opts.network)?;
+    info!("Verified git timestamp: {}", attestation);
+    eprintln!("ots: Success! {}", attestation);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn sign_arguments() {
+        assert!(is_sign(&args(&["--status-fd=2", "-bsau", "ABCD"])));
+        assert!(is_sign(&args(&["--detach-sign", "--armor"])));
+        assert!(!is_sign(&args(&[
+            "--status-fd=1",
+            "--keyid-format=long",
+            "--verify",
+            "/tmp/sig",
+            "-"
+        ])));
+        assert!(!is_sign(&args(&["--list-keys"])));
+    }
+
+    #[test]
+    fn private_signature_dir() {
+        let first = private_dir().unwrap();
+        let second = private_dir().unwrap();
+        assert_ne!(first, second);
+        assert_eq!(fs::read_dir(&first).unwrap().count(), 0);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&first).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o700);
+        }
+        fs::remove_dir(&first).unwrap();
+        fs::remove_dir(&second).unwrap();
+    }
+}
@@ -3,201 +3,1363 @@
 extern crate bitcoincore_rpc;
 extern crate camino;
 extern crate chrono;
+extern crate chrono_tz;
 extern crate clap;
 extern crate electrum_client;
 extern crate env_logger;
 extern crate log;
 extern crate opentimestamps;
 extern crate ots_core;
+extern crate serde;
+extern crate serde_json;
+extern crate tiny_http;
 
 mod args;
+mod atomic;
+mod git;
+mod output;
+mod server;
 
 use crate::args::*;
+use crate::output::{Report, Status};
 use bitcoincore_rpc::{Auth, Client};
-use camino::Utf8PathBuf;
+use camino::{Utf8Path, Utf8PathBuf};
+use chrono_tz::Tz;
 use clap::Parser;
 use electrum_client::bitcoin::hex::FromHex;
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
 use opentimestamps::hex::Hexed;
 use opentimestamps::ser::DigestType;
-use opentimestamps::{op::Op, DetachedTimestampFile};
+use opentimestamps::DetachedTimestampFile;
+use ots_core::aggregator::Backend;
+use ots_core::anchor::BitcoinAnchor;
+use ots_core::attestation::{AttestationKind, ChainAttestationResult, VerifierRegistry};
+use ots_core::client::{timestamp_to_iso8601, timestamp_to_iso8601_in, BitcoinAttestationResult};
 use ots_core::error::Error;
-use std::io::Write;
+use ots_core::extensions::TimestampExtension;
+use ots_core::header_cache::{CachedSource, HeaderCache, DEFAULT_CACHE_CAPACITY};
+use ots_core::header_chain::{default_checkpoints, Checkpoint, HeaderChain, HeadersFile};
+use ots_core::headers::{source_from_url, BitcoindSource, BlockHeaderSource, CrossCheckSource};
+use ots_core::network::{self, default_electrum, default_rpc_port, Network};
+use ots_core::pending::{PendingEntry, PendingStore};
+use ots_core::receipt::StampReceipt;
+use ots_core::scheduler::Scheduler;
+use ots_core::whitelist::UrlWhitelist;
+use serde::Serialize;
+use std::io::BufReader;
 use std::path::Path;
-use std::time::Duration;
-use std::{fs, io::Read};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use std::{fs, thread};
 
 fn main() {
     env_logger::init();
 
     let cli_opts: CliOpts = CliOpts::parse();
+    let json = cli_opts.json;
 
-    match handle_command(cli_opts) {
-        Ok(_result) => {}
-        Err(e) => error!("{}", e.to_string()),
-    }
+    let status = match handle_command(cli_opts) {
+        Ok(report) => {
+            report.print(json);
+            report.status
+        }
+        Err(e) => {
+            Report::print_error(&e, json);
+            Status::of(&e)
+        }
+    };
+    std::process::exit(status as i32);
 }
 
-pub(crate) fn handle_command(cli_opts: CliOpts) -> Result<(), Error> {
+pub(crate) fn handle_command(cli_opts: CliOpts) -> Result<Report, Error> {
     let result = match cli_opts.command {
         CliCommand::Info { file } => info(file),
         CliCommand::Stamp {
             files,
             calendar,
             timeout,
-        } => stamps(files, calendar, timeout),
-        CliCommand::Upgrade { files, calendar } => upgrade(files, calendar),
+            force,
+            receipt,
+        } => stamps(files, calendar, timeout, force, receipt, cli_opts.network),
+        CliCommand::Upgrade {
+            files,
+            calendar,
+            wait,
+            wait_interval,
+            max_wait,
+            dry_run,
+            no_backup,
+            whitelist,
+        } => {
+            let wait = wait.then_some(Wait {
+                interval: wait_interval,
+                deadline: max_wait.map(|max| Instant::now() + max),
+            });
+            upgrade(
+                files,
+                calendar,
+                url_whitelist(whitelist),
+                wait,
+                dry_run,
+                no_backup,
+            )
+        }
         CliCommand::Verify {
+            timestamps,
             target,
             digest,
-            timestamp,
-        } => verify(target, digest, timestamp, cli_opts.bitcoin),
+            git_blob,
+            jobs,
+            sources,
+            min_confirmations,
+            timezone,
+        } => {
+            let node = Node {
+                network: cli_opts.network,
+                opts: cli_opts.bitcoin,
+            };
+            let (timestamps, target) = positional_target(timestamps, target)?;
+            let mut files = timestamp_files(timestamps)?;
+            if files.len() == 1 {
+                verify(
+                    target,
+                    digest,
+                    git_blob,
+                    files.remove(0),
+                    sources,
+                    min_confirmations,
+                    timezone,
+                    node,
+                )
+            } else if target.is_some() || digest.is_some() || git_blob {
+                Err(Error::Generic(
+                    "--target, --digest and --git_blob need a single timestamp".to_string(),
+                ))
+            } else {
+                verify_batch(files, sources, min_confirmations, jobs, timezone, node)
+            }
+        }
+        CliCommand::Headers {
+            chain,
+            checkpoint,
+            import,
+            start_height,
+            sync,
+            source,
+        } => headers(
+            chain,
+            checkpoint,
+            import.map(|file| (file, start_height)),
+            sync,
+            source,
+            Node {
+                network: cli_opts.network,
+                opts: cli_opts.bitcoin,
+            },
+        ),
+        CliCommand::Watch { files, store } => watch(files, store),
+        CliCommand::Daemon {
+            store,
+            interval,
+            calendar,
+            once,
+            whitelist,
+        } => daemon(store, interval, calendar, url_whitelist(whitelist), once),
+        CliCommand::Status { store } => status(store),
+        CliCommand::Git {
+            gpg_program,
+            calendar,
+            timeout,
+            gpg_args,
+        } => {
+            let network = cli_opts.network;
+            let opts = git::GitOpts {
+                gpg_program,
+                calendar_urls: calendar,
+                timeout,
+                network,
+                bitcoin: bitcoin_client(Node {
+                    network,
+                    opts: cli_opts.bitcoin,
+                })?,
+            };
+            git::gpg_wrapper(opts, gpg_args).map(|_| Report::empty())
+        }
+        CliCommand::Serve {
+            bind,
+            public_url,
+            data_dir,
+            calendar,
+            timeout,
+            anchor,
+            confirmations,
+            batch_interval,
+            upgrade_interval,
+            retention_days,
+        } => {
+            let network = cli_opts.network;
+            let backend = if anchor {
+                let client = bitcoin_client(Node {
+                    network,
+                    opts: cli_opts.bitcoin,
+                })?
+                .ok_or(Error::Generic("Anchoring needs --bitcoin-node".to_string()))?;
+                let mut anchor = BitcoinAnchor::new(client);
+                anchor.confirmations = confirmations;
+                Backend::Bitcoin(anchor)
+            } else {
+                let urls = calendar.unwrap_or_else(|| network::default_calendars(network));
+                if urls.is_empty() {
+                    return Err(Error::Generic(format!("No calendar known for {}", network)));
+                }
+                Backend::Calendars {
+                    urls,
+                    timeout: Some(timeout),
+                }
+            };
+            let opts = server::ServeOpts {
+                public_url,
+                bind,
+                data_dir: data_dir.to_string(),
+                backend,
+                batch_interval,
+                upgrade_interval,
+                retention: Duration::from_secs(retention_days * 24 * 60 * 60),
+            };
+            server::serve(opts).map(|_| Report::empty())
+        }
+        CliCommand::GitExtract {
+            path,
+            commit,
+            repository,
+            output,
+            force,
+        } => git_extract(repository, commit, path, output, force),
     };
     result.map_err(|e| e.into())
 }
 
-fn info(file: Utf8PathBuf) -> Result<(), Error> {
-    let fh = fs::File::open(file).map_err(|_| Error::InvalidFile)?;
+#[derive(Serialize)]
+struct ExtractedFile {
+    path: String,
+    commit: String,
+    blob: String,
+    timestamp: Utf8PathBuf,
+}
+
+fn git_extract(
+    repository: Utf8PathBuf,
+    commit: String,
+    path: String,
+    output: Option<Utf8PathBuf>,
+    force: bool,
+) -> Result<Report, Error> {
+    let repository = ots_core::git::GitRepository::open(repository);
+    let commit = repository.resolve_commit(&commit)?;
+    let ots = ots_core::git::extract(&repository, &commit, &path)?;
+    let timestamp = output.unwrap_or_else(|| {
+        let name = Utf8Path::new(&path).file_name().unwrap_or(&path);
+        Utf8PathBuf::from(format!("{}{}", name, ots_core::git::EXTRACTED_SUFFIX))
+    });
+    atomic::write_ots(&timestamp, &ots, force)?;
+    let blob = Hexed(&ots.timestamp.start_digest).to_string();
+    let text = format!(
+        "Timestamp of {} in commit {} written to {}\nVerify it with: ots_cli verify --git_blob --target {} {}\n",
+        path, commit, timestamp, path, timestamp
+    );
+    let report = ExtractedFile {
+        path,
+        commit,
+        blob,
+        timestamp,
+    };
+    Ok(Report::new(Status::Success, text, &report))
+}
+
+#[derive(Serialize)]
+struct InfoReport {
+    file: Utf8PathBuf,
+    digest_type: String,
+    digest: String,
+    attestations: Vec<String>,
+    info: String,
+}
+
+fn info(file: Utf8PathBuf) -> Result<Report, Error> {
+    let fh = fs::File::open(&file).map_err(|_| Error::InvalidFile)?;
     let ots = DetachedTimestampFile::from_reader(fh).map_err(|err| Error::InvalidOts(err))?;
-    print!("{}", ots_core::client::info(ots)?);
-    Ok(())
+    let mut attestations: Vec<String> = ots
+        .timestamp
+        .all_attestations()
+        .values()
+        .map(|attestation| AttestationKind::classify(attestation).to_string())
+        .collect();
+    attestations.sort();
+    let report = InfoReport {
+        file,
+        digest_type: format!("{:?}", ots.digest_type),
+        digest: Hexed(&ots.timestamp.start_digest).to_string(),
+        attestations,
+        info: ots_core::client::info(ots)?,
+    };
+    Ok(Report::new(Status::Success, report.info.clone(), &report))
 }
 
 fn file_digest(path: Utf8PathBuf, digest_type: DigestType) -> Result<Vec<u8>, Error> {
-    let mut fh = fs::File::open(path).map_err(|_| Error::InvalidFile)?;
-    let mut buffer = Vec::new();
-    fh.read_to_end(&mut buffer).map_err(|_| Error::IOError)?;
-    let op = match digest_type {
-        DigestType::Sha1 => Op::Sha1,
-        DigestType::Sha256 => Op::Sha256,
-        DigestType::Ripemd160 => Op::Ripemd160,
-    };
-    Ok(op.execute(&buffer))
+    let fh = fs::File::open(path).map_err(|_| Error::InvalidFile)?;
+    ots_core::client::digest_reader(BufReader::new(fh), digest_type)
 }
 
 fn stamps(
     files: Vec<Utf8PathBuf>,
     calendar_urls: Option<Vec<String>>,
     timeout: Option<Duration>,
-) -> Result<(), Error> {
+    force: bool,
+    receipt: bool,
+    network: Network,
+) -> Result<Report, Error> {
+    if !force {
+        for file in files.iter() {
+            let timestamp_file_path = format!("{}.ots", file);
+            if Path::new(timestamp_file_path.as_str()).exists() {
+                return Err(Error::AlreadyExists(format!(
+                    "{} (use --force to overwrite)",
+                    timestamp_file_path
+                )));
+            }
+        }
+    }
     let mut file_digests = vec![];
     let digest_type = DigestType::Sha256;
     for file in files.clone() {
         file_digests.push(file_digest(file, digest_type)?);
     }
-    let timestamps = ots_core::client::stamps(file_digests, digest_type, calendar_urls, timeout)?;
-    for (in_file, ots) in files.iter().zip(timestamps) {
-        let timestamp_file_path = format!("{}.ots", in_file);
-        let file = fs::File::create(timestamp_file_path).map_err(|_| Error::InvalidFile)?;
-        ots.to_writer(file).map_err(|_| Error::IOError)?;
+    let timestamps = ots_core::client::stamps_with_receipts(
+        file_digests,
+        digest_type,
+        calendar_urls,
+        timeout,
+        network,
+    )?;
+    let mut stamped = vec![];
+    for (in_file, (ots, mut stamp_receipt)) in files.iter().zip(timestamps) {
+        let timestamp_file_path = Utf8PathBuf::from(format!("{}.ots", in_file));
+        atomic::write_ots(&timestamp_file_path, &ots, force)?;
+        let receipt_path = if receipt {
+            let path = StampReceipt::sidecar_path(timestamp_file_path.as_std_path());
+            stamp_receipt.tool = format!("ots_cli {}", env!("CARGO_PKG_VERSION"));
+            stamp_receipt.save(&path)?;
+            Utf8PathBuf::try_from(path).ok()
+        } else {
+            None
+        };
+        stamped.push(StampedFile {
+            file: in_file.clone(),
+            timestamp: timestamp_file_path,
+            digest: Hexed(&ots.timestamp.start_digest).to_string(),
+            pending: ots_core::client::pending_uris(&ots),
+            receipt: receipt_path,
+        });
     }
-    Ok(())
+    let text = stamped
+        .iter()
+        .map(|stamp| match &stamp.receipt {
+            Some(receipt) => format!(
+                "{}: timestamp written to {}, receipt to {}",
+                stamp.file, stamp.timestamp, receipt
+            ),
+            None => format!("{}: timestamp written to {}", stamp.file, stamp.timestamp),
+        })
+        .collect::<Vec<String>>()
+        .join("\n");
+    Ok(Report::new(Status::Success, text, &stamped))
+}
+
+#[derive(Serialize)]
+struct StampedFile {
+    file: Utf8PathBuf,
+    timestamp: Utf8PathBuf,
+    digest: String,
+    pending: Vec<String>,
+    receipt: Option<Utf8PathBuf>,
+}
+
+/// Bitcoin network and the options to reach a node on it
+struct Node {
+    network: Network,
+    opts: Option<BitcoinOpts>,
+}
+
+/// Polling policy of `upgrade --wait`
+struct Wait {
+    interval: Duration,
+    /// When to give up waiting, for all the files together
+    deadline: Option<Instant>,
+}
+
+fn url_whitelist(opts: WhitelistOpts) -> UrlWhitelist {
+    let mut whitelist = if opts.no_default_whitelist {
+        UrlWhitelist::new()
+    } else {
+        UrlWhitelist::default()
+    };
+    for pattern in opts.whitelist {
+        whitelist.add(&pattern);
+    }
+    whitelist.allow_unknown = opts.allow_unknown_calendars;
+    whitelist.https_only = !opts.allow_insecure_calendars;
+    whitelist
 }
 
-fn upgrade(files: Vec<Utf8PathBuf>, calendar_urls: Option<Vec<String>>) -> Result<(), Error> {
+fn upgrade(
+    files: Vec<Utf8PathBuf>,
+    calendar_urls: Option<Vec<String>>,
+    whitelist: UrlWhitelist,
+    wait: Option<Wait>,
+    dry_run: bool,
+    no_backup: bool,
+) -> Result<Report, Error> {
+    let mut upgraded = vec![];
+    // A file that cannot be upgraded is reported without stopping the others
     for file in files {
-        upgrade_file(file, calendar_urls.clone())?;
+        let result = upgrade_file(
+            file.clone(),
+            calendar_urls.clone(),
+            &whitelist,
+            wait.as_ref(),
+            dry_run,
+            no_backup,
+        );
+        upgraded.push(result.unwrap_or_else(|e| {
+            error!("Could not upgrade {}: {}", file, e);
+            UpgradedFile {
+                file,
+                changed: false,
+                written: false,
+                complete: false,
+                failed: true,
+                pending: vec![],
+                errors: vec![e.to_string()],
+            }
+        }));
     }
-    Ok(())
+    let status = if upgraded.iter().any(|file| file.failed) {
+        Status::Failed
+    } else if upgraded.iter().all(|file| file.complete) {
+        Status::Success
+    } else {
+        Status::Pending
+    };
+    let text = upgraded
+        .iter()
+        .map(|file| file.to_string())
+        .collect::<Vec<String>>()
+        .join("\n");
+    Ok(Report::new(status, text, &upgraded))
 }
 
-fn upgrade_file(path: Utf8PathBuf, calendar_urls: Option<Vec<String>>) -> Result<(), Error> {
+#[derive(Serialize)]
+struct UpgradedFile {
+    file: Utf8PathBuf,
+    changed: bool,
+    written: bool,
+    complete: bool,
+    /// The file could not be read or written, or no calendar can upgrade
+    /// the timestamp any further
+    failed: bool,
+    pending: Vec<String>,
+    errors: Vec<String>,
+}
+
+impl std::fmt::Display for UpgradedFile {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let change = match (self.changed, self.written) {
+            (true, true) => "upgraded",
+            (true, false) if self.failed => "upgrade not written",
+            (true, false) => "would be upgraded",
+            (false, _) => "unchanged",
+        };
+        let state = match (self.complete, self.failed) {
+            (true, _) => "complete",
+            (false, true) => "failed",
+            (false, false) => "pending",
+        };
+        write!(f, "{}: {}, {}", self.file, change, state)?;
+        if !self.errors.is_empty() {
+            write!(f, " ({})", self.errors.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+fn upgrade_file(
+    path: Utf8PathBuf,
+    calendar_urls: Option<Vec<String>>,
+    whitelist: &UrlWhitelist,
+    wait: Option<&Wait>,
+    dry_run: bool,
+    no_backup: bool,
+) -> Result<UpgradedFile, Error> {
     debug!("Upgrading {}", path);
 
     let file = fs::File::open(path.clone()).map_err(|_| Error::InvalidFile)?;
     let mut ots = DetachedTimestampFile::from_reader(file).map_err(|err| Error::InvalidOts(err))?;
-    ots_core::client::upgrade(&mut ots, calendar_urls)?;
-
-    let backup_name = format!("{}.bak", path);
-    debug!(
-        "Got new timestamp data; renaming existing timestamp to {}",
-        backup_name
-    );
-    if Path::new(backup_name.as_str()).exists() {
-        error!("Could not backup timestamp: {} already exists", backup_name);
-        return Ok(());
+    let mut changed = false;
+    let mut failed = false;
+    let mut errors: Vec<String>;
+    loop {
+        let outcome = ots_core::client::upgrade(&mut ots, calendar_urls.clone(), whitelist)?;
+        changed |= outcome.changed();
+        errors = outcome
+            .errors
+            .iter()
+            .map(|(uri, e)| format!("{}: {}", uri, e))
+            .collect();
+        for attestation in outcome.upgraded.iter() {
+            info!("Got new attestation {:?} for {}", attestation, path);
+        }
+        for uri in outcome.pending.iter() {
+            info!("Pending confirmation in Bitcoin blockchain at {}", uri);
+        }
+        let Some(wait) = wait else {
+            if !changed && !outcome.errors.is_empty() {
+                error!("Could not upgrade {}: {}", path, errors.join(", "));
+                failed = true;
+            }
+            break;
+        };
+        if ots_core::client::is_complete(&ots) {
+            info!("Success! Timestamp {} is complete", path);
+            break;
+        }
+        if !outcome.upgradable() {
+            error!(
+                "No calendar can upgrade the pending attestations of {}",
+                path
+            );
+            failed = true;
+            break;
+        }
+        if wait
+            .deadline
+            .is_some_and(|deadline| Instant::now() + wait.interval > deadline)
+        {
+            warn!("Timed out waiting for a Bitcoin attestation of {}", path);
+            break;
+        }
+        info!(
+            "Timestamp {} not complete; waiting {} sec before trying again",
+            path,
+            wait.interval.as_secs()
+        );
+        thread::sleep(wait.interval);
     }
-    fs::rename(path.clone(), backup_name).map_err(|_| Error::InvalidFile)?;
 
-    let file = fs::File::create(path).map_err(|_| Error::InvalidFile)?;
-    ots.to_writer(file).map_err(|_| Error::IOError)?;
+    let mut written = false;
+    if changed && !dry_run {
+        match write_upgraded(&path, &ots, no_backup) {
+            Ok(()) => written = true,
+            Err(e) => {
+                error!("Could not write {}: {}", path, e);
+                errors.push(e.to_string());
+                failed = true;
+            }
+        }
+    } else {
+        debug!("No new timestamp data written for {}", path);
+    }
+    Ok(UpgradedFile {
+        complete: ots_core::client::is_complete(&ots),
+        pending: ots_core::client::pending_uris(&ots),
+        file: path,
+        changed,
+        written,
+        failed,
+        errors,
+    })
+}
 
-    Ok(())
+fn write_upgraded(
+    path: &Utf8PathBuf,
+    ots: &DetachedTimestampFile,
+    no_backup: bool,
+) -> Result<(), Error> {
+    if !no_backup {
+        let backup_name = Utf8PathBuf::from(format!("{}.bak", path));
+        debug!(
+            "Got new timestamp data; copying existing timestamp to {}",
+            backup_name
+        );
+        atomic::backup(path, &backup_name)?;
+    }
+    atomic::write_ots(path, ots, true)
 }
 
 fn verify(
     target: Option<Utf8PathBuf>,
     digest: Option<String>,
+    git_blob: bool,
     timestamp: Utf8PathBuf,
-    bitcoin: Option<BitcoinOpts>,
-) -> Result<(), Error> {
+    sources: HeaderSourceOpts,
+    min_confirmations: u32,
+    timezone: Option<Tz>,
+    node: Node,
+) -> Result<Report, Error> {
     let file = fs::File::open(timestamp.clone()).map_err(|_| Error::InvalidFile)?;
-    let mut detached_timestamp =
+    let detached_timestamp =
         DetachedTimestampFile::from_reader(file).map_err(|err| Error::InvalidOts(err))?;
 
     if let Some(digest) = digest {
-        let bytes = Vec::<u8>::from_hex(&digest.as_str()).map_err(|_| Error::IOError)?;
-        if bytes != detached_timestamp.timestamp.start_digest {
-            let msg = format!(
-                "Digest provided does not match digest in timestamp, {:?} {:?}",
-                Hexed(detached_timestamp.timestamp.start_digest.by_ref()),
-                detached_timestamp.digest_type
-            );
-            return Err(Error::Generic(msg));
-        }
+        let bytes = Vec::<u8>::from_hex(&digest.as_str())
+            .map_err(|_| Error::InvalidArgument(format!("Invalid digest {}", digest)))?;
+        ots_core::client::verify_digest(&detached_timestamp, &bytes)?;
+    } else if git_blob {
+        let target_filename = match target {
+            Some(target) => target,
+            None => Utf8PathBuf::try_from(ots_core::git::extracted_target_path(
+                timestamp.as_std_path(),
+            )?)
+            .map_err(|_| Error::InvalidFile)?,
+        };
+        let fh = fs::File::open(target_filename).map_err(|_| Error::InvalidFile)?;
+        ots_core::git::verify_blob(&detached_timestamp, BufReader::new(fh))?;
     } else {
         let target_filename = match target {
             Some(target) => target,
-            None => target_filename(timestamp)?,
+            None => target_filename(timestamp.clone())?,
         };
-        debug!(
-            "Hashing file, algorithm {:?}",
-            detached_timestamp.digest_type
-        );
-        let actual_file_digest = file_digest(target_filename, detached_timestamp.digest_type)?;
-        debug!(
-            "Got digest {:?}",
-            Hexed(detached_timestamp.timestamp.start_digest.by_ref())
+        let fh = fs::File::open(target_filename).map_err(|_| Error::InvalidFile)?;
+        ots_core::client::verify_file(&detached_timestamp, BufReader::new(fh))?;
+    }
+    let registry = VerifierRegistry::with_litecoin_sources(&sources.litecoin_source)?;
+    let mut report = VerifyReport {
+        timestamp,
+        bitcoin: None,
+        other: vec![],
+        pending: ots_core::client::pending_uris(&detached_timestamp),
+    };
+    let has_bitcoin = ots_core::client::bitcoin_height(&detached_timestamp).is_some();
+    if !has_bitcoin && !registry.handles(&detached_timestamp) && !report.pending.is_empty() {
+        let text = format!(
+            "Pending confirmation in Bitcoin blockchain at {}",
+            report.pending.join(", ")
         );
+        return Ok(Report::new(Status::Pending, text, &report));
+    }
+    let mut caches = HeaderCaches::new(&sources, node.network);
+    let source = match has_bitcoin {
+        true => Some(header_source(sources, node, &mut caches)?),
+        false => None,
+    };
+    let verification = ots_core::client::verify_all(
+        detached_timestamp,
+        source.as_deref(),
+        &registry,
+        min_confirmations,
+    );
+    caches.save();
+    let verification = verification?;
+    // Shallow attestations could still be undone by a reorganization
+    let status = if verification.is_provisional() {
+        Status::Pending
+    } else {
+        Status::Success
+    };
+    report.bitcoin = verification.bitcoin;
+    report.other = verification.other;
+    let text = report.describe(timezone);
+    Ok(Report::new(status, text, &report))
+}
 
-        if actual_file_digest != detached_timestamp.timestamp.start_digest {
-            let msg = format!(
-                "Expected digest {:?}",
-                Hexed(detached_timestamp.timestamp.start_digest.by_ref())
-            );
-            return Err(Error::Generic(msg));
+/// Disk-backed caches of the headers fetched from each header source, in the
+/// `--header-cache` directory, if enabled.
+struct HeaderCaches {
+    dir: Option<Utf8PathBuf>,
+    network: Network,
+    opened: Vec<Arc<Mutex<HeaderCache>>>,
+}
+
+impl HeaderCaches {
+    fn new(sources: &HeaderSourceOpts, network: Network) -> Self {
+        HeaderCaches {
+            dir: sources.header_cache.clone(),
+            network,
+            opened: vec![],
         }
     }
-    let bitcoin_client = match bitcoin {
-        Some(opts) => Some(
-            Client::new(
+
+    /// `source` served from its own cache, so cross-checking still covers
+    /// cached headers.
+    fn wrap(
+        &mut self,
+        name: &str,
+        source: Box<dyn BlockHeaderSource>,
+    ) -> Box<dyn BlockHeaderSource> {
+        let Some(dir) = &self.dir else {
+            return source;
+        };
+        let cache = Arc::new(Mutex::new(HeaderCache::open(
+            dir.join(cache_file_name(self.network, name)),
+            DEFAULT_CACHE_CAPACITY,
+            self.network,
+        )));
+        self.opened.push(cache.clone());
+        Box::new(CachedSource::new(source, cache))
+    }
+
+    fn save(&self) {
+        let Some(dir) = &self.dir else {
+            return;
+        };
+        if let Err(e) = fs::create_dir_all(dir) {
+            warn!("Could not create the header cache directory {}: {}", dir, e);
+            return;
+        }
+        for cache in &self.opened {
+            if let Err(e) = cache.lock().unwrap().save() {
+                warn!("Could not save the header cache: {}", e);
+            }
+        }
+    }
+}
+
+/// File caching the headers of the source `name` on `network`.
+fn cache_file_name(network: Network, name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(
+            |c| match c.is_ascii_alphanumeric() || c == '.' || c == '-' {
+                true => c,
+                false => '_',
+            },
+        )
+        .collect();
+    format!("{}-{}.txt", network, name)
+}
+
+/// Bitcoin block headers to verify against: a local header chain or file,
+/// or else the header sources and node given, cross-checked and each served
+/// from its cache in `caches` when they are deep enough.
+fn header_source(
+    sources: HeaderSourceOpts,
+    node: Node,
+    caches: &mut HeaderCaches,
+) -> Result<Box<dyn BlockHeaderSource>, Error> {
+    match (sources.header_chain, sources.headers) {
+        (Some(path), _) => Ok(Box::new(HeaderChain::load(path, node.network)?)),
+        (None, Some(path)) => Ok(Box::new(HeadersFile::open(
+            path,
+            sources.headers_start_height,
+            node.network,
+        )?)),
+        (None, None) => {
+            let mut cross_check = CrossCheckSource::new();
+            for url in sources.sources {
+                cross_check.add(&url, caches.wrap(&url, source_from_url(&url)?));
+            }
+            let network = node.network;
+            let node_address = node.opts.as_ref().map(|opts| {
                 opts.bitcoin_node
-                    .unwrap_or("localhost".to_string())
-                    .as_str(),
-                Auth::UserPass(
-                    opts.bitcoin_username.unwrap(),
-                    opts.bitcoin_password.unwrap(),
-                ),
+                    .clone()
+                    .unwrap_or(format!("localhost:{}", default_rpc_port(network)))
+            });
+            if let Some(client) = bitcoin_client(node)? {
+                let name = format!("bitcoind-{}", node_address.unwrap_or_default());
+                let source = caches.wrap(&name, Box::new(BitcoindSource::new(client)));
+                cross_check.add("bitcoind", source);
+            }
+            if cross_check.is_empty() {
+                let url = default_source(network)?;
+                cross_check.add(url, caches.wrap(url, source_from_url(url)?));
+            }
+            Ok(Box::new(cross_check))
+        }
+    }
+}
+
+/// Accept the target file after the timestamp, as `verify FILE.ots FILE`,
+/// and reject other files given among several timestamps.
+fn positional_target(
+    mut timestamps: Vec<Utf8PathBuf>,
+    target: Option<Utf8PathBuf>,
+) -> Result<(Vec<Utf8PathBuf>, Option<Utf8PathBuf>), Error> {
+    let is_timestamp = |path: &Utf8Path| path.is_dir() || path.extension() == Some("ots");
+    if timestamps.len() == 2 && target.is_none() && !is_timestamp(&timestamps[1]) {
+        let target = timestamps.pop();
+        return Ok((timestamps, target));
+    }
+    if timestamps.len() > 1 {
+        if let Some(other) = timestamps.iter().find(|path| !is_timestamp(path)) {
+            return Err(Error::Generic(format!(
+                "{} is not a timestamp, give the target of a single timestamp as `verify FILE.ots FILE` or with --target",
+                other
+            )));
+        }
+    }
+    Ok((timestamps, target))
+}
+
+/// Timestamp files given on the command line, searching directories for
+/// `.ots` files.
+fn timestamp_files(paths: Vec<Utf8PathBuf>) -> Result<Vec<Utf8PathBuf>, Error> {
+    let mut files = vec![];
+    for path in paths {
+        if path.is_dir() {
+            let found = files.len();
+            collect_timestamps(&path, &mut files)?;
+            if files.len() == found {
+                warn!("No timestamp found in {}", path);
+            }
+        } else {
+            files.push(path);
+        }
+    }
+    if files.is_empty() {
+        return Err(Error::Generic("No timestamp to verify".to_string()));
+    }
+    Ok(files)
+}
+
+fn collect_timestamps(dir: &Utf8Path, files: &mut Vec<Utf8PathBuf>) -> Result<(), Error> {
+    let mut entries = dir
+        .read_dir_utf8()
+        .map_err(|_| Error::InvalidFile)?
+        .map(|entry| entry.map(|entry| entry.into_path()))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| Error::InvalidFile)?;
+    entries.sort();
+    for path in entries {
+        if path.is_dir() {
+            collect_timestamps(&path, files)?;
+        } else if path.extension() == Some("ots") {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Read a timestamp and check it against its target file, named after it.
+fn load_timestamp(timestamp: &Utf8Path) -> Result<DetachedTimestampFile, Error> {
+    let file = fs::File::open(timestamp).map_err(|_| Error::InvalidFile)?;
+    let ots = DetachedTimestampFile::from_reader(file).map_err(Error::InvalidOts)?;
+    let target = target_filename(timestamp.to_path_buf())?;
+    let fh = fs::File::open(target).map_err(|_| Error::InvalidFile)?;
+    ots_core::client::verify_file(&ots, BufReader::new(fh))?;
+    Ok(ots)
+}
+
+/// Verify many timestamps: files are read and hashed by `jobs` threads, then
+/// all Bitcoin attestations are checked against one header source, each
+/// block header fetched once.
+fn verify_batch(
+    files: Vec<Utf8PathBuf>,
+    sources: HeaderSourceOpts,
+    min_confirmations: u32,
+    jobs: usize,
+    timezone: Option<Tz>,
+    node: Node,
+) -> Result<Report, Error> {
+    let chunk_size = files.len().div_ceil(jobs.max(1));
+    let loaded: Vec<Result<DetachedTimestampFile, Error>> = thread::scope(|scope| {
+        let handles: Vec<_> = files
+            .chunks(chunk_size)
+            .map(|chunk| {
+                scope.spawn(move || {
+                    chunk
+                        .iter()
+                        .map(|file| load_timestamp(file))
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .flat_map(|handle| handle.join().unwrap())
+            .collect()
+    });
+
+    let mut entries: Vec<BatchEntry> = vec![];
+    let mut attested = vec![];
+    for (timestamp, ots) in files.into_iter().zip(loaded) {
+        let mut entry = BatchEntry {
+            timestamp,
+            status: BatchStatus::Failed,
+            bitcoin: None,
+            pending: vec![],
+            error: None,
+        };
+        match ots {
+            Ok(ots) => {
+                entry.pending = ots_core::client::pending_uris(&ots);
+                if ots_core::client::bitcoin_height(&ots).is_some() {
+                    attested.push((entries.len(), ots));
+                } else if !entry.pending.is_empty() {
+                    entry.status = BatchStatus::Pending;
+                } else {
+                    entry.error = Some("No bitcoin attestation found".to_string());
+                }
+            }
+            Err(e) => entry.error = Some(e.to_string()),
+        }
+        entries.push(entry);
+    }
+
+    if !attested.is_empty() {
+        let (indexes, timestamps): (Vec<usize>, Vec<DetachedTimestampFile>) =
+            attested.into_iter().unzip();
+        let mut caches = HeaderCaches::new(&sources, node.network);
+        let source = header_source(sources, node, &mut caches)?;
+        let results =
+            ots_core::client::verify_batch(&timestamps, source.as_ref(), min_confirmations);
+        caches.save();
+        let results = results?;
+        for (index, result) in indexes.into_iter().zip(results) {
+            let entry = &mut entries[index];
+            match result {
+                Ok(attestation) => {
+                    entry.status = if attestation.provisional {
+                        BatchStatus::Pending
+                    } else {
+                        BatchStatus::Verified
+                    };
+                    entry.bitcoin = Some(attestation);
+                }
+                Err(e) => entry.error = Some(e.to_string()),
+            }
+        }
+    }
+
+    let count = |status: BatchStatus| entries.iter().filter(|e| e.status == status).count();
+    let (verified, pending, failed) = (
+        count(BatchStatus::Verified),
+        count(BatchStatus::Pending),
+        count(BatchStatus::Failed),
+    );
+    let status = if failed > 0 {
+        Status::Failed
+    } else if pending > 0 {
+        Status::Pending
+    } else {
+        Status::Success
+    };
+    let mut text = batch_table(&entries, timezone);
+    text.push_str(&format!(
+        "{} timestamps: {} verified, {} pending, {} failed\n",
+        entries.len(),
+        verified,
+        pending,
+        failed
+    ));
+    Ok(Report::new(status, text, &entries))
+}
+
+/// Summary table of a batch verification, one line per timestamp.
+fn batch_table(entries: &[BatchEntry], timezone: Option<Tz>) -> String {
+    let width = entries
+        .iter()
+        .map(|entry| entry.timestamp.as_str().len())
+        .max()
+        .unwrap_or(0)
+        .max("TIMESTAMP".len());
+    let mut text = format!(
+        "{:width$}  {:8}  {:>7}  DETAILS\n",
+        "TIMESTAMP", "STATUS", "BLOCK"
+    );
+    for entry in entries {
+        let (block, details) = match (&entry.bitcoin, &entry.error) {
+            (Some(attestation), _) => {
+                let mut time = match timezone {
+                    Some(tz) => timestamp_to_iso8601_in(attestation.time as i64, tz),
+                    None => timestamp_to_iso8601(attestation.time as i64),
+                };
+                if attestation.provisional {
+                    time.push_str(" (provisional)");
+                }
+                (attestation.height.to_string(), time)
+            }
+            (None, Some(error)) => ("-".to_string(), error.clone()),
+            (None, None) => ("-".to_string(), entry.pending.join(", ")),
+        };
+        text.push_str(&format!(
+            "{:width$}  {:8}  {:>7}  {}\n",
+            entry.timestamp,
+            entry.status.name(),
+            block,
+            details
+        ));
+    }
+    text
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum BatchStatus {
+    Verified,
+    Pending,
+    Failed,
+}
+
+impl BatchStatus {
+    fn name(&self) -> &'static str {
+        match self {
+            BatchStatus::Verified => "verified",
+            BatchStatus::Pending => "pending",
+            BatchStatus::Failed => "failed",
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct BatchEntry {
+    timestamp: Utf8PathBuf,
+    status: BatchStatus,
+    bitcoin: Option<BitcoinAttestationResult>,
+    pending: Vec<String>,
+    error: Option<String>,
+}
+
+#[derive(Serialize)]
+struct VerifyReport {
+    timestamp: Utf8PathBuf,
+    bitcoin: Option<BitcoinAttestationResult>,
+    other: Vec<ChainAttestationResult>,
+    pending: Vec<String>,
+}
+
+impl VerifyReport {
+    fn describe(&self, timezone: Option<Tz>) -> String {
+        let mut lines = vec![];
+        if let Some(attestation) = &self.bitcoin {
+            match timezone {
+                Some(tz) => lines.push(format!("Success! {}", attestation.format_in(tz))),
+                None => lines.push(format!("Success! {}", attestation)),
+            }
+        }
+        for attestation in self.other.iter() {
+            lines.push(format!("Success! {}", attestation));
+        }
+        lines.join("\n")
+    }
+}
+
+/// Public header source of `network`, used when no node is configured.
+fn default_source(network: Network) -> Result<&'static str, Error> {
+    default_electrum(network).ok_or(Error::Generic(format!(
+        "No public header source for {}, use --source or a Bitcoin node",
+        network
+    )))
+}
+
+fn bitcoin_client(node: Node) -> Result<Option<Client>, Error> {
+    let client = match node.opts {
+        Some(opts) => {
+            let auth = match (
+                opts.bitcoin_cookie_file,
+                opts.bitcoin_username,
+                opts.bitcoin_password,
+            ) {
+                (Some(cookie_file), _, _) => Auth::CookieFile(cookie_file.into()),
+                (None, Some(username), Some(password)) => Auth::UserPass(username, password),
+                (None, username, _) => {
+                    let missing = match username {
+                        Some(_) => "--bitcoin-password",
+                        None => {
+                            "--bitcoin-cookie-file, or --bitcoin-username and --bitcoin-password"
+                        }
+                    };
+                    return Err(Error::InvalidArgument(format!(
+                        "The Bitcoin node needs {}",
+                        missing
+                    )));
+                }
+            };
+            Some(
+                Client::new(
+                    opts.bitcoin_node
+                        .unwrap_or(format!("localhost:{}", default_rpc_port(node.network)))
+                        .as_str(),
+                    auth,
+                )
+                .map_err(|_| Error::BitcoinNodeError)?,
             )
-            .map_err(|_| Error::BitcoinNodeError)?,
-        ),
+        }
         None => None,
     };
-    let attestation = ots_core::client::verify(detached_timestamp, bitcoin_client)?;
-    info!("Success! {}", attestation);
-    Ok(())
+    Ok(client)
+}
+
+fn headers(
+    path: Utf8PathBuf,
+    checkpoint: Option<String>,
+    import: Option<(Utf8PathBuf, Option<u32>)>,
+    sync: bool,
+    source: Option<String>,
+    node: Node,
+) -> Result<Report, Error> {
+    let network = node.network;
+    let mut chain = if path.exists() {
+        HeaderChain::load(&path, network)?
+    } else {
+        let checkpoint = match checkpoint {
+            Some(checkpoint) => checkpoint.parse::<Checkpoint>()?,
+            None => *default_checkpoints(network).last().unwrap(),
+        };
+        info!("Starting a new header chain at {}", checkpoint.height);
+        HeaderChain::new(checkpoint, network)
+    };
+    if let Some((file, start_height)) = import {
+        let fh = fs::File::open(file).map_err(|_| Error::InvalidFile)?;
+        let start_height = start_height.unwrap_or(chain.checkpoint().height);
+        chain.import(BufReader::new(fh), start_height)?;
+    }
+    if sync {
+        let tip = match bitcoin_client(node)? {
+            Some(client) => chain.sync(&BitcoindSource::new(client))?,
+            None => {
+                let url = match source {
+                    Some(url) => url,
+                    None => default_source(network)?.to_string(),
+                };
+                chain.sync(source_from_url(&url)?.as_ref())?
+            }
+        };
+        info!("Synced header chain up to block {}", tip);
+    }
+    chain.save(&path)?;
+    let report = HeadersReport {
+        path,
+        checkpoint: chain.checkpoint().height,
+        tip: chain.tip(),
+    };
+    let text = match report.tip {
+        Some(tip) => format!("Header chain from block {} to {}", report.checkpoint, tip),
+        None => format!("Empty header chain at block {}", report.checkpoint),
+    };
+    Ok(Report::new(Status::Success, text, &report))
+}
+
+#[derive(Serialize)]
+struct HeadersReport {
+    path: Utf8PathBuf,
+    checkpoint: u32,
+    tip: Option<u32>,
 }
 
 fn target_filename(timestamp: Utf8PathBuf) -> Result<Utf8PathBuf, Error> {
     // Target not specified, so assume it's the same name as the
     // timestamp file minus the .ots extension.
-    assert!(timestamp.file_name().unwrap().ends_with(".ots"));
+    let target = ots_core::client::target_path(timestamp.as_std_path())?;
+    let target = Utf8PathBuf::try_from(target).map_err(|_| Error::InvalidFile)?;
+    info!("Assuming target filename is {}", target);
+    Ok(target)
+}
+
+/// Timestamp tracked in the pending store.
+#[derive(Serialize)]
+struct TrackedTimestamp {
+    id: String,
+    path: Option<String>,
+}
 
-    let mut target = timestamp.clone();
-    let target_filename = timestamp.file_name().unwrap().strip_suffix(".ots").unwrap();
-    info!("Assuming target filename is {}", target_filename);
+fn watch(files: Vec<Utf8PathBuf>, store: Utf8PathBuf) -> Result<Report, Error> {
+    let mut store = PendingStore::open(store)?;
+    let now = chrono::Utc::now().timestamp();
+    let mut text = String::new();
+    let mut watched = vec![];
+    for file in files {
+        // The daemon writes upgrades from its own working directory
+        let file = file.canonicalize_utf8().map_err(|_| Error::InvalidFile)?;
+        let fh = fs::File::open(file.clone()).map_err(|_| Error::InvalidFile)?;
+        let ots = DetachedTimestampFile::from_reader(fh).map_err(|err| Error::InvalidOts(err))?;
+        let id = store.add(&ots, Some(file.to_string()), now)?;
+        text.push_str(&format!("Watching {} ({})\n", file, id));
+        watched.push(TrackedTimestamp {
+            id,
+            path: Some(file.to_string()),
+        });
+    }
+    store.save()?;
+    Ok(Report::new(Status::Success, text, &watched))
+}
 
-    target.pop();
-    target.push(Utf8PathBuf::from(target_filename));
-    Ok(target)
+fn daemon(
+    store: Utf8PathBuf,
+    interval: Duration,
+    calendar_urls: Option<Vec<String>>,
+    whitelist: UrlWhitelist,
+    once: bool,
+) -> Result<Report, Error> {
+    let mut scheduler = Scheduler::new(PendingStore::open(store)?);
+    scheduler.calendar_urls = calendar_urls;
+    scheduler.whitelist = whitelist;
+    let mut upgraded = vec![];
+    // Upgrades are written before their entry completes, a file that cannot
+    // be written stays pending with the error instead of stopping the daemon
+    let on_upgrade = |id: &str, entry: &PendingEntry| -> Result<(), Error> {
+        write_pending(id, entry)?;
+        upgraded.push(TrackedTimestamp {
+            id: id.to_string(),
+            path: entry.path.clone(),
+        });
+        Ok(())
+    };
+    if once {
+        let now = chrono::Utc::now().timestamp();
+        scheduler.tick(now, on_upgrade)?;
+    } else {
+        scheduler.run(interval, on_upgrade);
+    }
+    let text: String = upgraded
+        .iter()
+        .map(|tracked| {
+            format!(
+                "Upgraded {}\n",
+                tracked.path.as_deref().unwrap_or(&tracked.id)
+            )
+        })
+        .collect();
+    // Pending entries left after a single round
+    let status = match scheduler.store.pending().is_empty() {
+        true => Status::Success,
+        false => Status::Pending,
+    };
+    Ok(Report::new(status, text, &upgraded))
+}
+
+fn write_pending(id: &str, entry: &PendingEntry) -> Result<(), Error> {
+    let Some(path) = entry.path.as_ref() else {
+        return Ok(());
+    };
+    debug!("Writing upgraded timestamp {} to {}", id, path);
+    let ots = entry.timestamp()?;
+    atomic::write_ots(Utf8Path::new(path), &ots, true)
+}
+
+#[derive(Serialize)]
+struct StatusEntry {
+    id: String,
+    path: Option<String>,
+    complete: bool,
+    height: Option<u32>,
+    attempts: u32,
+    next_attempt: Option<i64>,
+    last_error: Option<String>,
+}
+
+fn status(store: Utf8PathBuf) -> Result<Report, Error> {
+    let store = PendingStore::open(store)?;
+    let mut text = String::new();
+    let mut entries = vec![];
+    for (id, entry) in store.entries() {
+        let state = match entry.height {
+            Some(height) => format!("complete (block {})", height),
+            None => format!("pending ({} attempts)", entry.attempts),
+        };
+        text.push_str(&format!(
+            "{} {} {}\n",
+            id,
+            state,
+            entry.path.as_deref().unwrap_or("-")
+        ));
+        entries.push(StatusEntry {
+            id: id.clone(),
+            path: entry.path.clone(),
+            complete: entry.is_complete(),
+            height: entry.height,
+            attempts: entry.attempts,
+            next_attempt: (!entry.is_complete()).then_some(entry.next_attempt),
+            last_error: entry.last_error.clone(),
+        });
+    }
+    Ok(Report::new(Status::Success, text, &entries))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn paths(paths: &[&str]) -> Vec<Utf8PathBuf> {
+        paths.iter().map(Utf8PathBuf::from).collect()
+    }
+
+    #[test]
+    fn node_without_credentials() {
+        let node = |username: Option<&str>| Node {
+            network: Network::Regtest,
+            opts: Some(BitcoinOpts {
+                bitcoin_node: Some("localhost:18443".to_string()),
+                bitcoin_username: username.map(str::to_string),
+                bitcoin_password: None,
+                bitcoin_cookie_file: None,
+            }),
+        };
+        assert!(matches!(
+            bitcoin_client(node(None)),
+            Err(Error::InvalidArgument(_))
+        ));
+        assert!(matches!(
+            bitcoin_client(node(Some("user"))),
+            Err(Error::InvalidArgument(_))
+        ));
+    }
+
+    #[test]
+    fn upgraded_file_errors() {
+        let file = UpgradedFile {
+            file: Utf8PathBuf::from("a.ots"),
+            changed: true,
+            written: false,
+            complete: false,
+            failed: true,
+            pending: vec![],
+            errors: vec!["a.ots.bak already exists".to_string()],
+        };
+        assert_eq!(
+            file.to_string(),
+            "a.ots: upgrade not written, failed (a.ots.bak already exists)"
+        );
+    }
+
+    #[test]
+    fn cache_file_per_source() {
+        assert_eq!(
+            cache_file_name(Network::Bitcoin, "ssl://electrum.blockstream.info:50002"),
+            "bitcoin-ssl___electrum.blockstream.info_50002.txt"
+        );
+        assert_eq!(
+            cache_file_name(Network::Signet, "bitcoind-localhost:38332"),
+            "signet-bitcoind-localhost_38332.txt"
+        );
+        assert_ne!(
+            cache_file_name(Network::Bitcoin, "https://blockstream.info/api"),
+            cache_file_name(Network::Bitcoin, "https://mempool.space/api")
+        );
+    }
+
+    #[test]
+    fn target_after_timestamp() {
+        let (timestamps, target) =
+            positional_target(paths(&["file.txt.ots", "file.txt"]), None).unwrap();
+        assert_eq!(timestamps, paths(&["file.txt.ots"]));
+        assert_eq!(target, Some(Utf8PathBuf::from("file.txt")));
+    }
+
+    #[test]
+    fn several_timestamps() {
+        let given = paths(&["a.ots", "b.ots"]);
+        let (timestamps, target) = positional_target(given.clone(), None).unwrap();
+        assert_eq!(timestamps, given);
+        assert!(target.is_none());
+
+        let (timestamps, target) = positional_target(paths(&["a.ots"]), None).unwrap();
+        assert_eq!(timestamps, paths(&["a.ots"]));
+        assert!(target.is_none());
+    }
+
+    #[test]
+    fn file_among_timestamps() {
+        assert!(positional_target(paths(&["a.ots", "b.ots", "b"]), None).is_err());
+        let explicit = Some(Utf8PathBuf::from("a"));
+        assert!(positional_target(paths(&["a.ots", "a"]), explicit).is_err());
+    }
 }
@@ -0,0 +1,126 @@
+// Copyright (C) 2024 The OpenTimestamps developers
+
+use ots_core::error::Error;
+use serde::Serialize;
+
+/// Outcome of a command, reported as the process exit code.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Status {
+    /// The command succeeded, timestamps verified
+    Success = 0,
+    /// The timestamp failed verification, or the command failed
+    Failed = 1,
+    /// Invalid file, timestamp or argument
+    BadInput = 2,
+    /// The timestamp is not attested in the Bitcoin blockchain yet
+    Pending = 3,
+    /// A calendar, header source or Bitcoin node could not be reached
+    NetworkError = 4,
+}
+
+impl Status {
+    pub(crate) fn of(error: &Error) -> Self {
+        match error {
+            Error::NetworkError(_)
+            | Error::BitcoinNodeError
+            | Error::HeaderSourceError(_)
+            | Error::CalendarUnavailable(_) => Status::NetworkError,
+            Error::InvalidOts(_)
+            | Error::InvalidFile
+            | Error::AlreadyExists(_)
+            | Error::InvalidArgument(_) => Status::BadInput,
+            _ => Status::Failed,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Status::Success => "success",
+            Status::Failed => "failed",
+            Status::BadInput => "bad_input",
+            Status::Pending => "pending",
+            Status::NetworkError => "network_error",
+        }
+    }
+}
+
+/// Result of a command, printed on stdout as text or as JSON.
+pub(crate) struct Report {
+    pub(crate) status: Status,
+    text: String,
+    result: serde_json::Value,
+}
+
+impl Report {
+    pub(crate) fn new<T: Serialize>(status: Status, text: String, result: &T) -> Self {
+        Report {
+            status,
+            text,
+            result: serde_json::to_value(result).unwrap_or_default(),
+        }
+    }
+
+    /// A successful command with nothing to report.
+    pub(crate) fn empty() -> Self {
+        Report {
+            status: Status::Success,
+            text: String::new(),
+            result: serde_json::Value::Null,
+        }
+    }
+
+    pub(crate) fn print(&self, json: bool) {
+        if json {
+            let output = serde_json::json!({
+                "status": self.status.name(),
+                "result": self.result,
+            });
+            println!("{}", output);
+        } else if !self.text.is_empty() {
+            println!("{}", self.text.trim_end());
+        }
+    }
+
+    /// Report a failed command, as JSON on stdout or as text on stderr.
+    pub(crate) fn print_error(error: &Error, json: bool) {
+        if json {
+            let output = serde_json::json!({
+                "status": Status::of(error).name(),
+                "error": error.to_string(),
+            });
+            println!("{}", output);
+        } else {
+            eprintln!("Error: {}", error);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exit_codes() {
+        let exists = Error::AlreadyExists("file.txt.ots".to_string());
+        assert_eq!(Status::of(&exists), Status::BadInput);
+        assert_eq!(Status::of(&exists) as i32, 2);
+        assert_eq!(Status::of(&Error::InvalidFile), Status::BadInput);
+        let missing = Error::InvalidArgument("--bitcoin_password".to_string());
+        assert_eq!(Status::of(&missing), Status::BadInput);
+        assert_eq!(
+            Status::of(&Error::HeaderSourceError("down".to_string())),
+            Status::NetworkError
+        );
+        assert_eq!(Status::of(&Error::BitcoinNodeError), Status::NetworkError);
+        let unreachable = Error::CalendarUnavailable("https://a.example".to_string());
+        assert_eq!(Status::of(&unreachable), Status::NetworkError);
+        assert_eq!(Status::of(&unreachable) as i32, 4);
+        // Writing the result failed, the input was fine
+        assert_eq!(Status::of(&Error::IOError), Status::Failed);
+        assert_eq!(
+            Status::of(&Error::Generic("failed".to_string())),
+            Status::Failed
+        );
+        assert_eq!(Status::Pending as i32, 3);
+    }
+}
@@ -0,0 +1,189 @@
+// Copyright (C) 2024 The OpenTimestamps developers
+
+use bitcoincore_rpc::bitcoin::hex::FromHex;
+use log::{debug, error, info};
+use ots_core::aggregator::{serialize_timestamp, Aggregator, Backend, MAX_DIGEST_SIZE};
+use ots_core::error::Error;
+use std::io::Read;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use tiny_http::{Header, Method, Request, Response, Server};
+
+/// Threads answering HTTP requests.
+const WORKERS: usize = 4;
+
+/// Options of the calendar server.
+pub(crate) struct ServeOpts {
+    pub(crate) bind: String,
+    pub(crate) public_url: String,
+    pub(crate) data_dir: String,
+    pub(crate) backend: Backend,
+    pub(crate) batch_interval: Duration,
+    pub(crate) upgrade_interval: Duration,
+    pub(crate) retention: Duration,
+}
+
+/// Run an aggregating calendar server: digests submitted to `/digest` are
+/// batched every `batch_interval` and the batch tip submitted to the
+/// backend, complete proofs are served by `/timestamp/<hex>`.
+pub(crate) fn serve(opts: ServeOpts) -> Result<(), Error> {
+    let mut aggregator = Aggregator::open(&opts.data_dir, &opts.public_url, opts.backend)?;
+    aggregator.retention = opts.retention;
+    let aggregator = Arc::new(Mutex::new(aggregator));
+    let server = Arc::new(
+        Server::http(&opts.bind)
+            .map_err(|e| Error::Generic(format!("Could not listen on {}: {}", opts.bind, e)))?,
+    );
+    info!(
+        "Calendar server listening on {} as {}",
+        opts.bind, opts.public_url
+    );
+
+    for _ in 0..WORKERS {
+        let server = server.clone();
+        let aggregator = aggregator.clone();
+        thread::spawn(move || loop {
+            match server.recv() {
+                Ok(request) => handle(request, &aggregator),
+                Err(e) => error!("Could not receive request: {}", e),
+            }
+        });
+    }
+
+    let upgrade_every =
+        (opts.upgrade_interval.as_secs() / opts.batch_interval.as_secs().max(1)).max(1);
+    let mut rounds = 0u64;
+    loop {
+        thread::sleep(opts.batch_interval);
+        // The lock is only held to take and record the work, submissions are
+        // answered while the backend is queried
+        let batch = aggregator.lock().unwrap().next_batch();
+        match batch {
+            Ok(Some(batch)) => {
+                let submitted = batch.submit();
+                if let Err(e) = aggregator.lock().unwrap().record_batch(batch, submitted) {
+                    error!("Could not submit batch, keeping it queued: {}", e);
+                }
+            }
+            Ok(None) => {}
+            Err(e) => error!("Could not aggregate batch: {}", e),
+        }
+        rounds += 1;
+        if rounds % upgrade_every == 0 {
+            let now = chrono::Utc::now().timestamp();
+            let upgrades = aggregator.lock().unwrap().due_upgrades(now);
+            let upgraded = upgrades.and_then(|upgrades| {
+                let results = upgrades.run();
+                aggregator.lock().unwrap().record_upgrades(now, results)
+            });
+            match upgraded {
+                Ok(upgraded) => debug!("Upgraded {} batch tips", upgraded.len()),
+                Err(e) => error!("Could not upgrade batch tips: {}", e),
+            }
+        }
+    }
+}
+
+fn handle(mut request: Request, aggregator: &Mutex<Aggregator>) {
+    let url = request.url().to_string();
+    debug!("{} {}", request.method(), url);
+    let response = match (request.method(), url.as_str()) {
+        (Method::Post, "/digest") => {
+            let mut digest = vec![];
+            let read = request
+                .as_reader()
+                .take(MAX_DIGEST_SIZE as u64 + 1)
+                .read_to_end(&mut digest);
+            match read {
+                Ok(_) => submit(aggregator, &digest),
+                Err(_) => text(400, "Invalid request body"),
+            }
+        }
+        (Method::Get, path) if path.starts_with("/timestamp/") => {
+            match Vec::<u8>::from_hex(&path["/timestamp/".len()..]) {
+                Ok(commitment) => timestamp(aggregator, &commitment),
+                Err(_) => text(400, "Invalid commitment"),
+            }
+        }
+        (Method::Get, "/") => {
+            let queued = aggregator.lock().unwrap().queued();
+            text(
+                200,
+                &format!("OpenTimestamps calendar\nQueued: {}\n", queued),
+            )
+        }
+        _ => text(404, "Not found"),
+    };
+    if let Err(e) = request.respond(response) {
+        error!("Could not answer {}: {}", url, e);
+    }
+}
+
+fn submit(aggregator: &Mutex<Aggregator>, digest: &[u8]) -> Response<std::io::Cursor<Vec<u8>>> {
+    let submitted = aggregator.lock().unwrap().submit(digest);
+    match submitted.and_then(|timestamp| serialize_timestamp(&timestamp)) {
+        Ok(bytes) => binary(bytes),
+        Err(e) => text(400, &e.to_string()),
+    }
+}
+
+fn timestamp(
+    aggregator: &Mutex<Aggregator>,
+    commitment: &[u8],
+) -> Response<std::io::Cursor<Vec<u8>>> {
+    let timestamp = aggregator.lock().unwrap().timestamp(commitment);
+    match timestamp {
+        Ok(Some(timestamp)) => match serialize_timestamp(&timestamp) {
+            Ok(bytes) => binary(bytes),
+            Err(e) => text(500, &e.to_string()),
+        },
+        // Clients keep the attestation pending on 404, as with public calendars
+        Ok(None) => text(404, "Pending confirmation in Bitcoin blockchain"),
+        Err(e) => text(500, &e.to_string()),
+    }
+}
+
+fn binary(bytes: Vec<u8>) -> Response<std::io::Cursor<Vec<u8>>> {
+    let header = Header::from_bytes("Content-Type", "application/octet-stream").unwrap();
+    Response::from_data(bytes).with_header(header)
+}
+
+fn text(status: u16, message: &str) -> Response<std::io::Cursor<Vec<u8>>> {
+    Response::from_string(message).with_status_code(status)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn aggregator(name: &str) -> (Mutex<Aggregator>, std::path::PathBuf) {
+        let dir = std::env::temp_dir().join(format!("ots-serve-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let backend = Backend::Calendars {
+            urls: vec![],
+            timeout: None,
+        };
+        let aggregator = Aggregator::open(&dir, "http://127.0.0.1:14788/", backend).unwrap();
+        (Mutex::new(aggregator), dir)
+    }
+
+    #[test]
+    fn submit_digest() {
+        let (aggregator, dir) = aggregator("submit");
+        assert_eq!(submit(&aggregator, &[0xab; 32]).status_code().0, 200);
+        assert_eq!(aggregator.lock().unwrap().queued(), 1);
+        assert_eq!(submit(&aggregator, &[]).status_code().0, 400);
+        let oversized = vec![0; MAX_DIGEST_SIZE + 1];
+        assert_eq!(submit(&aggregator, &oversized).status_code().0, 400);
+        assert_eq!(aggregator.lock().unwrap().queued(), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn unknown_commitment_is_pending() {
+        let (aggregator, dir) = aggregator("pending");
+        assert_eq!(timestamp(&aggregator, &[0xcd; 32]).status_code().0, 404);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,777 @@
+// Copyright (C) 2024 The OpenTimestamps developers
+
+use crate::atomic;
+use crate::client;
+use crate::error::Error;
+use crate::extensions::TimestampExtension;
+use crate::pending::{self, PendingStore};
+use crate::scheduler::{DueUpgrades, Scheduler, UpgradeResults};
+
+use bitcoincore_rpc::bitcoin::hex::FromHex;
+use log::{debug, info, warn};
+use opentimestamps::attestation::Attestation;
+use opentimestamps::hex::Hexed;
+use opentimestamps::op::Op;
+use opentimestamps::ser::{DigestType, Serializer};
+use opentimestamps::timestamp::{Step, StepData};
+use opentimestamps::{DetachedTimestampFile, Timestamp};
+use rs_merkle::{algorithms::Sha256, MerkleTree};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Largest digest accepted by `submit`, as for public calendars.
+pub const MAX_DIGEST_SIZE: usize = 64;
+
+/// A batched commitment, waiting for the timestamp of its batch tip.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct BatchedCommitment {
+    /// Hex-encoded tip of the batch
+    tip: String,
+    /// Hex-encoded path from the commitment to the tip, empty for a batch of one
+    path: Option<String>,
+}
+
+/// State of the aggregator persisted between runs.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct AggregatorState {
+    /// Hex-encoded commitments waiting for the next batch
+    queue: Vec<String>,
+    /// Batched commitments, by hex-encoded commitment
+    batched: BTreeMap<String, BatchedCommitment>,
+    /// Transactions committing to the batch tips not confirmed yet, by tip
+    #[serde(default)]
+    anchors: BTreeMap<String, String>,
+}
+
+/// Where the aggregator gets the timestamps of its batch tips from.
+pub enum Backend {
+    /// Submit the tips to upstream calendars
+    Calendars {
+        urls: Vec<String>,
+        /// Timeout before giving up on a calendar
+        timeout: Option<Duration>,
+    },
+    /// Commit the tips to Bitcoin, making the aggregator a calendar itself
+    #[cfg(not(feature = "async"))]
+    Bitcoin(crate::anchor::BitcoinAnchor),
+}
+
+/// How the backend took a batch tip.
+pub enum Submitted {
+    /// Timestamp of the tip returned by the upstream calendars
+    Timestamp(Timestamp),
+    /// Transaction committing to the tip
+    #[cfg(not(feature = "async"))]
+    Anchor(bitcoincore_rpc::bitcoin::Txid),
+}
+
+/// Queued commitments aggregated into a merkle tree, whose tip is submitted
+/// to the backend without holding the aggregator.
+pub struct Batch {
+    /// Merkle tip of the batch
+    pub tip: Vec<u8>,
+    /// Commitments and the paths from each of them to the tip
+    paths: Vec<(String, Option<String>)>,
+    backend: Arc<Backend>,
+}
+
+/// Batch tips due for an upgrade, taken out of the aggregator so that the
+/// calendars and the Bitcoin node are queried without holding it.
+pub struct TipUpgrades {
+    due: DueUpgrades,
+    #[cfg(not(feature = "async"))]
+    now: i64,
+    /// Transactions committing to the unconfirmed tips, by tip
+    #[cfg(not(feature = "async"))]
+    anchors: Vec<(String, String)>,
+    #[cfg(not(feature = "async"))]
+    backend: Arc<Backend>,
+}
+
+/// Results of [`TipUpgrades::run`], recorded with
+/// [`Aggregator::record_upgrades`].
+pub struct TipResults {
+    upgrades: UpgradeResults,
+    /// Timestamps of the tips whose transaction confirmed, by tip
+    anchors: Vec<(String, Result<Option<Timestamp>, Error>)>,
+    /// Transactions replacing the stuck ones, by tip
+    replaced: Vec<(String, String)>,
+}
+
+/// Time the proofs of a batch are served for once complete, as clients
+/// usually upgrade their timestamps within hours.
+pub const DEFAULT_RETENTION: Duration = Duration::from_secs(90 * 24 * 60 * 60);
+
+/// Calendar aggregating submitted digests into batches, whose merkle tips
+/// are timestamped by the [`Backend`].
+///
+/// A submitted digest gets a pending attestation to the aggregator right
+/// away. Batch tips are tracked in a [`PendingStore`], upgraded from the
+/// upstream calendars or completed once their transaction confirms, and
+/// once a tip reaches a Bitcoin attestation the aggregator serves the
+/// complete proof of each commitment of its batch for [`Aggregator::retention`].
+///
+/// Submitted commitments are appended to a journal, the whole state is only
+/// written when batches are recorded.
+pub struct Aggregator {
+    path: PathBuf,
+    journal: PathBuf,
+    state: AggregatorState,
+    /// Store of the batch tip timestamps
+    pub scheduler: Scheduler,
+    /// Public URL of the aggregator, used in the pending attestations
+    pub uri: String,
+    pub backend: Arc<Backend>,
+    /// Time the proofs of a complete batch are served for
+    pub retention: Duration,
+}
+
+impl Aggregator {
+    /// Open the aggregator persisted in the directory `dir`, starting empty
+    /// if it does not exist yet.
+    pub fn open<P: AsRef<Path>>(dir: P, uri: &str, backend: Backend) -> Result<Self, Error> {
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir).map_err(|_| Error::IOError)?;
+        let path = dir.join("aggregator.json");
+        let mut state: AggregatorState = if path.exists() {
+            let content = fs::read_to_string(&path).map_err(|_| Error::IOError)?;
+            serde_json::from_str(&content).map_err(|_| Error::InvalidFile)?
+        } else {
+            AggregatorState::default()
+        };
+        let journal = dir.join("queue.log");
+        if journal.exists() {
+            let content = fs::read_to_string(&journal).map_err(|_| Error::IOError)?;
+            let mut queued: HashSet<String> = state.queue.iter().cloned().collect();
+            for commitment in content.lines() {
+                // A crash can leave the last line partially written
+                let valid = Vec::<u8>::from_hex(commitment).is_ok_and(|bytes| bytes.len() == 32);
+                if valid
+                    && !state.batched.contains_key(commitment)
+                    && queued.insert(commitment.to_string())
+                {
+                    state.queue.push(commitment.to_string());
+                }
+            }
+        }
+        let mut scheduler = Scheduler::new(PendingStore::open(dir.join("tips.json"))?);
+        if let Backend::Calendars { urls, .. } = &backend {
+            scheduler.calendar_urls = Some(urls.clone());
+        }
+        Ok(Aggregator {
+            path,
+            journal,
+            state,
+            scheduler,
+            uri: uri.trim_end_matches('/').to_string(),
+            backend: Arc::new(backend),
+            retention: DEFAULT_RETENTION,
+        })
+    }
+
+    /// Persist the state, replacing the previous content atomically, then
+    /// empty the journal it includes.
+    fn save(&self) -> Result<(), Error> {
+        let content =
+            serde_json::to_string_pretty(&self.state).map_err(|e| Error::Generic(e.to_string()))?;
+        atomic::write_file(&self.path, content.as_bytes())?;
+        match fs::remove_file(&self.journal) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(Error::IOError),
+            _ => Ok(()),
+        }
+    }
+
+    /// Append a queued commitment to the journal, synced to disk before its
+    /// submission is answered.
+    fn append_journal(&self, commitment: &str) -> Result<(), Error> {
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.journal)
+            .map_err(|_| Error::IOError)?;
+        file.write_all(format!("{}\n", commitment).as_bytes())
+            .and_then(|_| file.sync_data())
+            .map_err(|_| Error::IOError)
+    }
+
+    /// Number of commitments waiting for the next batch.
+    pub fn queued(&self) -> usize {
+        self.state.queue.len()
+    }
+
+    /// Queue `digest` for the next batch, returning its timestamp up to the
+    /// pending attestation of the aggregator.
+    pub fn submit(&mut self, digest: &[u8]) -> Result<Timestamp, Error> {
+        if digest.is_empty() || digest.len() > MAX_DIGEST_SIZE {
+            return Err(Error::Generic(format!(
+                "Digest must be 1 to {} bytes",
+                MAX_DIGEST_SIZE
+            )));
+        }
+        let nonce: Vec<u8> = (0..16).map(|_| rand::random::<u8>()).collect();
+        let nonce_op = Op::Append(nonce);
+        let nonce_output = nonce_op.execute(digest);
+        let commitment = Op::Sha256.execute(&nonce_output);
+        let hex = Hexed(&commitment).to_string();
+        self.append_journal(&hex)?;
+        self.state.queue.push(hex);
+        Ok(Timestamp {
+            start_digest: digest.to_vec(),
+            first_step: Step {
+                data: StepData::Op(nonce_op),
+                output: nonce_output,
+                next: vec![Step {
+                    data: StepData::Op(Op::Sha256),
+                    output: commitment.clone(),
+                    next: vec![Step {
+                        data: StepData::Attestation(Attestation::Pending {
+                            uri: self.uri.clone(),
+                        }),
+                        output: commitment,
+                        next: vec![],
+                    }],
+                }],
+            },
+        })
+    }
+
+    /// Complete timestamp of `commitment`, once its batch tip is attested
+    /// in Bitcoin.
+    pub fn timestamp(&self, commitment: &[u8]) -> Result<Option<Timestamp>, Error> {
+        let Some(batched) = self.state.batched.get(&Hexed(commitment).to_string()) else {
+            return Ok(None);
+        };
+        let Some(entry) = self.scheduler.store.get(&batched.tip) else {
+            return Ok(None);
+        };
+        if !entry.is_complete() {
+            return Ok(None);
+        }
+        let tip = entry.timestamp()?.timestamp;
+        match &batched.path {
+            Some(path) => {
+                let mut timestamp = pending::decode(path)?.timestamp;
+                timestamp.merge(tip);
+                Ok(Some(timestamp))
+            }
+            None => Ok(Some(tip)),
+        }
+    }
+
+    /// Aggregate the queued commitments into a batch, if any, to submit
+    /// with [`Batch::submit`] and record with [`Aggregator::record_batch`].
+    /// Commitments queued meanwhile wait for the next batch.
+    pub fn next_batch(&self) -> Result<Option<Batch>, Error> {
+        if self.state.queue.is_empty() {
+            return Ok(None);
+        }
+        let mut leaves: Vec<[u8; 32]> = vec![];
+        for commitment in &self.state.queue {
+            let leaf = Vec::<u8>::from_hex(commitment)
+                .ok()
+                .and_then(|leaf| leaf.try_into().ok())
+                .ok_or(Error::Generic(format!("Invalid commitment {}", commitment)))?;
+            leaves.push(leaf);
+        }
+        let merkle_tree = MerkleTree::<Sha256>::from_leaves(&leaves);
+        let tip = merkle_tree.root().unwrap().to_vec();
+        let mut paths = vec![];
+        for (commitment, leaf) in self.state.queue.iter().zip(leaves) {
+            let path = if self.state.queue.len() > 1 {
+                let timestamp = client::timestamp_from_merkle(&merkle_tree, leaf)?;
+                Some(pending::encode(&DetachedTimestampFile {
+                    digest_type: DigestType::Sha256,
+                    timestamp,
+                })?)
+            } else {
+                None
+            };
+            paths.push((commitment.clone(), path));
+        }
+        Ok(Some(Batch {
+            tip,
+            paths,
+            backend: self.backend.clone(),
+        }))
+    }
+
+    /// Record how the backend took the tip of `batch`, moving its
+    /// commitments out of the queue, and return their number. The queue is
+    /// kept when the backend did not accept the tip.
+    pub fn record_batch(
+        &mut self,
+        batch: Batch,
+        submitted: Result<Submitted, Error>,
+    ) -> Result<usize, Error> {
+        let now = chrono::Utc::now().timestamp();
+        let tip = match submitted? {
+            Submitted::Timestamp(timestamp) => self.add_tip(timestamp, now)?,
+            #[cfg(not(feature = "async"))]
+            Submitted::Anchor(txid) => {
+                let tip = Hexed(&batch.tip).to_string();
+                self.state.anchors.insert(tip.clone(), txid.to_string());
+                tip
+            }
+        };
+        let count = batch.paths.len();
+        let commitments: HashSet<&String> = batch
+            .paths
+            .iter()
+            .map(|(commitment, _)| commitment)
+            .collect();
+        self.state
+            .queue
+            .retain(|queued| !commitments.contains(queued));
+        for (commitment, path) in batch.paths {
+            let batched = BatchedCommitment {
+                tip: tip.clone(),
+                path,
+            };
+            self.state.batched.insert(commitment, batched);
+        }
+        info!("Batch of {} commitments with tip {}", count, tip);
+        self.scheduler.store.save()?;
+        self.save()?;
+        Ok(count)
+    }
+
+    /// Track the timestamp of a batch tip, returning its id.
+    fn add_tip(&mut self, timestamp: Timestamp, now: i64) -> Result<String, Error> {
+        let ots = DetachedTimestampFile {
+            digest_type: DigestType::Sha256,
+            timestamp,
+        };
+        self.scheduler.store.add(&ots, None, now)
+    }
+
+    /// Batch tips due for an upgrade at `now`, to query with
+    /// [`TipUpgrades::run`] and record with [`Aggregator::record_upgrades`].
+    pub fn due_upgrades(&self, now: i64) -> Result<TipUpgrades, Error> {
+        Ok(TipUpgrades {
+            due: self.scheduler.due(now)?,
+            #[cfg(not(feature = "async"))]
+            now,
+            #[cfg(not(feature = "async"))]
+            anchors: self
+                .state
+                .anchors
+                .iter()
+                .map(|(tip, txid)| (tip.clone(), txid.clone()))
+                .collect(),
+            #[cfg(not(feature = "async"))]
+            backend: self.backend.clone(),
+        })
+    }
+
+    /// Record the upgrades of the batch tips made at `now`, then expire the
+    /// batches complete for longer than the retention, returning the ids of
+    /// the upgraded tips. A tip whose transaction could not be checked is
+    /// checked again on the next upgrade.
+    pub fn record_upgrades(&mut self, now: i64, results: TipResults) -> Result<Vec<String>, Error> {
+        // Batch tips are served from the store, there is no file to write
+        let mut upgraded = self
+            .scheduler
+            .record_all(now, results.upgrades, |_, _| Ok(()))?;
+        let mut completed = vec![];
+        for (tip, timestamp) in results.anchors {
+            let timestamp = match timestamp {
+                Ok(timestamp) => timestamp,
+                Err(e) => {
+                    warn!(
+                        "Could not check the transaction of batch tip {}: {}",
+                        tip, e
+                    );
+                    None
+                }
+            };
+            if let Some(timestamp) = timestamp {
+                let height = client::bitcoin_height(&DetachedTimestampFile {
+                    digest_type: DigestType::Sha256,
+                    timestamp: timestamp.clone(),
+                });
+                let id = self.add_tip(timestamp, now)?;
+                let entry = self.scheduler.store.get_mut(&id).unwrap();
+                entry.completed = Some(now);
+                entry.height = height;
+                self.state.anchors.remove(&tip);
+                completed.push(id);
+            }
+        }
+        let replaced = results.replaced.len();
+        for (tip, txid) in results.replaced {
+            if self.state.anchors.contains_key(&tip) {
+                self.state.anchors.insert(tip, txid);
+            }
+        }
+        let expired = self.expire(now);
+        if !completed.is_empty() || replaced > 0 || expired > 0 {
+            self.scheduler.store.save()?;
+            self.save()?;
+        }
+        upgraded.append(&mut completed);
+        Ok(upgraded)
+    }
+
+    /// Forget the batches complete for longer than the retention at `now`,
+    /// returning the number of commitments expired.
+    fn expire(&mut self, now: i64) -> usize {
+        let retention = self.retention.as_secs() as i64;
+        let expired: HashSet<String> = self
+            .scheduler
+            .store
+            .entries()
+            .filter(|(_, entry)| entry.completed.is_some_and(|at| at + retention < now))
+            .map(|(id, _)| id.clone())
+            .collect();
+        if expired.is_empty() {
+            return 0;
+        }
+        let before = self.state.batched.len();
+        self.state
+            .batched
+            .retain(|_, batched| !expired.contains(&batched.tip));
+        for id in &expired {
+            self.scheduler.store.remove(id);
+        }
+        let count = before - self.state.batched.len();
+        info!("Expired {} batches of {} commitments", expired.len(), count);
+        count
+    }
+}
+
+#[cfg(not(feature = "async"))]
+impl Backend {
+    /// Submit a batch tip to the upstream calendars or commit it to Bitcoin.
+    pub fn submit(&self, tip: &[u8]) -> Result<Submitted, Error> {
+        debug!("Submitting batch tip {}", Hexed(tip));
+        match self {
+            Backend::Calendars { urls, timeout } => {
+                let (timestamp, _) =
+                    client::calendar_timestamp(tip.to_vec(), urls.clone(), *timeout)?;
+                Ok(Submitted::Timestamp(timestamp))
+            }
+            Backend::Bitcoin(anchor) => Ok(Submitted::Anchor(anchor.send(tip)?)),
+        }
+    }
+}
+
+#[cfg(not(feature = "async"))]
+impl Batch {
+    /// Submit the tip of the batch to the backend.
+    pub fn submit(&self) -> Result<Submitted, Error> {
+        self.backend.submit(&self.tip)
+    }
+}
+
+#[cfg(not(feature = "async"))]
+impl TipUpgrades {
+    /// Query the upstream calendars for the due tips and the Bitcoin node
+    /// for the transactions of the unconfirmed ones, rebroadcasting or
+    /// bumping the fee of the stuck ones.
+    pub fn run(self) -> TipResults {
+        let mut anchors = vec![];
+        let mut replaced = vec![];
+        if let Backend::Bitcoin(anchor) = self.backend.as_ref() {
+            for (tip, txid) in self.anchors {
+                let digest = Vec::<u8>::from_hex(&tip)
+                    .map_err(|_| Error::Generic(format!("Invalid batch tip {}", tip)));
+                let txid = txid
+                    .parse::<bitcoincore_rpc::bitcoin::Txid>()
+                    .map_err(|_| Error::Generic(format!("Invalid txid {}", txid)));
+                let timestamp = digest.and_then(|digest| {
+                    let txid = txid?;
+                    let timestamp = anchor.timestamp(&digest, &txid)?;
+                    if timestamp.is_none() {
+                        match anchor.unstick(&txid, self.now) {
+                            Ok(Some(replacement)) => {
+                                replaced.push((tip.clone(), replacement.to_string()))
+                            }
+                            Ok(None) => {}
+                            Err(e) => warn!("Could not unstick transaction {}: {}", txid, e),
+                        }
+                    }
+                    Ok(timestamp)
+                });
+                anchors.push((tip, timestamp));
+            }
+        }
+        TipResults {
+            upgrades: self.due.run(),
+            anchors,
+            replaced,
+        }
+    }
+}
+
+#[cfg(not(feature = "async"))]
+impl Aggregator {
+    /// Submit the tip of the queued commitments to the backend, returning
+    /// the number of commitments batched. The queue is kept when the
+    /// backend does not accept the tip.
+    pub fn flush(&mut self) -> Result<usize, Error> {
+        let Some(batch) = self.next_batch()? else {
+            return Ok(0);
+        };
+        let submitted = batch.submit();
+        self.record_batch(batch, submitted)
+    }
+
+    /// Upgrade the batch tips due at `now` from the upstream calendars, or
+    /// complete the tips whose transaction confirmed, returning their ids.
+    pub fn upgrade(&mut self, now: i64) -> Result<Vec<String>, Error> {
+        let results = self.due_upgrades(now)?.run();
+        self.record_upgrades(now, results)
+    }
+}
+
+#[cfg(feature = "async")]
+impl Backend {
+    /// Submit a batch tip to the upstream calendars.
+    pub async fn submit(&self, tip: &[u8]) -> Result<Submitted, Error> {
+        debug!("Submitting batch tip {}", Hexed(tip));
+        let Backend::Calendars { urls, timeout } = self;
+        let (timestamp, _) =
+            client::calendar_timestamp(tip.to_vec(), urls.clone(), *timeout).await?;
+        Ok(Submitted::Timestamp(timestamp))
+    }
+}
+
+#[cfg(feature = "async")]
+impl Batch {
+    /// Submit the tip of the batch to the backend.
+    pub async fn submit(&self) -> Result<Submitted, Error> {
+        self.backend.submit(&self.tip).await
+    }
+}
+
+#[cfg(feature = "async")]
+impl TipUpgrades {
+    /// Query the upstream calendars for the due tips.
+    pub async fn run(self) -> TipResults {
+        TipResults {
+            upgrades: self.due.run().await,
+            anchors: vec![],
+            replaced: vec![],
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+impl Aggregator {
+    /// Submit the tip of the queued commitments to the backend, returning
+    /// the number of commitments batched. The queue is kept when the
+    /// backend does not accept the tip.
+    pub async fn flush(&mut self) -> Result<usize, Error> {
+        let Some(batch) = self.next_batch()? else {
+            return Ok(0);
+        };
+        let submitted = batch.submit().await;
+        self.record_batch(batch, submitted)
+    }
+
+    /// Upgrade the batch tips due at `now` from the upstream calendars.
+    pub async fn upgrade(&mut self, now: i64) -> Result<Vec<String>, Error> {
+        let results = self.due_upgrades(now)?.run().await;
+        self.record_upgrades(now, results)
+    }
+}
+
+/// Serialize a timestamp as calendars return it, without the file header.
+pub fn serialize_timestamp(timestamp: &Timestamp) -> Result<Vec<u8>, Error> {
+    let mut buffer = vec![];
+    let mut ser = Serializer::new(&mut buffer);
+    timestamp.serialize(&mut ser).map_err(Error::InvalidOts)?;
+    Ok(buffer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::anchor::linear_timestamp;
+
+    fn aggregator(name: &str) -> (Aggregator, PathBuf) {
+        let dir =
+            std::env::temp_dir().join(format!("ots-aggregator-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        (open(&dir), dir)
+    }
+
+    fn open(dir: &Path) -> Aggregator {
+        let backend = Backend::Calendars {
+            urls: vec![],
+            timeout: None,
+        };
+        Aggregator::open(dir, "http://127.0.0.1:14788/", backend).unwrap()
+    }
+
+    /// Commitment the timestamp returned by `submit` is pending at.
+    fn commitment(timestamp: &Timestamp) -> Vec<u8> {
+        timestamp.all_attestations().into_keys().next().unwrap()
+    }
+
+    /// Timestamp of a batch tip attested in Bitcoin.
+    fn attested(batch: &Batch) -> Result<Submitted, Error> {
+        Ok(Submitted::Timestamp(linear_timestamp(
+            batch.tip.clone(),
+            vec![Op::Sha256],
+            Attestation::Bitcoin { height: 800_000 },
+        )))
+    }
+
+    #[test]
+    fn submit_is_journaled() {
+        let (mut aggregator, dir) = aggregator("journal");
+        assert!(aggregator.submit(&[]).is_err());
+        assert!(aggregator.submit(&[0; MAX_DIGEST_SIZE + 1]).is_err());
+        let timestamp = aggregator.submit(&[1; 32]).unwrap();
+        aggregator.submit(&[2; 32]).unwrap();
+        assert!(!aggregator.path.exists());
+        assert!(matches!(
+            timestamp.all_attestations().into_values().next(),
+            Some(Attestation::Pending { uri }) if uri == "http://127.0.0.1:14788"
+        ));
+
+        // A partial line left by a crash is skipped
+        let mut journal = fs::OpenOptions::new()
+            .append(true)
+            .open(&aggregator.journal)
+            .unwrap();
+        journal.write_all(b"12ab").unwrap();
+        assert_eq!(open(&dir).queued(), 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn batch_keeps_later_submissions() {
+        let (mut aggregator, dir) = aggregator("batch");
+        let first = commitment(&aggregator.submit(&[1; 32]).unwrap());
+        let second = commitment(&aggregator.submit(&[2; 32]).unwrap());
+        let batch = aggregator.next_batch().unwrap().unwrap();
+        // Submitted while the backend is queried
+        let third = commitment(&aggregator.submit(&[3; 32]).unwrap());
+        let submitted = attested(&batch);
+        assert_eq!(aggregator.record_batch(batch, submitted).unwrap(), 2);
+        assert_eq!(aggregator.queued(), 1);
+        assert!(!aggregator.journal.exists());
+
+        // Served once the tip is complete
+        assert!(aggregator.timestamp(&first).unwrap().is_none());
+        let tip = aggregator.state.batched[&Hexed(&first).to_string()]
+            .tip
+            .clone();
+        aggregator.scheduler.store.get_mut(&tip).unwrap().completed = Some(0);
+        for commitment in [&first, &second] {
+            let timestamp = aggregator.timestamp(commitment).unwrap().unwrap();
+            assert_eq!(&timestamp.start_digest, commitment);
+            assert!(client::bitcoin_height(&DetachedTimestampFile {
+                digest_type: DigestType::Sha256,
+                timestamp,
+            })
+            .is_some());
+        }
+        assert!(aggregator.timestamp(&third).unwrap().is_none());
+
+        let reopened = open(&dir);
+        assert_eq!(reopened.queued(), 1);
+        assert_eq!(reopened.state.batched.len(), 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn failed_submission_keeps_queue() {
+        let (mut aggregator, dir) = aggregator("failed");
+        aggregator.submit(&[1; 32]).unwrap();
+        let batch = aggregator.next_batch().unwrap().unwrap();
+        let failure = Err(Error::Generic("unreachable".to_string()));
+        assert!(aggregator.record_batch(batch, failure).is_err());
+        assert_eq!(aggregator.queued(), 1);
+        assert!(aggregator.state.batched.is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn complete_batches_expire() {
+        let (mut aggregator, dir) = aggregator("expire");
+        aggregator.retention = Duration::from_secs(100);
+        let commitment = commitment(&aggregator.submit(&[1; 32]).unwrap());
+        let batch = aggregator.next_batch().unwrap().unwrap();
+        let submitted = attested(&batch);
+        aggregator.record_batch(batch, submitted).unwrap();
+        let tip = aggregator.state.batched[&Hexed(&commitment).to_string()]
+            .tip
+            .clone();
+        aggregator.scheduler.store.get_mut(&tip).unwrap().completed = Some(1000);
+
+        let nothing = || TipResults {
+            upgrades: vec![],
+            anchors: vec![],
+            replaced: vec![],
+        };
+        aggregator.record_upgrades(1100, nothing()).unwrap();
+        assert!(aggregator.timestamp(&commitment).unwrap().is_some());
+        aggregator.record_upgrades(1101, nothing()).unwrap();
+        assert!(aggregator.timestamp(&commitment).unwrap().is_none());
+        assert!(aggregator.state.batched.is_empty());
+        assert!(aggregator.scheduler.store.get(&tip).is_none());
+        assert!(open(&dir).state.batched.is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// End-to-end run against a regtest node, with the wallet RPC URL in
+    /// `OTS_REGTEST_RPC` and its cookie file in `OTS_REGTEST_COOKIE`.
+    #[cfg(not(feature = "async"))]
+    #[test]
+    #[ignore]
+    fn regtest_anchor() {
+        use crate::anchor::BitcoinAnchor;
+        use bitcoincore_rpc::bitcoin::hashes::Hash;
+        use bitcoincore_rpc::{Auth, Client, RpcApi};
+
+        let url = std::env::var("OTS_REGTEST_RPC").expect("OTS_REGTEST_RPC");
+        let cookie = std::env::var("OTS_REGTEST_COOKIE").expect("OTS_REGTEST_COOKIE");
+        let client = || Client::new(&url, Auth::CookieFile(cookie.clone().into())).unwrap();
+        let miner = client();
+        let address = miner.get_new_address(None, None).unwrap().assume_checked();
+        let mine = |blocks| miner.generate_to_address(blocks, &address).unwrap();
+        mine(101);
+
+        let mut anchor = BitcoinAnchor::new(client());
+        anchor.confirmations = 1;
+        anchor.bump_after = Duration::ZERO;
+        let dir =
+            std::env::temp_dir().join(format!("ots-aggregator-regtest-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let mut aggregator =
+            Aggregator::open(&dir, "http://127.0.0.1:14788", Backend::Bitcoin(anchor)).unwrap();
+        let commitments: Vec<Vec<u8>> = (1..=3)
+            .map(|byte| commitment(&aggregator.submit(&[byte; 32]).unwrap()))
+            .collect();
+        assert_eq!(aggregator.flush().unwrap(), 3);
+        let sent = aggregator.state.anchors.values().next().unwrap().clone();
+
+        // Unconfirmed, so the fee is bumped at once
+        let now = chrono::Utc::now().timestamp();
+        assert!(aggregator.upgrade(now).unwrap().is_empty());
+        let bumped = aggregator.state.anchors.values().next().unwrap().clone();
+        assert_ne!(sent, bumped);
+
+        let block = mine(1)[0];
+        assert_eq!(aggregator.upgrade(now).unwrap().len(), 1);
+        assert!(aggregator.state.anchors.is_empty());
+        let header = miner.get_block_header(&block).unwrap();
+        let height = miner.get_block_header_info(&block).unwrap().height;
+        for commitment in &commitments {
+            let timestamp = aggregator.timestamp(commitment).unwrap().unwrap();
+            let attestations = timestamp.all_attestations();
+            let (root, attestation) = attestations.iter().next().unwrap();
+            assert!(matches!(attestation, Attestation::Bitcoin { height: h } if *h == height));
+            assert_eq!(root, &header.merkle_root.to_byte_array().to_vec());
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
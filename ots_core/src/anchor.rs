@@ -0,0 +1,465 @@
+// Copyright (C) 2024 The OpenTimestamps developers
+
+use crate::error::Error;
+
+use bitcoincore_rpc::bitcoin::consensus::encode::{deserialize, serialize};
+use bitcoincore_rpc::bitcoin::hashes::{sha256d, Hash};
+use bitcoincore_rpc::bitcoin::{Block, Transaction, TxMerkleNode, Txid};
+#[cfg(not(feature = "async"))]
+use bitcoincore_rpc::json::{FundRawTransactionOptions, WalletTxInfo};
+#[cfg(not(feature = "async"))]
+use bitcoincore_rpc::RpcApi;
+#[cfg(not(feature = "async"))]
+use log::{debug, info};
+use opentimestamps::attestation::Attestation;
+use opentimestamps::hex::Hexed;
+use opentimestamps::op::Op;
+use opentimestamps::timestamp::{Step, StepData};
+use opentimestamps::Timestamp;
+#[cfg(not(feature = "async"))]
+use std::time::Duration;
+
+/// Timestamp made of the `ops` applied in sequence to `start_digest`,
+/// ending in `attestation`.
+pub fn linear_timestamp(
+    start_digest: Vec<u8>,
+    ops: Vec<Op>,
+    attestation: Attestation,
+) -> Timestamp {
+    let mut outputs = vec![];
+    let mut digest = start_digest.clone();
+    for op in &ops {
+        digest = op.execute(&digest);
+        outputs.push(digest.clone());
+    }
+    let mut step = Step {
+        data: StepData::Attestation(attestation),
+        output: digest,
+        next: vec![],
+    };
+    for (op, output) in ops.into_iter().zip(outputs).rev() {
+        step = Step {
+            data: StepData::Op(op),
+            output,
+            next: vec![step],
+        };
+    }
+    Timestamp {
+        start_digest,
+        first_step: step,
+    }
+}
+
+/// Decode a raw, consensus-serialized transaction.
+pub fn decode_transaction(raw: &[u8]) -> Result<Transaction, Error> {
+    deserialize(raw).map_err(|e| Error::Generic(format!("Invalid transaction: {}", e)))
+}
+
+/// Serialization of `tx` its txid commits to, without witnesses.
+fn txid_serialization(tx: &Transaction) -> Vec<u8> {
+    let mut stripped = tx.clone();
+    for input in stripped.input.iter_mut() {
+        input.witness.clear();
+    }
+    serialize(&stripped)
+}
+
+/// Operations turning `digest` into the txid of `tx`, which must contain it,
+/// usually in an `OP_RETURN` output.
+pub fn tx_ops(tx: &Transaction, digest: &[u8]) -> Result<Vec<Op>, Error> {
+    let bytes = txid_serialization(tx);
+    let position = bytes
+        .windows(digest.len())
+        .position(|window| window == digest)
+        .ok_or(Error::Generic(format!(
+            "Transaction {} does not contain {}",
+            tx.txid(),
+            Hexed(digest)
+        )))?;
+    Ok(vec![
+        Op::Prepend(bytes[..position].to_vec()),
+        Op::Append(bytes[position + digest.len()..].to_vec()),
+        Op::Sha256,
+        Op::Sha256,
+    ])
+}
+
+/// Merkle branch from a transaction to the merkle root of its block.
+///
+/// Hashes are in internal byte order, the reverse of the hex displayed by
+/// block explorers and returned by Electrum's `blockchain.transaction.get_merkle`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MerkleBranch {
+    /// Position of the transaction in the block
+    pub index: usize,
+    /// Sibling hashes, from the transactions level up to the root
+    pub siblings: Vec<[u8; 32]>,
+}
+
+impl MerkleBranch {
+    /// Branch of the transaction at `index` among all the `txids` of a block,
+    /// duplicating the last node of odd levels as Bitcoin does.
+    pub fn from_txids(txids: &[Txid], index: usize) -> Result<Self, Error> {
+        if index >= txids.len() {
+            return Err(Error::Generic(format!(
+                "Transaction index {} out of {} transactions",
+                index,
+                txids.len()
+            )));
+        }
+        let mut level: Vec<[u8; 32]> = txids.iter().map(|txid| txid.to_byte_array()).collect();
+        let mut position = index;
+        let mut siblings = vec![];
+        while level.len() > 1 {
+            if level.len() % 2 == 1 {
+                level.push(*level.last().unwrap());
+            }
+            siblings.push(level[position ^ 1]);
+            level = level
+                .chunks(2)
+                .map(|pair| sha256d::Hash::hash(&pair.concat()).to_byte_array())
+                .collect();
+            position /= 2;
+        }
+        Ok(MerkleBranch { index, siblings })
+    }
+
+    /// Operations turning the txid into the merkle root.
+    pub fn ops(&self) -> Vec<Op> {
+        let mut ops = vec![];
+        for (level, sibling) in self.siblings.iter().enumerate() {
+            if (self.index >> level) % 2 == 1 {
+                ops.push(Op::Prepend(sibling.to_vec()));
+            } else {
+                ops.push(Op::Append(sibling.to_vec()));
+            }
+            ops.push(Op::Sha256);
+            ops.push(Op::Sha256);
+        }
+        ops
+    }
+
+    /// Merkle root the branch leads to from `txid`.
+    pub fn root(&self, txid: &Txid) -> TxMerkleNode {
+        let mut digest = txid.to_byte_array().to_vec();
+        for op in self.ops() {
+            digest = op.execute(&digest);
+        }
+        TxMerkleNode::from_slice(&digest).unwrap()
+    }
+}
+
+/// Timestamp of `digest`, committed in `tx`, through the merkle `branch` of
+/// `tx` up to an attestation of the Bitcoin block at `height`.
+pub fn bitcoin_timestamp(
+    digest: &[u8],
+    tx: &Transaction,
+    branch: &MerkleBranch,
+    height: u32,
+) -> Result<Timestamp, Error> {
+    let mut ops = tx_ops(tx, digest)?;
+    ops.extend(branch.ops());
+    Ok(linear_timestamp(
+        digest.to_vec(),
+        ops,
+        Attestation::Bitcoin {
+            height: height as usize,
+        },
+    ))
+}
+
+/// Timestamp of `digest`, committed in the transaction `txid` of `block`,
+/// the block at `height`.
+pub fn block_timestamp(
+    digest: &[u8],
+    txid: &Txid,
+    block: &Block,
+    height: u32,
+) -> Result<Timestamp, Error> {
+    let txids: Vec<Txid> = block.txdata.iter().map(|tx| tx.txid()).collect();
+    let index = txids
+        .iter()
+        .position(|id| id == txid)
+        .ok_or(Error::Generic(format!("Transaction {} not in block", txid)))?;
+    let branch = MerkleBranch::from_txids(&txids, index)?;
+    if branch.root(txid) != block.header.merkle_root {
+        return Err(Error::Generic(format!(
+            "Block {} merkle root mismatch",
+            block.block_hash()
+        )));
+    }
+    bitcoin_timestamp(digest, &block.txdata[index], &branch, height)
+}
+
+/// Commits digests to Bitcoin in `OP_RETURN` outputs of transactions funded
+/// by the wallet of a Bitcoin Core node.
+///
+/// Transactions signal replaceability, so that the fee of the ones stuck in
+/// the mempool can be bumped.
+#[cfg(not(feature = "async"))]
+pub struct BitcoinAnchor {
+    client: bitcoincore_rpc::Client,
+    /// Confirmations a transaction needs before its timestamp is complete
+    pub confirmations: u32,
+    /// Time a transaction can stay unconfirmed before its fee is bumped
+    pub bump_after: Duration,
+}
+
+#[cfg(not(feature = "async"))]
+impl BitcoinAnchor {
+    pub fn new(client: bitcoincore_rpc::Client) -> Self {
+        BitcoinAnchor {
+            client,
+            confirmations: 6,
+            bump_after: Duration::from_secs(6 * 60 * 60),
+        }
+    }
+
+    /// Broadcast a transaction committing to `digest`, returning its txid.
+    pub fn send(&self, digest: &[u8]) -> Result<Txid, Error> {
+        let outputs = serde_json::json!([{ "data": Hexed(digest).to_string() }]);
+        let raw: String = self
+            .client
+            .call("createrawtransaction", &[serde_json::json!([]), outputs])
+            .map_err(|_| Error::BitcoinNodeError)?;
+        let options = FundRawTransactionOptions {
+            replaceable: Some(true),
+            ..Default::default()
+        };
+        let funded = self
+            .client
+            .fund_raw_transaction(raw.as_str(), Some(&options), None)
+            .map_err(|_| Error::BitcoinNodeError)?;
+        let signed = self
+            .client
+            .sign_raw_transaction_with_wallet(&funded.hex, None, None)
+            .map_err(|_| Error::BitcoinNodeError)?;
+        if !signed.complete {
+            return Err(Error::Generic(
+                "Wallet could not sign the anchor transaction".to_string(),
+            ));
+        }
+        let txid = self
+            .client
+            .send_raw_transaction(&signed.hex)
+            .map_err(|_| Error::BitcoinNodeError)?;
+        info!("Committed {} in transaction {}", Hexed(digest), txid);
+        Ok(txid)
+    }
+
+    /// Timestamp of `digest` attested by the block of `txid`, or of the
+    /// transaction which replaced it, once it has enough confirmations.
+    pub fn timestamp(&self, digest: &[u8], txid: &Txid) -> Result<Option<Timestamp>, Error> {
+        let tx = self
+            .client
+            .get_transaction(txid, None)
+            .map_err(|_| Error::BitcoinNodeError)?;
+        if tx.info.confirmations < 0 {
+            // A fee bump, or the transaction it replaced, confirmed instead
+            for conflict in &tx.info.wallet_conflicts {
+                let confirmed = self
+                    .client
+                    .get_transaction(conflict, None)
+                    .map_err(|_| Error::BitcoinNodeError)?;
+                if confirmed.info.confirmations > 0 {
+                    debug!("Transaction {} replaced by {}", txid, conflict);
+                    return self.confirmed_timestamp(digest, conflict, &confirmed.info);
+                }
+            }
+        }
+        self.confirmed_timestamp(digest, txid, &tx.info)
+    }
+
+    fn confirmed_timestamp(
+        &self,
+        digest: &[u8],
+        txid: &Txid,
+        info: &WalletTxInfo,
+    ) -> Result<Option<Timestamp>, Error> {
+        let confirmations = info.confirmations.max(0) as u32;
+        let Some(block_hash) = info
+            .blockhash
+            .filter(|_| confirmations >= self.confirmations)
+        else {
+            debug!("Transaction {} has {} confirmations", txid, confirmations);
+            return Ok(None);
+        };
+        let block = self
+            .client
+            .get_block(&block_hash)
+            .map_err(|_| Error::BitcoinNodeError)?;
+        let height = self
+            .client
+            .get_block_header_info(&block_hash)
+            .map_err(|_| Error::BitcoinNodeError)?
+            .height as u32;
+        block_timestamp(digest, txid, &block, height).map(Some)
+    }
+
+    /// Rebroadcast the unconfirmed transaction `txid` if the node dropped it
+    /// from its mempool, or bump its fee once it has been unconfirmed for
+    /// [`BitcoinAnchor::bump_after`] at `now`, returning the replacement.
+    pub fn unstick(&self, txid: &Txid, now: i64) -> Result<Option<Txid>, Error> {
+        let tx = self
+            .client
+            .get_transaction(txid, None)
+            .map_err(|_| Error::BitcoinNodeError)?;
+        if tx.info.confirmations != 0 {
+            return Ok(None);
+        }
+        if self.client.get_mempool_entry(txid).is_err() {
+            info!("Rebroadcasting transaction {}", txid);
+            self.client
+                .send_raw_transaction(&tx.hex)
+                .map_err(|_| Error::BitcoinNodeError)?;
+            return Ok(None);
+        }
+        if now < tx.info.time as i64 + self.bump_after.as_secs() as i64 {
+            return Ok(None);
+        }
+        let bumped: serde_json::Value = self
+            .client
+            .call("bumpfee", &[serde_json::json!(txid.to_string())])
+            .map_err(|_| Error::BitcoinNodeError)?;
+        let replacement: Txid = bumped["txid"]
+            .as_str()
+            .and_then(|replacement| replacement.parse().ok())
+            .ok_or(Error::BitcoinNodeError)?;
+        info!("Bumped the fee of transaction {} in {}", txid, replacement);
+        Ok(Some(replacement))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoincore_rpc::bitcoin::blockdata::script::Builder;
+    use bitcoincore_rpc::bitcoin::blockdata::transaction::{OutPoint, TxIn, TxOut};
+    use bitcoincore_rpc::bitcoin::hex::FromHex;
+    use bitcoincore_rpc::bitcoin::merkle_tree;
+    use bitcoincore_rpc::bitcoin::opcodes::all::OP_RETURN;
+    use bitcoincore_rpc::bitcoin::{absolute, transaction, Amount, ScriptBuf, Sequence, Witness};
+
+    /// Mainnet block 00000000b0c5a240b2a61d2e75692224efd4cbecdf6eaf4cc2cf477ca7c270e7,
+    /// with two transactions, as in the rust-bitcoin tests.
+    const BLOCK: &str = "010000004ddccd549d28f385ab457e98d1b11ce80bfea2c5ab93015ade4973e400000000bf4473e53794beae34e64fccc471dace6ae544180816f89591894e0f417a914cd74d6e49ffff001d323b3a7b0201000000010000000000000000000000000000000000000000000000000000000000000000ffffffff0804ffff001d026e04ffffffff0100f2052a0100000043410446ef0102d1ec5240f0d061a4246c1bdef63fc3dbab7733052fbbf0ecd8f41fc26bf049ebb4f9527f374280259e7cfa99c48b0e3f39c51347a19a5819651503a5ac00000000010000000321f75f3139a013f50f315b23b0c9a2b6eac31e2bec98e5891c924664889942260000000049483045022100cb2c6b346a978ab8c61b18b5e9397755cbd17d6eb2fe0083ef32e067fa6c785a02206ce44e613f31d9a6b0517e46f3db1576e9812cc98d159bfdaf759a5014081b5c01ffffffff79cda0945903627c3da1f85fc95d0b8ee3e76ae0cfdc9a65d09744b1f8fc85430000000049483045022047957cdd957cfd0becd642f6b84d82f49b6cb4c51a91f49246908af7c3cfdf4a022100e96b46621f1bffcf5ea5982f88cef651e9354f5791602369bf5a82a6cd61a62501fffffffffe09f5fe3ffbf5ee97a54eb5e5069e9da6b4856ee86fc52938c2f979b0f38e82000000004847304402204165be9a4cbab8049e1af9723b96199bfd3e85f44c6b4c0177e3962686b26073022028f638da23fc003760861ad481ead4099312c60030d4cb57820ce4d33812a5ce01ffffffff01009d966b01000000434104ea1feff861b51fe3f5f8a3b12d0f4712db80e919548a80839fc47c6a21e66d957e9c5d8cd108c7a2d2324bad71f9904ac0ae7336507d785b17a2c115e427a32fac00000000";
+
+    fn block() -> Block {
+        deserialize(&Vec::<u8>::from_hex(BLOCK).unwrap()).unwrap()
+    }
+
+    fn execute(digest: &[u8], ops: &[Op]) -> Vec<u8> {
+        ops.iter()
+            .fold(digest.to_vec(), |digest, op| op.execute(&digest))
+    }
+
+    fn txids(count: u8) -> Vec<Txid> {
+        (0..count)
+            .map(|byte| Txid::from_byte_array([byte; 32]))
+            .collect()
+    }
+
+    /// Transaction committing to `digest` in two `OP_RETURN` outputs.
+    fn committing_twice(digest: &[u8; 32]) -> Transaction {
+        let output = TxOut {
+            value: Amount::ZERO,
+            script_pubkey: Builder::new()
+                .push_opcode(OP_RETURN)
+                .push_slice(digest)
+                .into_script(),
+        };
+        Transaction {
+            version: transaction::Version::TWO,
+            lock_time: absolute::LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::new(Txid::from_byte_array([9; 32]), 0),
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+                witness: Witness::from_slice(&[vec![1; 64]]),
+            }],
+            output: vec![output.clone(), output],
+        }
+    }
+
+    #[test]
+    fn branch_of_real_block() {
+        let block = block();
+        let txids: Vec<Txid> = block.txdata.iter().map(|tx| tx.txid()).collect();
+        assert_eq!(txids.len(), 2);
+        for index in 0..txids.len() {
+            let branch = MerkleBranch::from_txids(&txids, index).unwrap();
+            assert_eq!(branch.siblings.len(), 1);
+            assert_eq!(branch.root(&txids[index]), block.header.merkle_root);
+            assert_eq!(
+                execute(&txids[index].to_byte_array(), &branch.ops()),
+                block.header.merkle_root.to_byte_array()
+            );
+        }
+        assert!(MerkleBranch::from_txids(&txids, 2).is_err());
+    }
+
+    #[test]
+    fn branch_of_odd_levels() {
+        for count in 1..=9 {
+            let txids = txids(count);
+            let expected = merkle_tree::calculate_root(txids.iter().map(|txid| txid.to_raw_hash()))
+                .map(TxMerkleNode::from_raw_hash)
+                .unwrap();
+            for (index, txid) in txids.iter().enumerate() {
+                let branch = MerkleBranch::from_txids(&txids, index).unwrap();
+                assert_eq!(branch.index, index);
+                assert_eq!(branch.root(txid), expected, "{} of {}", index, count);
+            }
+        }
+        // A lone transaction is the root
+        let single = MerkleBranch::from_txids(&txids(1), 0).unwrap();
+        assert!(single.siblings.is_empty() && single.ops().is_empty());
+        // The last of three is paired with itself
+        let last = MerkleBranch::from_txids(&txids(3), 2).unwrap();
+        assert_eq!(last.siblings[0], [2; 32]);
+    }
+
+    #[test]
+    fn tx_ops_of_real_transaction() {
+        let block = block();
+        let tx = &block.txdata[1];
+        // The first input spends this txid
+        let digest = tx.input[0].previous_output.txid.to_byte_array();
+        let ops = tx_ops(tx, &digest).unwrap();
+        assert_eq!(execute(&digest, &ops), tx.txid().to_byte_array());
+        assert!(tx_ops(tx, &[0xab; 32]).is_err());
+    }
+
+    #[test]
+    fn tx_ops_of_repeated_digest() {
+        let digest = [7; 32];
+        let tx = committing_twice(&digest);
+        let ops = tx_ops(&tx, &digest).unwrap();
+        // Witnesses are not committed to by the txid
+        assert_eq!(execute(&digest, &ops), tx.txid().to_byte_array());
+        let Op::Append(rest) = &ops[1] else {
+            panic!("Expected an append, got {:?}", ops[1]);
+        };
+        assert!(rest.windows(32).any(|window| window == digest));
+    }
+
+    #[test]
+    fn timestamp_of_real_block() {
+        let block = block();
+        let tx = &block.txdata[1];
+        let digest = tx.input[0].previous_output.txid.to_byte_array();
+        let timestamp = block_timestamp(&digest, &tx.txid(), &block, 546).unwrap();
+        assert_eq!(timestamp.start_digest, digest);
+        let mut step = &timestamp.first_step;
+        while let Some(next) = step.next.first() {
+            step = next;
+        }
+        assert!(matches!(
+            step.data,
+            StepData::Attestation(Attestation::Bitcoin { height: 546 })
+        ));
+        assert_eq!(step.output, block.header.merkle_root.to_byte_array());
+        let other = Txid::from_byte_array([1; 32]);
+        assert!(block_timestamp(&digest, &other, &block, 546).is_err());
+    }
+}
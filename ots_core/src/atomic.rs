@@ -0,0 +1,177 @@
+// Copyright (C) 2024 The OpenTimestamps developers
+
+use crate::error::Error;
+
+use log::warn;
+use std::fs;
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+/// Write `content` to `path` as [`write_with`].
+pub fn write_file<P: AsRef<Path>>(path: P, content: &[u8]) -> Result<(), Error> {
+    write_with(path, |file| {
+        file.write_all(content).map_err(|_| Error::IOError)
+    })
+}
+
+/// Write to `path` through a temporary file in the same directory, synced to
+/// disk and then renamed over the destination, so a crash never leaves a
+/// truncated or missing file behind.
+pub fn write_with<P, F>(path: P, write: F) -> Result<(), Error>
+where
+    P: AsRef<Path>,
+    F: FnOnce(&mut fs::File) -> Result<(), Error>,
+{
+    let path = path.as_ref();
+    let tmp = write_temp(path, write)?;
+    if let Err(e) = fs::rename(&tmp, path) {
+        let _ = fs::remove_file(&tmp);
+        warn!("Could not rename {}: {}", tmp.display(), e);
+        return Err(Error::IOError);
+    }
+    sync_dir(path)
+}
+
+/// Write a new file at `path` as [`write_with`], failing with
+/// [`Error::AlreadyExists`] if it exists, even when it is created while
+/// writing.
+pub fn write_new_with<P, F>(path: P, write: F) -> Result<(), Error>
+where
+    P: AsRef<Path>,
+    F: FnOnce(&mut fs::File) -> Result<(), Error>,
+{
+    let path = path.as_ref();
+    if path.exists() {
+        return Err(Error::AlreadyExists(path.display().to_string()));
+    }
+    let tmp = write_temp(path, write)?;
+    // Unlike a rename, a hard link never replaces an existing file
+    let linked = fs::hard_link(&tmp, path);
+    let _ = fs::remove_file(&tmp);
+    match linked {
+        Ok(()) => sync_dir(path),
+        Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+            Err(Error::AlreadyExists(path.display().to_string()))
+        }
+        Err(e) => {
+            warn!("Could not link {}: {}", path.display(), e);
+            Err(Error::IOError)
+        }
+    }
+}
+
+/// Write and sync a temporary file next to `path`, returning its path.
+fn write_temp<F>(path: &Path, write: F) -> Result<PathBuf, Error>
+where
+    F: FnOnce(&mut fs::File) -> Result<(), Error>,
+{
+    let tmp = temp_path(path);
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&tmp)
+        .map_err(|e| {
+            warn!("Could not create {}: {}", tmp.display(), e);
+            Error::IOError
+        })?;
+    let written = write(&mut file)
+        .and_then(|_| file.flush().map_err(|_| Error::IOError))
+        .and_then(|_| file.sync_all().map_err(|_| Error::IOError));
+    drop(file);
+    if let Err(e) = written {
+        let _ = fs::remove_file(&tmp);
+        return Err(e);
+    }
+    Ok(tmp)
+}
+
+/// Hidden temporary file next to `path`, its whole name kept so files that
+/// only differ by extension do not share one. The random suffix keeps a
+/// file left by a crashed process with the same pid from getting in the way.
+fn temp_path(path: &Path) -> PathBuf {
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or("file".to_string());
+    path.with_file_name(format!(
+        ".{}.{}.{:016x}.tmp",
+        name,
+        std::process::id(),
+        rand::random::<u64>()
+    ))
+}
+
+#[cfg(unix)]
+fn sync_dir(path: &Path) -> Result<(), Error> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    fs::File::open(dir)
+        .and_then(|dir| dir.sync_all())
+        .map_err(|_| Error::IOError)
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_path: &Path) -> Result<(), Error> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn temp_name_keeps_extensions() {
+        let tmp = temp_path(Path::new("dir/x.ots.json"));
+        let name = tmp.file_name().unwrap().to_str().unwrap();
+        assert_eq!(tmp.parent(), Some(Path::new("dir")));
+        assert!(name.starts_with(&format!(".x.ots.json.{}.", std::process::id())));
+        assert!(name.ends_with(".tmp"));
+        assert_ne!(tmp, temp_path(Path::new("dir/x.ots.json")));
+    }
+
+    #[test]
+    fn replace_file() {
+        let dir = std::env::temp_dir().join(format!("ots-atomic-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("x.ots.json");
+        write_file(&path, b"first").unwrap();
+        write_file(&path, b"second").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"second");
+        // A failed write leaves the previous content and no temporary file
+        assert!(write_with(&path, |_| Err(Error::IOError)).is_err());
+        assert_eq!(fs::read(&path).unwrap(), b"second");
+        let names: Vec<_> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(names, vec!["x.ots.json"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn write_new_file() {
+        let dir = std::env::temp_dir().join(format!("ots-atomic-new-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("x.ots");
+        let write = |content: &'static [u8]| {
+            move |file: &mut fs::File| file.write_all(content).map_err(|_| Error::IOError)
+        };
+        write_new_with(&path, write(b"first")).unwrap();
+        assert!(matches!(
+            write_new_with(&path, write(b"second")),
+            Err(Error::AlreadyExists(_))
+        ));
+        // Created while the new file was being written
+        fs::remove_file(&path).unwrap();
+        let raced = write_new_with(&path, |file| {
+            fs::write(&path, b"other").unwrap();
+            file.write_all(b"second").map_err(|_| Error::IOError)
+        });
+        assert!(matches!(raced, Err(Error::AlreadyExists(_))));
+        assert_eq!(fs::read(&path).unwrap(), b"other");
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,404 @@
+// Copyright (C) 2024 The OpenTimestamps developers
+
+use crate::error::Error;
+use crate::extensions::TimestampExtension;
+use crate::headers::{source_from_url, BlockHeaderSource, CrossCheckSource};
+
+use bitcoincore_rpc::bitcoin::hashes::Hash;
+use log::debug;
+use opentimestamps::attestation::Attestation;
+use opentimestamps::DetachedTimestampFile;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// Tag of the attestations decoded as `Attestation::Bitcoin`
+pub const BITCOIN_TAG: [u8; 8] = [0x05, 0x88, 0x96, 0x0d, 0x73, 0xd7, 0x19, 0x01];
+/// Tag of Litecoin block header attestations
+pub const LITECOIN_TAG: [u8; 8] = [0x06, 0x86, 0x9a, 0x0d, 0x73, 0xd7, 0x1b, 0x45];
+/// Tag of Ethereum block header attestations
+pub const ETHEREUM_TAG: [u8; 8] = [0x30, 0xfe, 0x80, 0x87, 0xb5, 0xc7, 0xea, 0xd7];
+
+/// Attestation with its known tags decoded.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AttestationKind {
+    Bitcoin { height: u32 },
+    Litecoin { height: u32 },
+    Ethereum { height: u32 },
+    Pending { uri: String },
+    Unknown { tag: Vec<u8> },
+}
+
+impl AttestationKind {
+    pub fn classify(attestation: &Attestation) -> Self {
+        match attestation {
+            Attestation::Bitcoin { height } => AttestationKind::Bitcoin {
+                height: *height as u32,
+            },
+            Attestation::Pending { uri } => AttestationKind::Pending { uri: uri.clone() },
+            Attestation::Unknown { tag, data } => {
+                let height = read_varuint(data).map(|height| height as u32);
+                match (tag.as_slice(), height) {
+                    (t, Some(height)) if t == LITECOIN_TAG => AttestationKind::Litecoin { height },
+                    (t, Some(height)) if t == ETHEREUM_TAG => AttestationKind::Ethereum { height },
+                    _ => AttestationKind::Unknown { tag: tag.clone() },
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Display for AttestationKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AttestationKind::Bitcoin { height } => write!(f, "Bitcoin block {}", height),
+            AttestationKind::Litecoin { height } => write!(f, "Litecoin block {}", height),
+            AttestationKind::Ethereum { height } => write!(f, "Ethereum block {}", height),
+            AttestationKind::Pending { uri } => write!(f, "pending at {}", uri),
+            AttestationKind::Unknown { tag } => {
+                write!(f, "unknown attestation {}", opentimestamps::hex::Hexed(tag))
+            }
+        }
+    }
+}
+
+/// Decode the LEB128 varuint heading an attestation payload.
+fn read_varuint(data: &[u8]) -> Option<u64> {
+    let mut value = 0u64;
+    for (i, byte) in data.iter().enumerate().take(10) {
+        value |= ((byte & 0x7f) as u64) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+/// Result of verifying an attestation on a chain other than Bitcoin.
+#[derive(Clone, Debug, Serialize)]
+pub struct ChainAttestationResult {
+    pub chain: String,
+    pub height: u32,
+    pub time: u32,
+    pub block_hash: String,
+}
+
+impl fmt::Display for ChainAttestationResult {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} block {} attests existence as of {}",
+            self.chain,
+            self.height,
+            crate::client::timestamp_to_iso8601(self.time as i64)
+        )
+    }
+}
+
+/// Verifies the attestations carrying a given tag.
+#[cfg(not(feature = "async"))]
+pub trait AttestationVerifier {
+    /// Check that `digest` is committed to by the attestation `payload`.
+    fn verify(&self, digest: &[u8], payload: &[u8]) -> Result<ChainAttestationResult, Error>;
+}
+
+/// Verifies the attestations carrying a given tag.
+#[cfg(feature = "async")]
+#[cfg_attr(target_arch = "wasm32", async_trait::async_trait(?Send))]
+#[cfg_attr(not(target_arch = "wasm32"), async_trait::async_trait)]
+pub trait AttestationVerifier: crate::headers::MaybeSendSync {
+    /// Check that `digest` is committed to by the attestation `payload`.
+    async fn verify(&self, digest: &[u8], payload: &[u8]) -> Result<ChainAttestationResult, Error>;
+}
+
+/// Verifier of attestations to the merkle root of a Bitcoin-like block
+/// header, such as Litecoin ones, whose payload is the block height.
+pub struct HeaderVerifier {
+    chain: String,
+    source: Box<dyn BlockHeaderSource>,
+}
+
+impl HeaderVerifier {
+    pub fn new(chain: &str, source: Box<dyn BlockHeaderSource>) -> Self {
+        HeaderVerifier {
+            chain: chain.to_string(),
+            source,
+        }
+    }
+
+    fn check(
+        &self,
+        digest: &[u8],
+        height: u32,
+        header: crate::headers::BlockHeader,
+    ) -> Result<ChainAttestationResult, Error> {
+        if header.merkle_root.to_byte_array()[..] != digest[..] {
+            return Err(Error::Generic(format!(
+                "{} block {} merkle root mismatch",
+                self.chain, height
+            )));
+        }
+        Ok(ChainAttestationResult {
+            chain: self.chain.clone(),
+            height,
+            time: header.time,
+            block_hash: header.block_hash().to_string(),
+        })
+    }
+}
+
+fn payload_height(payload: &[u8]) -> Result<u32, Error> {
+    read_varuint(payload)
+        .and_then(|height| u32::try_from(height).ok())
+        .ok_or(Error::Generic("Invalid attestation height".to_string()))
+}
+
+#[cfg(not(feature = "async"))]
+impl AttestationVerifier for HeaderVerifier {
+    fn verify(&self, digest: &[u8], payload: &[u8]) -> Result<ChainAttestationResult, Error> {
+        let height = payload_height(payload)?;
+        let header = self.source.block_header(height)?;
+        self.check(digest, height, header)
+    }
+}
+
+#[cfg(feature = "async")]
+#[cfg_attr(target_arch = "wasm32", async_trait::async_trait(?Send))]
+#[cfg_attr(not(target_arch = "wasm32"), async_trait::async_trait)]
+impl AttestationVerifier for HeaderVerifier {
+    async fn verify(&self, digest: &[u8], payload: &[u8]) -> Result<ChainAttestationResult, Error> {
+        let height = payload_height(payload)?;
+        let header = self.source.block_header(height).await?;
+        self.check(digest, height, header)
+    }
+}
+
+/// Verifiers of the attestations that are not Bitcoin ones, by tag.
+#[derive(Default)]
+pub struct VerifierRegistry {
+    verifiers: BTreeMap<[u8; 8], Box<dyn AttestationVerifier>>,
+}
+
+impl VerifierRegistry {
+    pub fn new() -> Self {
+        VerifierRegistry::default()
+    }
+
+    /// Verify the attestations tagged `tag` with `verifier`, replacing any
+    /// verifier previously registered for it.
+    pub fn register(&mut self, tag: [u8; 8], verifier: Box<dyn AttestationVerifier>) {
+        self.verifiers.insert(tag, verifier);
+    }
+
+    /// Registry verifying Litecoin attestations against the header sources
+    /// at `urls`, which must all agree, or an empty one without any.
+    pub fn with_litecoin_sources(urls: &[String]) -> Result<Self, Error> {
+        let mut registry = VerifierRegistry::new();
+        if !urls.is_empty() {
+            let mut cross_check = CrossCheckSource::new();
+            for url in urls {
+                cross_check.add(url, source_from_url(url)?);
+            }
+            registry.register(
+                LITECOIN_TAG,
+                Box::new(HeaderVerifier::new("Litecoin", Box::new(cross_check))),
+            );
+        }
+        Ok(registry)
+    }
+
+    /// Whether `ots` has attestations with a registered verifier.
+    pub fn handles(&self, ots: &DetachedTimestampFile) -> bool {
+        !self.attestations(ots).is_empty()
+    }
+
+    pub fn get(&self, tag: &[u8]) -> Option<&dyn AttestationVerifier> {
+        let tag: [u8; 8] = tag.try_into().ok()?;
+        self.verifiers.get(&tag).map(|verifier| verifier.as_ref())
+    }
+
+    /// Unknown attestations of `ots` with a registered verifier.
+    fn attestations(&self, ots: &DetachedTimestampFile) -> Vec<(Vec<u8>, Vec<u8>, Vec<u8>)> {
+        let mut attestations = vec![];
+        for (digest, attestation) in ots.timestamp.all_attestations() {
+            if let Attestation::Unknown { tag, data } = attestation {
+                if self.get(&tag).is_some() {
+                    attestations.push((digest, tag, data));
+                } else {
+                    debug!(
+                        "No verifier for {}",
+                        AttestationKind::classify(&Attestation::Unknown { tag, data })
+                    );
+                }
+            }
+        }
+        attestations
+    }
+}
+
+/// Verify the attestations of `ots` handled by `registry`, one result each.
+#[cfg(not(feature = "async"))]
+pub fn verify_with_registry(
+    ots: &DetachedTimestampFile,
+    registry: &VerifierRegistry,
+) -> Vec<Result<ChainAttestationResult, Error>> {
+    registry
+        .attestations(ots)
+        .into_iter()
+        .map(|(digest, tag, data)| registry.get(&tag).unwrap().verify(&digest, &data))
+        .collect()
+}
+
+/// Verify the attestations of `ots` handled by `registry`, one result each.
+#[cfg(feature = "async")]
+pub async fn verify_with_registry(
+    ots: &DetachedTimestampFile,
+    registry: &VerifierRegistry,
+) -> Vec<Result<ChainAttestationResult, Error>> {
+    let mut results = vec![];
+    for (digest, tag, data) in registry.attestations(ots) {
+        results.push(registry.get(&tag).unwrap().verify(&digest, &data).await);
+    }
+    results
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::anchor::linear_timestamp;
+    use crate::headers::BlockHeader;
+    use bitcoincore_rpc::bitcoin::blockdata::constants::genesis_block;
+    use bitcoincore_rpc::bitcoin::{Network, TxMerkleNode};
+    use opentimestamps::op::Op;
+    use opentimestamps::ser::DigestType;
+
+    /// Header source serving `header` at every height.
+    struct FixedSource(BlockHeader);
+
+    #[cfg(not(feature = "async"))]
+    impl BlockHeaderSource for FixedSource {
+        fn block_header(&self, _height: u32) -> Result<BlockHeader, Error> {
+            Ok(self.0)
+        }
+
+        fn tip_height(&self) -> Result<u32, Error> {
+            Ok(1000)
+        }
+    }
+
+    fn litecoin_timestamp(height: u8) -> DetachedTimestampFile {
+        DetachedTimestampFile {
+            digest_type: DigestType::Sha256,
+            timestamp: linear_timestamp(
+                vec![1; 32],
+                vec![Op::Sha256],
+                Attestation::Unknown {
+                    tag: LITECOIN_TAG.to_vec(),
+                    data: vec![height],
+                },
+            ),
+        }
+    }
+
+    /// Header committing to `digest` as its merkle root.
+    fn header_with_root(digest: &[u8]) -> BlockHeader {
+        let mut header = genesis_block(Network::Bitcoin).header;
+        header.merkle_root = TxMerkleNode::from_byte_array(digest.try_into().unwrap());
+        header
+    }
+
+    #[test]
+    fn varuint() {
+        assert_eq!(read_varuint(&[0x00]), Some(0));
+        assert_eq!(read_varuint(&[0x7f]), Some(127));
+        assert_eq!(read_varuint(&[0x80, 0x01]), Some(128));
+        assert_eq!(read_varuint(&[0xc0, 0xa5, 0x33]), Some(840_384));
+        assert_eq!(read_varuint(&[0x80]), None);
+        assert_eq!(read_varuint(&[]), None);
+    }
+
+    #[test]
+    fn classify() {
+        let litecoin = Attestation::Unknown {
+            tag: LITECOIN_TAG.to_vec(),
+            data: vec![0x80, 0x01],
+        };
+        assert_eq!(
+            AttestationKind::classify(&litecoin),
+            AttestationKind::Litecoin { height: 128 }
+        );
+        let ethereum = Attestation::Unknown {
+            tag: ETHEREUM_TAG.to_vec(),
+            data: vec![0x05],
+        };
+        assert_eq!(
+            AttestationKind::classify(&ethereum),
+            AttestationKind::Ethereum { height: 5 }
+        );
+        let truncated = Attestation::Unknown {
+            tag: LITECOIN_TAG.to_vec(),
+            data: vec![0x80],
+        };
+        assert_eq!(
+            AttestationKind::classify(&truncated),
+            AttestationKind::Unknown {
+                tag: LITECOIN_TAG.to_vec()
+            }
+        );
+        assert_eq!(
+            AttestationKind::classify(&Attestation::Bitcoin { height: 7 }),
+            AttestationKind::Bitcoin { height: 7 }
+        );
+    }
+
+    #[cfg(not(feature = "async"))]
+    #[test]
+    fn registry_verifies_litecoin() {
+        let ots = litecoin_timestamp(42);
+        let digest = Op::Sha256.execute(&[1; 32]);
+        let mut registry = VerifierRegistry::new();
+        assert!(!registry.handles(&ots));
+        registry.register(
+            LITECOIN_TAG,
+            Box::new(HeaderVerifier::new(
+                "Litecoin",
+                Box::new(FixedSource(header_with_root(&digest))),
+            )),
+        );
+        assert!(registry.handles(&ots));
+        let results = verify_with_registry(&ots, &registry);
+        assert_eq!(results.len(), 1);
+        let result = results[0].as_ref().unwrap();
+        assert_eq!((result.chain.as_str(), result.height), ("Litecoin", 42));
+
+        let verification = crate::client::verify_all(ots, None, &registry, 6).unwrap();
+        assert!(verification.bitcoin.is_none());
+        assert_eq!(verification.other.len(), 1);
+    }
+
+    #[cfg(not(feature = "async"))]
+    #[test]
+    fn registry_failure_fails_verification() {
+        let mut registry = VerifierRegistry::new();
+        registry.register(
+            LITECOIN_TAG,
+            Box::new(HeaderVerifier::new(
+                "Litecoin",
+                Box::new(FixedSource(header_with_root(&[0; 32]))),
+            )),
+        );
+        let results = verify_with_registry(&litecoin_timestamp(42), &registry);
+        assert!(results[0].is_err());
+        assert!(crate::client::verify_all(litecoin_timestamp(42), None, &registry, 6).is_err());
+    }
+
+    #[cfg(not(feature = "async"))]
+    #[test]
+    fn unhandled_attestation_is_reported() {
+        let registry = VerifierRegistry::new();
+        match crate::client::verify_all(litecoin_timestamp(42), None, &registry, 6) {
+            Err(Error::Generic(message)) => assert!(message.contains("Litecoin block 42")),
+            _ => panic!("expected an unverifiable timestamp"),
+        }
+    }
+}
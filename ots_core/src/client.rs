@@ -1,9 +1,19 @@
+use crate::attestation::{
+    verify_with_registry, AttestationKind, ChainAttestationResult, VerifierRegistry,
+};
 use crate::error::Error;
 use crate::extensions::{StepExtension, TimestampExtension};
+use crate::header_cache::{CachedSource, HeaderCache};
+use crate::headers::{BlockHeader, BlockHeaderSource};
+use crate::network::{self, Network};
+use crate::receipt::{
+    digest_algorithm, nonce_policy, CalendarSubmission, StampReceipt, NONCE_SIZE, TOOL,
+};
+use crate::whitelist::UrlWhitelist;
 
-use bitcoin_hashes::Hash;
-use chrono::DateTime;
-use log::{debug, error, info};
+use chrono::{DateTime, SecondsFormat};
+use chrono_tz::Tz;
+use log::{debug, error, info, warn};
 use opentimestamps::hex::Hexed;
 use opentimestamps::ser::DigestType;
 use opentimestamps::{
@@ -13,140 +23,735 @@ use opentimestamps::{
     DetachedTimestampFile, Timestamp,
 };
 use rs_merkle::{algorithms::Sha256, MerkleTree};
+use serde::Serialize;
+use std::collections::{BTreeSet, HashMap};
 use std::convert::TryInto;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
 #[cfg(not(feature = "async"))]
-use crate::block_calendar::{Calendar, APOOL, BPOOL, FINNEY};
+use crate::block_calendar::Calendar;
+#[cfg(not(feature = "async"))]
+use crate::headers::{source_from_url, BitcoindSource};
 
 #[cfg(feature = "async")]
-use crate::async_calendar::{Calendar, APOOL, BPOOL, FINNEY};
+use crate::async_calendar::Calendar;
+#[cfg(feature = "async")]
+use crate::headers::{source_from_url, BitcoindRpcSource};
 
 pub fn info(ots: DetachedTimestampFile) -> Result<String, Error> {
     Ok(ots.to_string())
 }
 
-fn timestamp_to_date(timestamp: i64) -> String {
+/// Lowest block height among the Bitcoin attestations of a timestamp, if any.
+pub fn bitcoin_height(ots: &DetachedTimestampFile) -> Option<u32> {
+    ots.timestamp
+        .all_attestations()
+        .values()
+        .filter_map(|attestation| match attestation {
+            Attestation::Bitcoin { height } => Some(*height as u32),
+            _ => None,
+        })
+        .min()
+}
+
+/// Whether every pending attestation of a timestamp has been replaced by a Bitcoin one.
+pub fn is_complete(ots: &DetachedTimestampFile) -> bool {
+    let attestations = ots.timestamp.all_attestations();
+    let mut bitcoin = false;
+    for attestation in attestations.values() {
+        match attestation {
+            Attestation::Bitcoin { height: _ } => bitcoin = true,
+            Attestation::Pending { uri: _ } => return false,
+            Attestation::Unknown { tag: _, data: _ } => {}
+        }
+    }
+    bitcoin
+}
+
+/// Calendars still to attest a timestamp, from its pending attestations.
+pub fn pending_uris(ots: &DetachedTimestampFile) -> Vec<String> {
+    let mut uris: Vec<String> = ots
+        .timestamp
+        .all_attestations()
+        .into_values()
+        .filter_map(|attestation| match attestation {
+            Attestation::Pending { uri } => Some(uri),
+            _ => None,
+        })
+        .collect();
+    uris.sort();
+    uris.dedup();
+    uris
+}
+
+/// Digest of the document read from `reader`, hashed with `digest_type`.
+pub fn digest_reader<R: Read>(reader: R, digest_type: DigestType) -> Result<Vec<u8>, Error> {
+    use bitcoin_hashes::{ripemd160, sha1, sha256};
+
+    match digest_type {
+        DigestType::Sha1 => hash_reader::<sha1::Hash, R>(reader),
+        DigestType::Sha256 => hash_reader::<sha256::Hash, R>(reader),
+        DigestType::Ripemd160 => hash_reader::<ripemd160::Hash, R>(reader),
+    }
+}
+
+fn hash_reader<H: bitcoin_hashes::Hash, R: Read>(mut reader: R) -> Result<Vec<u8>, Error> {
+    use bitcoin_hashes::HashEngine;
+
+    let mut engine = H::engine();
+    let mut buffer = [0u8; 64 * 1024];
+    loop {
+        let read = reader.read(&mut buffer).map_err(|_| Error::IOError)?;
+        if read == 0 {
+            break;
+        }
+        engine.input(&buffer[..read]);
+    }
+    Ok(H::from_engine(engine).as_ref().to_vec())
+}
+
+/// Check that `ots` timestamps the document whose digest is `digest`.
+pub fn verify_digest(ots: &DetachedTimestampFile, digest: &[u8]) -> Result<(), Error> {
+    if digest != ots.timestamp.start_digest.as_slice() {
+        return Err(Error::DigestMismatch {
+            expected: Hexed(&ots.timestamp.start_digest).to_string(),
+            actual: Hexed(digest).to_string(),
+        });
+    }
+    Ok(())
+}
+
+/// Check that `ots` timestamps the document read from `reader`, hashed with
+/// the digest type of the proof.
+pub fn verify_file<R: Read>(ots: &DetachedTimestampFile, reader: R) -> Result<(), Error> {
+    debug!("Hashing file, algorithm {:?}", ots.digest_type);
+    let digest = digest_reader(reader, ots.digest_type)?;
+    verify_digest(ots, &digest)
+}
+
+/// Path of the document timestamped by the proof at `ots_path`, the same
+/// name without the `.ots` extension.
+pub fn target_path(ots_path: &Path) -> Result<PathBuf, Error> {
+    match ots_path.to_str().and_then(|path| path.strip_suffix(".ots")) {
+        Some(target) if !target.is_empty() => Ok(PathBuf::from(target)),
+        _ => Err(Error::Generic(format!(
+            "{} has no .ots extension, the target file must be given",
+            ots_path.display()
+        ))),
+    }
+}
+
+/// Format a unix time as an ISO-8601 UTC timestamp.
+pub fn timestamp_to_iso8601(timestamp: i64) -> String {
     let from = DateTime::from_timestamp(timestamp, 0).unwrap();
-    let date = from.naive_local();
-    date.format("%Y-%m-%d").to_string()
+    from.to_rfc3339_opts(SecondsFormat::Secs, true)
 }
 
+/// Format a unix time as an ISO-8601 timestamp in the timezone `tz`.
+pub fn timestamp_to_iso8601_in(timestamp: i64, tz: Tz) -> String {
+    let from = DateTime::from_timestamp(timestamp, 0).unwrap();
+    from.with_timezone(&tz)
+        .to_rfc3339_opts(SecondsFormat::Secs, false)
+}
+
+/// Confirmations below which an attestation is reported as provisional.
+pub const DEFAULT_MIN_CONFIRMATIONS: u32 = 6;
+
+/// Number of blocks whose median time is the median time past of the last one.
+const MEDIAN_TIME_SPAN: u32 = 11;
+
+#[derive(Clone, Debug, Serialize)]
 pub struct BitcoinAttestationResult {
     pub height: u32,
+    /// Time of the attesting block header, which miners can skew by hours
     pub time: u32,
+    /// Median time of the 11 blocks ending at the attesting block: unlike the
+    /// header time it cannot be skewed by a single miner and never decreases
+    /// with the height, making it a conservative bound
+    pub median_time_past: Option<u32>,
+    /// Hash of the attesting block
+    pub block_hash: String,
+    /// Height of the chain tip at verification time
+    pub tip_height: u32,
+    /// Depth of the attesting block, 1 when it is the tip
+    pub confirmations: u32,
+    /// Whether the attesting block is too shallow to be safe from a reorganization
+    pub provisional: bool,
+}
+
+impl BitcoinAttestationResult {
+    /// Describe the result with times in the timezone `tz` rather than UTC.
+    pub fn format_in(&self, tz: Tz) -> String {
+        self.describe(|time| timestamp_to_iso8601_in(time as i64, tz))
+    }
+
+    fn describe(&self, format_time: impl Fn(u32) -> String) -> String {
+        let mut details = vec![];
+        if let Some(median_time_past) = self.median_time_past {
+            details.push(format!(
+                "median time past {}",
+                format_time(median_time_past)
+            ));
+        }
+        details.push(format!("{} confirmations", self.confirmations));
+        if self.provisional {
+            details.push("provisional".to_string());
+        }
+        format!(
+            "Bitcoin block {} attests existence as of {} ({})",
+            self.height,
+            format_time(self.time),
+            details.join(", ")
+        )
+    }
 }
 
 impl std::fmt::Display for BitcoinAttestationResult {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
-            "Bitcoin block {} attests existence as of {}",
-            self.height,
-            timestamp_to_date(self.time as i64)
+            "{}",
+            self.describe(|time| timestamp_to_iso8601(time as i64))
         )
     }
 }
 
+/// Median time of the headers ending at the attesting block at `height`,
+/// none unless `headers` holds all of them.
+fn median_time_past(height: u32, headers: &[BlockHeader]) -> Option<u32> {
+    let (_, count) = median_time_span(height);
+    if headers.len() != count as usize {
+        warn!(
+            "Median time past of block {} needs {} headers, got {}",
+            height,
+            count,
+            headers.len()
+        );
+        return None;
+    }
+    let mut times: Vec<u32> = headers.iter().map(|header| header.time).collect();
+    times.sort_unstable();
+    times.get(times.len() / 2).copied()
+}
+
+/// First height and number of the headers the median time past of `height` is computed from.
+fn median_time_span(height: u32) -> (u32, u32) {
+    let start = (height + 1).saturating_sub(MEDIAN_TIME_SPAN);
+    (start, height - start + 1)
+}
+
+/// Lowest Bitcoin attestation of a timestamp, with the digest it attests.
+fn lowest_bitcoin_attestation(ots: &DetachedTimestampFile) -> Option<(Vec<u8>, u32)> {
+    ots.timestamp
+        .all_attestations()
+        .into_iter()
+        .filter_map(|(digest, attestation)| match attestation {
+            Attestation::Bitcoin { height } => Some((digest, height as u32)),
+            _ => None,
+        })
+        .min_by_key(|(_, height)| *height)
+}
+
+/// Runs of consecutive heights, as first height and count, covering the
+/// headers needed to verify all of `timestamps`.
+fn batch_header_runs(timestamps: &[DetachedTimestampFile]) -> Vec<(u32, u32)> {
+    let mut heights = BTreeSet::new();
+    for ots in timestamps {
+        if let Some((_, height)) = lowest_bitcoin_attestation(ots) {
+            let (start, count) = median_time_span(height);
+            heights.extend(start..start + count);
+        }
+    }
+    let mut runs: Vec<(u32, u32)> = vec![];
+    for height in heights {
+        match runs.last_mut() {
+            Some((start, count)) if *start + *count == height => *count += 1,
+            _ => runs.push((height, 1)),
+        }
+    }
+    runs
+}
+
+/// Check the lowest Bitcoin attestation of `ots` against the headers
+/// fetched for a whole batch.
+fn check_batched_attestation(
+    ots: &DetachedTimestampFile,
+    headers: &HashMap<u32, BlockHeader>,
+    tip_height: u32,
+    min_confirmations: u32,
+) -> Result<BitcoinAttestationResult, Error> {
+    let (digest, height) = lowest_bitcoin_attestation(ots)
+        .ok_or(Error::Generic("No bitcoin attestion found".to_string()))?;
+    let block_header = headers
+        .get(&height)
+        .ok_or(Error::HeaderSourceError(format!(
+            "Block header {} unavailable",
+            height
+        )))?;
+    let (start, count) = median_time_span(height);
+    let span: Option<Vec<BlockHeader>> = (start..start + count)
+        .map(|height| headers.get(&height).cloned())
+        .collect();
+    let result = check_bitcoin_attestation(
+        &digest,
+        height as usize,
+        block_header,
+        span.and_then(|span| median_time_past(height, &span)),
+        tip_height,
+        min_confirmations,
+    )?;
+    debug!("Success! {}", result);
+    Ok(result)
+}
+
+/// Attestations of a timestamp that verified.
+#[derive(Clone, Debug, Serialize)]
+pub struct Verification {
+    /// Lowest Bitcoin attestation, if the timestamp has one
+    pub bitcoin: Option<BitcoinAttestationResult>,
+    /// Attestations to other chains, checked by a [`VerifierRegistry`]
+    pub other: Vec<ChainAttestationResult>,
+}
+
+impl Verification {
+    /// Whether the Bitcoin attestation is too shallow to be safe from a
+    /// reorganization.
+    pub fn is_provisional(&self) -> bool {
+        self.bitcoin
+            .as_ref()
+            .is_some_and(|attestation| attestation.provisional)
+    }
+}
+
+fn no_source(height: u32) -> Error {
+    Error::HeaderSourceError(format!(
+        "No block header source to verify Bitcoin block {}",
+        height
+    ))
+}
+
+/// Error for a merkle tip that no calendar accepted, naming why each one
+/// was ignored.
+fn unavailable(submissions: &[CalendarSubmission]) -> Error {
+    let reasons: Vec<String> = submissions
+        .iter()
+        .map(|submission| {
+            format!(
+                "{} ({})",
+                submission.url,
+                submission.error.as_deref().unwrap_or("no answer")
+            )
+        })
+        .collect();
+    Error::CalendarUnavailable(reasons.join(", "))
+}
+
+/// Error for a timestamp without any attestation that can be verified,
+/// naming the attestations no verifier handles.
+fn unverifiable(ots: &DetachedTimestampFile) -> Error {
+    let unverified: Vec<String> = ots
+        .timestamp
+        .all_attestations()
+        .values()
+        .filter(|attestation| matches!(attestation, Attestation::Unknown { .. }))
+        .map(|attestation| AttestationKind::classify(attestation).to_string())
+        .collect();
+    if unverified.is_empty() {
+        return Error::Generic("No bitcoin attestion found".to_string());
+    }
+    Error::Generic(format!(
+        "No bitcoin attestion found, no verifier for {}",
+        unverified.join(", ")
+    ))
+}
+
+/// Result of an upgrade: what each remote calendar answered.
+#[derive(Debug, Default)]
+pub struct UpgradeOutcome {
+    /// Attestations added to the timestamp
+    pub upgraded: Vec<Attestation>,
+    /// Calendars which have not attested the timestamp in Bitcoin yet
+    pub pending: Vec<String>,
+    /// Calendars not contacted, not being among the ones requested
+    pub skipped: Vec<String>,
+    /// Calendars which could not be upgraded from
+    pub errors: Vec<(String, Error)>,
+}
+
+impl UpgradeOutcome {
+    /// Whether the upgrade changed the timestamp.
+    pub fn changed(&self) -> bool {
+        !self.upgraded.is_empty()
+    }
+
+    /// Whether another attempt may upgrade the timestamp: a calendar has it
+    /// pending, or failed for a reason other than not being whitelisted.
+    pub fn upgradable(&self) -> bool {
+        !self.pending.is_empty()
+            || self
+                .errors
+                .iter()
+                .any(|(_, e)| !matches!(e, Error::CalendarNotWhitelisted(_)))
+    }
+}
+
+/// Check a Bitcoin attestation of `digest` against the header of the attesting block.
+fn check_bitcoin_attestation(
+    digest: &[u8],
+    height: usize,
+    block_header: &BlockHeader,
+    median_time_past: Option<u32>,
+    tip_height: u32,
+    min_confirmations: u32,
+) -> Result<BitcoinAttestationResult, Error> {
+    use bitcoincore_rpc::bitcoin::hashes::Hash;
+
+    if block_header.merkle_root.to_byte_array()[..] != digest[..] {
+        return Err(Error::Generic("Merkle root mismatch".to_string()));
+    }
+    let height: u32 = height.try_into().unwrap();
+    let confirmations = (tip_height + 1).saturating_sub(height);
+    if confirmations < min_confirmations {
+        warn!(
+            "Bitcoin block {} has only {} confirmations, attestation is provisional",
+            height, confirmations
+        );
+    }
+    Ok(BitcoinAttestationResult {
+        height,
+        time: block_header.time,
+        median_time_past,
+        block_hash: block_header.block_hash().to_string(),
+        tip_height,
+        confirmations,
+        provisional: confirmations < min_confirmations,
+    })
+}
+
+/// Public header source of `network`, for verifying without a node.
+fn default_source_url(network: Network) -> Result<&'static str, Error> {
+    #[cfg(not(feature = "async"))]
+    let url = network::default_electrum(network);
+    #[cfg(feature = "async")]
+    let url = network::default_esplora(network);
+    url.ok_or(Error::HeaderSourceError(format!(
+        "No public block header source for {}, a Bitcoin node is required",
+        network
+    )))
+}
+
+/// Header caches shared by the [`verify`] calls of the process, by public
+/// header source, once enabled with [`share_header_caches`].
+static SHARED_CACHES: Mutex<Option<(usize, HashMap<String, Arc<Mutex<HeaderCache>>>)>> =
+    Mutex::new(None);
+
+/// Keep the headers [`verify`] fetches from public header sources in memory,
+/// up to `capacity` per source, for the later calls of the process. Off by
+/// default, each call fetching its headers again. Headers from a node are
+/// never shared.
+pub fn share_header_caches(capacity: usize) {
+    let mut shared = SHARED_CACHES.lock().unwrap();
+    match shared.as_mut() {
+        Some((shared_capacity, _)) => *shared_capacity = capacity,
+        None => *shared = Some((capacity, HashMap::new())),
+    }
+}
+
+/// Shared header cache of the source at `url`, if enabled.
+fn shared_header_cache(url: &str) -> Option<Arc<Mutex<HeaderCache>>> {
+    let mut shared = SHARED_CACHES.lock().unwrap();
+    let (capacity, caches) = shared.as_mut()?;
+    let cache = caches
+        .entry(url.to_string())
+        .or_insert_with(|| Arc::new(Mutex::new(HeaderCache::new(*capacity))));
+    Some(cache.clone())
+}
+
+/// The public header source at `url`, served from its shared cache if
+/// enabled.
+fn default_source(url: &str) -> Result<Box<dyn BlockHeaderSource>, Error> {
+    let source = source_from_url(url)?;
+    Ok(match shared_header_cache(url) {
+        Some(cache) => Box::new(CachedSource::new(source, cache)),
+        None => source,
+    })
+}
+
+/// Verify a timestamp against the Bitcoin node, or else the public header
+/// source of `network`, see [`share_header_caches`] to cache its headers
+/// across calls.
 #[cfg(not(feature = "async"))]
 pub fn verify(
     ots: DetachedTimestampFile,
     bitcoin_client: Option<bitcoincore_rpc::Client>,
+    network: Network,
 ) -> Result<BitcoinAttestationResult, Error> {
-    use crate::electrum_client::ElectrumApi;
-    use bitcoincore_rpc::bitcoin::hashes::Hash;
-    use bitcoincore_rpc::RpcApi;
+    let source = node_or_default_source(bitcoin_client, network)?;
+    verify_with_source(ots, source.as_ref(), DEFAULT_MIN_CONFIRMATIONS)
+}
 
-    let electrum_client =
-        electrum_client::Client::new("tcp://electrum.blockstream.info:50001").unwrap();
+/// Verify a timestamp as [`verify`], along with its attestations to other
+/// chains handled by `registry`.
+#[cfg(not(feature = "async"))]
+pub fn verify_chains(
+    ots: DetachedTimestampFile,
+    bitcoin_client: Option<bitcoincore_rpc::Client>,
+    network: Network,
+    registry: &VerifierRegistry,
+) -> Result<Verification, Error> {
+    let source = match bitcoin_height(&ots) {
+        Some(_) => Some(node_or_default_source(bitcoin_client, network)?),
+        None => None,
+    };
+    verify_all(ots, source.as_deref(), registry, DEFAULT_MIN_CONFIRMATIONS)
+}
 
-    for attestation in ots.timestamp.all_attestations() {
-        match attestation.1 {
-            Attestation::Bitcoin { height } => {
-                let block_header = match bitcoin_client {
-                    Some(client) => {
-                        let block_hash = client.get_block_hash(height as u64).unwrap();
-                        debug!("Attestation block hash: {:?}", block_hash);
-                        client.get_block_header(&block_hash).unwrap()
-                    }
-                    None => {
-                        let block_header = electrum_client.block_header(height).unwrap();
-                        debug!("Attestation block hash: {:?}", block_header);
-                        block_header
-                    }
-                };
-                let att = bitcoin_hashes::sha256d::Hash::from_slice(&attestation.0).unwrap();
-                let att =
-                    electrum_client::bitcoin::hashes::sha256d::Hash::from_slice(&attestation.0)
-                        .unwrap();
-                if att != block_header.merkle_root.to_raw_hash() {
-                    return Err(Error::Generic("Merkle root mismatch".to_string()));
-                }
-                let time = block_header.time;
-                let result = BitcoinAttestationResult {
-                    height: height.try_into().unwrap(),
-                    time,
-                };
-                info!("Success! {}", result);
-                return Ok(BitcoinAttestationResult {
-                    height: height.try_into().unwrap(),
-                    time,
-                });
-            }
-            Attestation::Pending { uri } => {
-                debug!("Ignoring Pending Attestation at {:?}", uri);
-            }
-            Attestation::Unknown { tag: _, data: _ } => {
-                debug!("Ignoring Unknown Attestation");
-            }
-        };
+#[cfg(not(feature = "async"))]
+fn node_or_default_source(
+    bitcoin_client: Option<bitcoincore_rpc::Client>,
+    network: Network,
+) -> Result<Box<dyn BlockHeaderSource>, Error> {
+    match bitcoin_client {
+        Some(client) => Ok(Box::new(BitcoindSource::new(client))),
+        None => default_source(default_source_url(network)?),
+    }
+}
+
+/// Verify every attestation of a timestamp: the Bitcoin one against
+/// `source`, the others with the verifiers of `registry`. Fails if any of
+/// them does not verify, or if none can be verified.
+#[cfg(not(feature = "async"))]
+pub fn verify_all(
+    ots: DetachedTimestampFile,
+    source: Option<&dyn BlockHeaderSource>,
+    registry: &VerifierRegistry,
+    min_confirmations: u32,
+) -> Result<Verification, Error> {
+    let other = verify_with_registry(&ots, registry)
+        .into_iter()
+        .collect::<Result<Vec<_>, _>>()?;
+    let bitcoin = match (bitcoin_height(&ots), source) {
+        (None, _) if other.is_empty() => return Err(unverifiable(&ots)),
+        (None, _) => None,
+        (Some(_), Some(source)) => Some(verify_with_source(ots, source, min_confirmations)?),
+        (Some(height), None) => return Err(no_source(height)),
+    };
+    Ok(Verification { bitcoin, other })
+}
+
+/// Verify a timestamp against the block headers of `source`, reporting
+/// attestations with less than `min_confirmations` as provisional.
+#[cfg(not(feature = "async"))]
+pub fn verify_with_source(
+    ots: DetachedTimestampFile,
+    source: &dyn BlockHeaderSource,
+    min_confirmations: u32,
+) -> Result<BitcoinAttestationResult, Error> {
+    // The lowest attestation is the earliest proof, and the one verify_batch reports
+    let Some((digest, height)) = lowest_bitcoin_attestation(&ots) else {
+        return Err(unverifiable(&ots));
+    };
+    let block_header = source.block_header(height)?;
+    debug!("Attestation block hash: {:?}", block_header.block_hash());
+    let (start, count) = median_time_span(height);
+    let median_time_past = match source.block_headers(start, count) {
+        Ok(headers) => median_time_past(height, &headers),
+        Err(e) => {
+            warn!("Median time past unavailable: {}", e);
+            None
+        }
+    };
+    let tip_height = source.tip_height()?;
+    let result = check_bitcoin_attestation(
+        &digest,
+        height as usize,
+        &block_header,
+        median_time_past,
+        tip_height,
+        min_confirmations,
+    )?;
+    info!("Success! {}", result);
+    Ok(result)
+}
+
+/// Verify the Bitcoin attestations of many timestamps against `source`,
+/// fetching each needed block header and the tip height only once.
+///
+/// Results are in the order of `timestamps`, the outer error is for a tip
+/// height the source could not tell.
+#[cfg(not(feature = "async"))]
+pub fn verify_batch(
+    timestamps: &[DetachedTimestampFile],
+    source: &dyn BlockHeaderSource,
+    min_confirmations: u32,
+) -> Result<Vec<Result<BitcoinAttestationResult, Error>>, Error> {
+    let mut headers = HashMap::new();
+    for (start, count) in batch_header_runs(timestamps) {
+        match source.block_headers(start, count) {
+            Ok(fetched) => headers.extend((start..).zip(fetched)),
+            Err(e) => warn!(
+                "Block headers {} to {} unavailable: {}",
+                start,
+                start + count - 1,
+                e
+            ),
+        }
     }
-    Err(Error::Generic("No bitcoin attestion found".to_string()))
+    debug!(
+        "Fetched {} block headers for {} timestamps",
+        headers.len(),
+        timestamps.len()
+    );
+    let tip_height = source.tip_height()?;
+    Ok(timestamps
+        .iter()
+        .map(|ots| check_batched_attestation(ots, &headers, tip_height, min_confirmations))
+        .collect())
 }
 
+/// Verify a timestamp against the Bitcoin node, or else the public header
+/// source of `network`, see [`share_header_caches`] to cache its headers
+/// across calls.
 #[cfg(feature = "async")]
 pub async fn verify(
     ots: DetachedTimestampFile,
-    _bitcoin_client: Option<bitcoincore_rpc::Client>,
+    bitcoin_client: Option<BitcoindRpcSource>,
+    network: Network,
 ) -> Result<BitcoinAttestationResult, Error> {
-    let builder = esplora_client::Builder::new("https://blockstream.info/api");
-    let client = builder.build_async().unwrap();
+    let source = node_or_default_source(bitcoin_client, network)?;
+    verify_with_source(ots, source.as_ref(), DEFAULT_MIN_CONFIRMATIONS).await
+}
 
-    for attestation in ots.timestamp.all_attestations() {
-        match attestation.1 {
-            Attestation::Bitcoin { height } => {
-                let block_hash = client.get_block_hash(height as u32).await.unwrap();
-                let block_header = client.get_header_by_hash(&block_hash).await.unwrap();
-                let att = bitcoin_hashes::sha256d::Hash::from_slice(&attestation.0).unwrap();
-                if att != block_header.merkle_root.to_raw_hash() {
-                    return Err(Error::Generic("Merkle root mismatch".to_string()));
-                }
-                let result = BitcoinAttestationResult {
-                    height: height.try_into().unwrap(),
-                    time: block_header.time,
-                };
-                info!("Success! {}", result);
-                return Ok(BitcoinAttestationResult {
-                    height: height.try_into().unwrap(),
-                    time: block_header.time,
-                });
-            }
-            Attestation::Pending { uri } => {
-                debug!("Ignoring Pending Attestation at {:?}", uri);
-            }
-            Attestation::Unknown { tag: _, data: _ } => {
-                debug!("Ignoring Unknown Attestation");
-            }
-        };
+/// Verify a timestamp as [`verify`], along with its attestations to other
+/// chains handled by `registry`.
+#[cfg(feature = "async")]
+pub async fn verify_chains(
+    ots: DetachedTimestampFile,
+    bitcoin_client: Option<BitcoindRpcSource>,
+    network: Network,
+    registry: &VerifierRegistry,
+) -> Result<Verification, Error> {
+    let source = match bitcoin_height(&ots) {
+        Some(_) => Some(node_or_default_source(bitcoin_client, network)?),
+        None => None,
+    };
+    verify_all(ots, source.as_deref(), registry, DEFAULT_MIN_CONFIRMATIONS).await
+}
+
+#[cfg(feature = "async")]
+fn node_or_default_source(
+    bitcoin_client: Option<BitcoindRpcSource>,
+    network: Network,
+) -> Result<Box<dyn BlockHeaderSource>, Error> {
+    match bitcoin_client {
+        Some(client) => Ok(Box::new(client)),
+        None => default_source(default_source_url(network)?),
+    }
+}
+
+/// Verify every attestation of a timestamp: the Bitcoin one against
+/// `source`, the others with the verifiers of `registry`. Fails if any of
+/// them does not verify, or if none can be verified.
+#[cfg(feature = "async")]
+pub async fn verify_all(
+    ots: DetachedTimestampFile,
+    source: Option<&dyn BlockHeaderSource>,
+    registry: &VerifierRegistry,
+    min_confirmations: u32,
+) -> Result<Verification, Error> {
+    let other = verify_with_registry(&ots, registry)
+        .await
+        .into_iter()
+        .collect::<Result<Vec<_>, _>>()?;
+    let bitcoin = match (bitcoin_height(&ots), source) {
+        (None, _) if other.is_empty() => return Err(unverifiable(&ots)),
+        (None, _) => None,
+        (Some(_), Some(source)) => Some(verify_with_source(ots, source, min_confirmations).await?),
+        (Some(height), None) => return Err(no_source(height)),
+    };
+    Ok(Verification { bitcoin, other })
+}
+
+/// Verify a timestamp against the block headers of `source`, reporting
+/// attestations with less than `min_confirmations` as provisional.
+#[cfg(feature = "async")]
+pub async fn verify_with_source(
+    ots: DetachedTimestampFile,
+    source: &dyn BlockHeaderSource,
+    min_confirmations: u32,
+) -> Result<BitcoinAttestationResult, Error> {
+    // The lowest attestation is the earliest proof, and the one verify_batch reports
+    let Some((digest, height)) = lowest_bitcoin_attestation(&ots) else {
+        return Err(unverifiable(&ots));
+    };
+    let block_header = source.block_header(height).await?;
+    debug!("Attestation block hash: {:?}", block_header.block_hash());
+    let (start, count) = median_time_span(height);
+    let median_time_past = match source.block_headers(start, count).await {
+        Ok(headers) => median_time_past(height, &headers),
+        Err(e) => {
+            warn!("Median time past unavailable: {}", e);
+            None
+        }
+    };
+    let tip_height = source.tip_height().await?;
+    let result = check_bitcoin_attestation(
+        &digest,
+        height as usize,
+        &block_header,
+        median_time_past,
+        tip_height,
+        min_confirmations,
+    )?;
+    info!("Success! {}", result);
+    Ok(result)
+}
+
+/// Verify the Bitcoin attestations of many timestamps against `source`,
+/// fetching each needed block header and the tip height only once.
+///
+/// Results are in the order of `timestamps`, the outer error is for a tip
+/// height the source could not tell.
+#[cfg(feature = "async")]
+pub async fn verify_batch(
+    timestamps: &[DetachedTimestampFile],
+    source: &dyn BlockHeaderSource,
+    min_confirmations: u32,
+) -> Result<Vec<Result<BitcoinAttestationResult, Error>>, Error> {
+    let mut headers = HashMap::new();
+    for (start, count) in batch_header_runs(timestamps) {
+        match source.block_headers(start, count).await {
+            Ok(fetched) => headers.extend((start..).zip(fetched)),
+            Err(e) => warn!(
+                "Block headers {} to {} unavailable: {}",
+                start,
+                start + count - 1,
+                e
+            ),
+        }
     }
-    Err(Error::Generic("No bitcoin attestion found".to_string()))
+    debug!(
+        "Fetched {} block headers for {} timestamps",
+        headers.len(),
+        timestamps.len()
+    );
+    let tip_height = source.tip_height().await?;
+    Ok(timestamps
+        .iter()
+        .map(|ots| check_batched_attestation(ots, &headers, tip_height, min_confirmations))
+        .collect())
 }
 
 #[cfg(feature = "async")]
 pub async fn upgrade(
     ots: &mut DetachedTimestampFile,
     calendar_urls: Option<Vec<String>>,
-) -> Result<(), Error> {
+    whitelist: &UrlWhitelist,
+) -> Result<UpgradeOutcome, Error> {
+    let mut outcome = UpgradeOutcome::default();
     for attestation in ots.timestamp.all_attestations() {
         match attestation.1 {
             Attestation::Bitcoin { height: _ } => {}
@@ -156,16 +761,23 @@ pub async fn upgrade(
                     .as_ref()
                     .is_some_and(|urls| !urls.contains(uri))
                 {
-                    error!("No valid calendar found");
+                    debug!("Skipping remote calendar {}", uri);
+                    outcome.skipped.push(uri.to_string());
+                    continue;
+                }
+                if !calendar_allowed(uri, calendar_urls.is_some(), whitelist) {
+                    outcome
+                        .errors
+                        .push((uri.to_string(), Error::CalendarNotWhitelisted(uri.clone())));
                     continue;
                 }
                 info!("Upgrading to remote calendar {}", uri.to_string());
-                let upgraded = upgrade_timestamp(attestation.0, uri.to_string(), None).await?;
-                ots.timestamp.merge(upgraded);
+                let upgraded = upgrade_timestamp(attestation.0, uri.to_string(), None).await;
+                merge_upgrade(ots, &mut outcome, uri, upgraded);
             }
         };
     }
-    Ok(())
+    Ok(outcome)
 }
 
 #[cfg(feature = "async")]
@@ -173,7 +785,7 @@ async fn upgrade_timestamp(
     commitment: Vec<u8>,
     calendar_url: String,
     timeout: Option<Duration>,
-) -> Result<Timestamp, Error> {
+) -> Result<Option<Timestamp>, Error> {
     use std::io::Cursor;
 
     let res = Calendar {
@@ -182,19 +794,29 @@ async fn upgrade_timestamp(
     }
     .get_timestamp(commitment.clone())
     .await
-    .map_err(|err| Error::NetworkError(err))?
-    .bytes()
-    .await
     .map_err(|err| Error::NetworkError(err))?;
+    if res.status() == reqwest::StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+    let res = res
+        .error_for_status()
+        .map_err(|err| Error::NetworkError(err))?
+        .bytes()
+        .await
+        .map_err(|err| Error::NetworkError(err))?;
     let mut deser = opentimestamps::ser::Deserializer::new(Cursor::new(res));
-    Timestamp::deserialize(&mut deser, commitment).map_err(|err| Error::InvalidOts(err))
+    Timestamp::deserialize(&mut deser, commitment)
+        .map(Some)
+        .map_err(|err| Error::InvalidOts(err))
 }
 
 #[cfg(not(feature = "async"))]
 pub fn upgrade(
     ots: &mut DetachedTimestampFile,
     calendar_urls: Option<Vec<String>>,
-) -> Result<(), Error> {
+    whitelist: &UrlWhitelist,
+) -> Result<UpgradeOutcome, Error> {
+    let mut outcome = UpgradeOutcome::default();
     for attestation in ots.timestamp.all_attestations() {
         match attestation.1 {
             Attestation::Bitcoin { height: _ } => {}
@@ -204,16 +826,23 @@ pub fn upgrade(
                     .as_ref()
                     .is_some_and(|urls| !urls.contains(uri))
                 {
-                    error!("No valid calendar found");
+                    debug!("Skipping remote calendar {}", uri);
+                    outcome.skipped.push(uri.to_string());
+                    continue;
+                }
+                if !calendar_allowed(uri, calendar_urls.is_some(), whitelist) {
+                    outcome
+                        .errors
+                        .push((uri.to_string(), Error::CalendarNotWhitelisted(uri.clone())));
                     continue;
                 }
                 info!("Upgrading to remote calendar {}", uri.to_string());
-                let upgraded = upgrade_timestamp(attestation.0, uri.to_string(), None)?;
-                ots.timestamp.merge(upgraded);
+                let upgraded = upgrade_timestamp(attestation.0, uri.to_string(), None);
+                merge_upgrade(ots, &mut outcome, uri, upgraded);
             }
         };
     }
-    Ok(())
+    Ok(outcome)
 }
 
 #[cfg(not(feature = "async"))]
@@ -221,18 +850,75 @@ fn upgrade_timestamp(
     commitment: Vec<u8>,
     calendar_url: String,
     timeout: Option<Duration>,
-) -> Result<Timestamp, Error> {
+) -> Result<Option<Timestamp>, Error> {
     let res = Calendar {
         url: calendar_url,
         timeout: timeout,
     }
     .get_timestamp(commitment.clone())
     .map_err(|err| Error::NetworkError(err))?;
+    if res.status() == reqwest::StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+    let res = res
+        .error_for_status()
+        .map_err(|err| Error::NetworkError(err))?;
     let mut deser = opentimestamps::ser::Deserializer::new(res);
-    Timestamp::deserialize(&mut deser, commitment).map_err(|err| Error::InvalidOts(err))
+    Timestamp::deserialize(&mut deser, commitment)
+        .map(Some)
+        .map_err(|err| Error::InvalidOts(err))
+}
+
+/// Whether the calendar at `uri` may be contacted: calendars explicitly
+/// requested by the caller skip the whitelist, but not the HTTPS requirement.
+fn calendar_allowed(uri: &str, requested: bool, whitelist: &UrlWhitelist) -> bool {
+    if !whitelist.is_secure(uri) {
+        warn!("Ignoring attestation from calendar {}: not over HTTPS", uri);
+        return false;
+    }
+    if !requested && !whitelist.contains(uri) {
+        warn!(
+            "Ignoring attestation from calendar {}: not whitelisted",
+            uri
+        );
+        return false;
+    }
+    true
 }
 
-fn timestamp_from_merkle(
+/// Merge the answer of a calendar into the timestamp, recording it in the outcome.
+fn merge_upgrade(
+    ots: &mut DetachedTimestampFile,
+    outcome: &mut UpgradeOutcome,
+    uri: &str,
+    upgraded: Result<Option<Timestamp>, Error>,
+) {
+    match upgraded {
+        Ok(Some(timestamp)) => {
+            let attestations: Vec<Attestation> = timestamp
+                .all_attestations()
+                .into_values()
+                .filter(|attestation| !matches!(attestation, Attestation::Pending { uri: _ }))
+                .collect();
+            if attestations.is_empty() {
+                outcome.pending.push(uri.to_string());
+                return;
+            }
+            ots.timestamp.merge(timestamp);
+            outcome.upgraded.extend(attestations);
+        }
+        Ok(None) => {
+            debug!("Remote calendar {} has no Bitcoin attestation yet", uri);
+            outcome.pending.push(uri.to_string());
+        }
+        Err(e) => {
+            error!("Upgrade from remote calendar {} failed: {}", uri, e);
+            outcome.errors.push((uri.to_string(), e));
+        }
+    }
+}
+
+pub(crate) fn timestamp_from_merkle(
     merkle_tree: &MerkleTree<Sha256>,
     leave: [u8; 32],
 ) -> Result<Timestamp, Error> {
@@ -285,17 +971,48 @@ fn timestamp_from_merkle(
     })
 }
 
-#[cfg(feature = "async")]
-pub async fn stamps(
-    digests: Vec<Vec<u8>>,
-    digest_type: DigestType,
-    calendar_urls: Option<Vec<String>>,
-    timeout: Option<Duration>,
-) -> Result<Vec<DetachedTimestampFile>, Error> {
+/// Digests aggregated in a merkle tree, before any calendar is contacted.
+#[derive(Clone)]
+pub struct Aggregation {
+    /// Partial timestamp of each digest, in order, ending at the tip
+    pub timestamps: Vec<DetachedTimestampFile>,
+    /// Merkle tip committing to every digest
+    pub tip: Vec<u8>,
+}
+
+impl Aggregation {
+    /// Complete the partial timestamps with a timestamp of the tip, from
+    /// calendars or anchored in another ledger.
+    pub fn attach(self, tip_timestamp: Timestamp) -> Result<Vec<DetachedTimestampFile>, Error> {
+        if tip_timestamp.start_digest != self.tip {
+            return Err(Error::Generic(format!(
+                "Timestamp is for {}, not for the tip {}",
+                Hexed(&tip_timestamp.start_digest),
+                Hexed(&self.tip)
+            )));
+        }
+        Ok(self
+            .timestamps
+            .into_iter()
+            .map(|mut ots| {
+                ots.timestamp.merge(tip_timestamp.clone());
+                ots
+            })
+            .collect())
+    }
+}
+
+/// Blind each digest with a random nonce and aggregate them in a merkle
+/// tree, without contacting any calendar: the tip can then be attested with
+/// [`attach_calendar_timestamps`] or anchored elsewhere.
+pub fn aggregate(digests: Vec<Vec<u8>>, digest_type: DigestType) -> Result<Aggregation, Error> {
+    if digests.is_empty() {
+        return Err(Error::Generic("No digest to timestamp".to_string()));
+    }
     let mut merkle_roots: Vec<[u8; 32]> = vec![];
     let mut file_timestamps: Vec<DetachedTimestampFile> = vec![];
     for digest in digests {
-        let random: Vec<u8> = (0..16).map(|_| rand::random::<u8>()).collect();
+        let random: Vec<u8> = (0..NONCE_SIZE).map(|_| rand::random::<u8>()).collect();
         let nonce_op = Op::Append(random);
         let nonce_output_digest = nonce_op.execute(&digest);
         let hash_op = Op::Sha256;
@@ -319,7 +1036,6 @@ pub async fn stamps(
         file_timestamps.push(file_timestamp.clone());
         merkle_roots.push(hash_output_digest.try_into().unwrap());
     }
-    debug!("file_timestamps {}", file_timestamps[0]);
     debug!("merkle_roots {:?}", merkle_roots.len());
     for root in merkle_roots.iter() {
         debug!("{:?}", Hexed(root));
@@ -334,23 +1050,113 @@ pub async fn stamps(
             }
         }
     }
+    Ok(Aggregation {
+        timestamps: file_timestamps,
+        tip: merkle_tip.to_vec(),
+    })
+}
+
+#[cfg(feature = "async")]
+pub async fn stamps(
+    digests: Vec<Vec<u8>>,
+    digest_type: DigestType,
+    calendar_urls: Option<Vec<String>>,
+    timeout: Option<Duration>,
+    network: Network,
+) -> Result<Vec<DetachedTimestampFile>, Error> {
+    let stamped =
+        stamps_with_receipts(digests, digest_type, calendar_urls, timeout, network).await?;
+    Ok(stamped.into_iter().map(|(ots, _)| ots).collect())
+}
+
+/// Timestamp `digests` as [`stamps`] does, along with a receipt of how each
+/// timestamp was made.
+#[cfg(feature = "async")]
+pub async fn stamps_with_receipts(
+    digests: Vec<Vec<u8>>,
+    digest_type: DigestType,
+    calendar_urls: Option<Vec<String>>,
+    timeout: Option<Duration>,
+    network: Network,
+) -> Result<Vec<(DetachedTimestampFile, StampReceipt)>, Error> {
+    let aggregation = aggregate(digests, digest_type)?;
+    let merkle_tip = Hexed(&aggregation.tip).to_string();
+    let batch_size = aggregation.timestamps.len();
+    let submitted = timestamp_to_iso8601(chrono::Utc::now().timestamp());
+    let (timestamps, submissions) =
+        attach_calendar_timestamps(aggregation, calendar_urls, timeout, network).await?;
+    let stamped = timestamps
+        .into_iter()
+        .map(|ots| {
+            let receipt = StampReceipt {
+                digest: Hexed(&ots.timestamp.start_digest).to_string(),
+                digest_algorithm: digest_algorithm(digest_type).to_string(),
+                nonce_policy: nonce_policy(),
+                merkle_tip: merkle_tip.clone(),
+                batch_size,
+                submitted: submitted.clone(),
+                calendars: submissions.clone(),
+                tool: TOOL.to_string(),
+            };
+            (ots, receipt)
+        })
+        .collect();
+    Ok(stamped)
+}
+
+/// Attest the tip of `aggregation` by the calendars, or the default ones of
+/// `network`, completing its timestamps; also report what each calendar
+/// answered.
+#[cfg(feature = "async")]
+pub async fn attach_calendar_timestamps(
+    aggregation: Aggregation,
+    calendar_urls: Option<Vec<String>>,
+    timeout: Option<Duration>,
+    network: Network,
+) -> Result<(Vec<DetachedTimestampFile>, Vec<CalendarSubmission>), Error> {
     let calendar_urls = match calendar_urls {
         Some(urls) => urls,
-        None => vec![APOOL.to_string(), BPOOL.to_string(), FINNEY.to_string()],
+        None => network::default_calendars(network),
     };
+    if calendar_urls.is_empty() {
+        return Err(Error::Generic(format!("No calendar known for {}", network)));
+    }
+    let (timestamp, submissions) =
+        calendar_timestamp(aggregation.tip.clone(), calendar_urls, timeout).await?;
+    Ok((aggregation.attach(timestamp)?, submissions))
+}
 
+/// Submit `tip` to every calendar, forking over the calendars that answered,
+/// and report what each calendar answered.
+#[cfg(feature = "async")]
+pub async fn calendar_timestamp(
+    tip: Vec<u8>,
+    calendar_urls: Vec<String>,
+    timeout: Option<Duration>,
+) -> Result<(Timestamp, Vec<CalendarSubmission>), Error> {
     let mut calendar_timestamps = vec![];
+    let mut submissions = vec![];
     for calendar in calendar_urls {
         info!("Submitting to remote calendar {}", calendar);
-        let calendar_timestamp =
-            create_timestamp(merkle_tip.to_vec(), calendar.clone(), timeout).await;
-        match calendar_timestamp {
-            Ok(timestamp) => calendar_timestamps.push(timestamp),
-            Err(e) => error!("Ignoring remote calendar {}: {}", calendar, e.to_string()),
-        }
+        let calendar_timestamp = create_timestamp(tip.clone(), calendar.clone(), timeout).await;
+        let error = match calendar_timestamp {
+            Ok(timestamp) => {
+                calendar_timestamps.push(timestamp);
+                None
+            }
+            Err(e) => {
+                error!("Ignoring remote calendar {}: {}", calendar, e.to_string());
+                Some(e.to_string())
+            }
+        };
+        submissions.push(CalendarSubmission {
+            url: calendar,
+            accepted: error.is_none(),
+            error,
+        });
     }
     if calendar_timestamps.is_empty() {
-        return Err(Error::Generic("No valid calendar found".to_string()));
+        return Err(unavailable(&submissions));
     }
     let timestamp: Timestamp;
     if calendar_timestamps.len() == 1 {
@@ -362,18 +1168,15 @@ pub async fn stamps(
             .collect();
         let fork = Step {
             data: StepData::Fork,
-            output: merkle_tip.to_vec(),
+            output: tip.clone(),
             next: steps,
         };
         timestamp = Timestamp {
-            start_digest: merkle_tip.to_vec(),
+            start_digest: tip.clone(),
             first_step: fork,
         };
     }
-    for ft in file_timestamps.iter_mut() {
-        ft.timestamp.merge(timestamp.clone());
-    }
-    Ok(file_timestamps)
+    Ok((timestamp, submissions))
 }
 
 #[cfg(feature = "async")]
@@ -404,65 +1207,100 @@ pub fn stamps(
     digest_type: DigestType,
     calendar_urls: Option<Vec<String>>,
     timeout: Option<Duration>,
+    network: Network,
 ) -> Result<Vec<DetachedTimestampFile>, Error> {
-    let mut merkle_roots: Vec<[u8; 32]> = vec![];
-    let mut file_timestamps: Vec<DetachedTimestampFile> = vec![];
-    for digest in digests {
-        let random: Vec<u8> = (0..16).map(|_| rand::random::<u8>()).collect();
-        let nonce_op = Op::Append(random);
-        let nonce_output_digest = nonce_op.execute(&digest);
-        let hash_op = Op::Sha256;
-        let hash_output_digest = hash_op.execute(&nonce_output_digest);
-        let file_timestamp = DetachedTimestampFile {
-            digest_type: digest_type,
-            timestamp: Timestamp {
-                start_digest: digest,
-                first_step: Step {
-                    data: StepData::Op(nonce_op),
-                    output: nonce_output_digest,
-                    next: vec![Step {
-                        data: StepData::Op(hash_op),
-                        output: hash_output_digest.clone(),
-                        next: vec![],
-                    }],
-                },
-            },
-        };
-        //let timestamp = file_timestamp.timestamp;
-        file_timestamps.push(file_timestamp.clone());
-        merkle_roots.push(hash_output_digest.try_into().unwrap());
-    }
-    debug!("file_timestamps {}", file_timestamps[0]);
-    debug!("merkle_roots {:?}", merkle_roots.len());
-    for root in merkle_roots.iter() {
-        debug!("{:?}", Hexed(root));
-    }
-    let merkle_tree = MerkleTree::<Sha256>::from_leaves(&merkle_roots);
-    let merkle_tip = merkle_tree.root().unwrap();
+    let stamped = stamps_with_receipts(digests, digest_type, calendar_urls, timeout, network)?;
+    Ok(stamped.into_iter().map(|(ots, _)| ots).collect())
+}
 
-    if file_timestamps.len() > 1 {
-        for ft in file_timestamps.iter_mut().enumerate() {
-            if let Ok(timestamp) = timestamp_from_merkle(&merkle_tree, merkle_roots[ft.0]) {
-                ft.1.timestamp.merge(timestamp);
-            }
-        }
-    }
+/// Timestamp `digests` as [`stamps`] does, along with a receipt of how each
+/// timestamp was made.
+#[cfg(not(feature = "async"))]
+pub fn stamps_with_receipts(
+    digests: Vec<Vec<u8>>,
+    digest_type: DigestType,
+    calendar_urls: Option<Vec<String>>,
+    timeout: Option<Duration>,
+    network: Network,
+) -> Result<Vec<(DetachedTimestampFile, StampReceipt)>, Error> {
+    let aggregation = aggregate(digests, digest_type)?;
+    let merkle_tip = Hexed(&aggregation.tip).to_string();
+    let batch_size = aggregation.timestamps.len();
+    let submitted = timestamp_to_iso8601(chrono::Utc::now().timestamp());
+    let (timestamps, submissions) =
+        attach_calendar_timestamps(aggregation, calendar_urls, timeout, network)?;
+    let stamped = timestamps
+        .into_iter()
+        .map(|ots| {
+            let receipt = StampReceipt {
+                digest: Hexed(&ots.timestamp.start_digest).to_string(),
+                digest_algorithm: digest_algorithm(digest_type).to_string(),
+                nonce_policy: nonce_policy(),
+                merkle_tip: merkle_tip.clone(),
+                batch_size,
+                submitted: submitted.clone(),
+                calendars: submissions.clone(),
+                tool: TOOL.to_string(),
+            };
+            (ots, receipt)
+        })
+        .collect();
+    Ok(stamped)
+}
+
+/// Attest the tip of `aggregation` by the calendars, or the default ones of
+/// `network`, completing its timestamps; also report what each calendar
+/// answered.
+#[cfg(not(feature = "async"))]
+pub fn attach_calendar_timestamps(
+    aggregation: Aggregation,
+    calendar_urls: Option<Vec<String>>,
+    timeout: Option<Duration>,
+    network: Network,
+) -> Result<(Vec<DetachedTimestampFile>, Vec<CalendarSubmission>), Error> {
     let calendar_urls = match calendar_urls {
         Some(urls) => urls,
-        None => vec![APOOL.to_string(), BPOOL.to_string(), FINNEY.to_string()],
+        None => network::default_calendars(network),
     };
+    if calendar_urls.is_empty() {
+        return Err(Error::Generic(format!("No calendar known for {}", network)));
+    }
+    let (timestamp, submissions) =
+        calendar_timestamp(aggregation.tip.clone(), calendar_urls, timeout)?;
+    Ok((aggregation.attach(timestamp)?, submissions))
+}
 
+/// Submit `tip` to every calendar, forking over the calendars that answered,
+/// and report what each calendar answered.
+#[cfg(not(feature = "async"))]
+pub fn calendar_timestamp(
+    tip: Vec<u8>,
+    calendar_urls: Vec<String>,
+    timeout: Option<Duration>,
+) -> Result<(Timestamp, Vec<CalendarSubmission>), Error> {
     let mut calendar_timestamps = vec![];
+    let mut submissions = vec![];
     for calendar in calendar_urls {
         info!("Submitting to remote calendar {}", calendar);
-        let calendar_timestamp = create_timestamp(merkle_tip.to_vec(), calendar.clone(), timeout);
-        match calendar_timestamp {
-            Ok(timestamp) => calendar_timestamps.push(timestamp),
-            Err(e) => error!("Ignoring remote calendar {}: {}", calendar, e.to_string()),
-        }
+        let calendar_timestamp = create_timestamp(tip.clone(), calendar.clone(), timeout);
+        let error = match calendar_timestamp {
+            Ok(timestamp) => {
+                calendar_timestamps.push(timestamp);
+                None
+            }
+            Err(e) => {
+                error!("Ignoring remote calendar {}: {}", calendar, e.to_string());
+                Some(e.to_string())
+            }
+        };
+        submissions.push(CalendarSubmission {
+            url: calendar,
+            accepted: error.is_none(),
+            error,
+        });
     }
     if calendar_timestamps.is_empty() {
-        return Err(Error::Generic("No valid calendar found".to_string()));
+        return Err(unavailable(&submissions));
     }
     let timestamp: Timestamp;
     if calendar_timestamps.len() == 1 {
@@ -474,18 +1312,15 @@ pub fn stamps(
             .collect();
         let fork = Step {
             data: StepData::Fork,
-            output: merkle_tip.to_vec(),
+            output: tip.clone(),
             next: steps,
         };
         timestamp = Timestamp {
-            start_digest: merkle_tip.to_vec(),
+            start_digest: tip.clone(),
             first_step: fork,
         };
     }
-    for ft in file_timestamps.iter_mut() {
-        ft.timestamp.merge(timestamp.clone());
-    }
-    Ok(file_timestamps)
+    Ok((timestamp, submissions))
 }
 
 #[cfg(not(feature = "async"))]
@@ -503,3 +1338,145 @@ fn create_timestamp(
     let mut deser = opentimestamps::ser::Deserializer::new(res);
     Timestamp::deserialize(&mut deser, stamp.to_vec()).map_err(|err| Error::InvalidOts(err))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shared_caches_by_source() {
+        // Disabled until asked for
+        assert!(shared_header_cache("tcp://a.example:50001").is_none());
+        share_header_caches(10);
+        let first = shared_header_cache("tcp://a.example:50001").unwrap();
+        let again = shared_header_cache("tcp://a.example:50001").unwrap();
+        let other = shared_header_cache("tcp://b.example:50001").unwrap();
+        assert!(Arc::ptr_eq(&first, &again));
+        assert!(!Arc::ptr_eq(&first, &other));
+    }
+
+    #[test]
+    fn upgradable_outcome() {
+        let mut outcome = UpgradeOutcome::default();
+        assert!(!outcome.upgradable());
+        outcome.skipped.push("https://a.example".to_string());
+        assert!(!outcome.upgradable());
+        let uri = "https://b.example".to_string();
+        outcome
+            .errors
+            .push((uri.clone(), Error::CalendarNotWhitelisted(uri.clone())));
+        assert!(!outcome.upgradable());
+        outcome
+            .errors
+            .push((uri.clone(), Error::Generic("unreachable".to_string())));
+        assert!(outcome.upgradable());
+        let pending = UpgradeOutcome {
+            pending: vec![uri],
+            ..Default::default()
+        };
+        assert!(pending.upgradable());
+    }
+
+    #[test]
+    fn no_calendar_answered() {
+        let submissions = vec![CalendarSubmission {
+            url: "https://a.example".to_string(),
+            accepted: false,
+            error: Some("Network error".to_string()),
+        }];
+        assert_eq!(
+            unavailable(&submissions).to_string(),
+            "No calendar answered: https://a.example (Network error)"
+        );
+        #[cfg(not(feature = "async"))]
+        assert!(matches!(
+            calendar_timestamp(vec![0; 32], vec![], None),
+            Err(Error::CalendarUnavailable(_))
+        ));
+    }
+
+    /// Headers whose merkle root is `root` at `height` only.
+    #[cfg(not(feature = "async"))]
+    struct RootSource {
+        height: u32,
+        root: Vec<u8>,
+    }
+
+    #[cfg(not(feature = "async"))]
+    impl BlockHeaderSource for RootSource {
+        fn block_header(&self, height: u32) -> Result<BlockHeader, Error> {
+            use bitcoincore_rpc::bitcoin::blockdata::constants::genesis_block;
+            use bitcoincore_rpc::bitcoin::hashes::Hash;
+            use bitcoincore_rpc::bitcoin::TxMerkleNode;
+
+            let mut header = genesis_block(Network::Bitcoin).header;
+            header.time += height * 600;
+            if height == self.height {
+                header.merkle_root = TxMerkleNode::from_slice(&self.root).unwrap();
+            }
+            Ok(header)
+        }
+
+        fn tip_height(&self) -> Result<u32, Error> {
+            Ok(1000)
+        }
+    }
+
+    #[cfg(not(feature = "async"))]
+    #[test]
+    fn verify_lowest_attestation() {
+        use crate::anchor::linear_timestamp;
+
+        let start = vec![3; 32];
+        let branch = |ops: Vec<Op>, height| {
+            linear_timestamp(start.clone(), ops, Attestation::Bitcoin { height })
+        };
+        let later = branch(vec![Op::Sha256], 200);
+        let earlier = branch(vec![Op::Append(vec![1]), Op::Sha256], 100);
+        let ots = DetachedTimestampFile {
+            digest_type: DigestType::Sha256,
+            timestamp: Timestamp {
+                start_digest: start.clone(),
+                first_step: Step {
+                    data: StepData::Fork,
+                    output: start.clone(),
+                    next: vec![later.first_step, earlier.first_step.clone()],
+                },
+            },
+        };
+        let (root, height) = lowest_bitcoin_attestation(&ots).unwrap();
+        assert_eq!(height, 100);
+        assert_eq!(
+            root,
+            Op::Sha256.execute(&Op::Append(vec![1]).execute(&start))
+        );
+        let source = RootSource { height, root };
+        // Whatever the order the attestations are listed in
+        for _ in 0..10 {
+            let copy = DetachedTimestampFile {
+                digest_type: DigestType::Sha256,
+                timestamp: ots.timestamp.clone(),
+            };
+            let result = verify_with_source(copy, &source, 1).unwrap();
+            assert_eq!(result.height, 100);
+        }
+    }
+
+    #[test]
+    fn median_time_past_needs_every_header() {
+        use bitcoincore_rpc::bitcoin::blockdata::constants::genesis_block;
+
+        let headers: Vec<BlockHeader> = (0..11)
+            .map(|i| {
+                let mut header = genesis_block(Network::Bitcoin).header;
+                header.time = 1000 + (i * 7 % 11) * 10;
+                header
+            })
+            .collect();
+        assert_eq!(median_time_past(20, &headers), Some(1050));
+        assert_eq!(median_time_past(20, &headers[..10]), None);
+        assert_eq!(median_time_past(20, &[]), None);
+        // Blocks near genesis have fewer headers before them
+        assert_eq!(median_time_past(2, &headers[..3]), Some(1030));
+    }
+}
@@ -12,6 +12,22 @@ pub enum Error {
     InvalidFile,
     #[error("Bitcoin node error")]
     BitcoinNodeError,
+    #[error("No calendar answered: {0}")]
+    CalendarUnavailable(String),
+    #[error("Calendar not whitelisted: {0}")]
+    CalendarNotWhitelisted(String),
+    #[error("Block header source error: {0}")]
+    HeaderSourceError(String),
+    #[error("Invalid block header: {0}")]
+    InvalidBlockHeader(String),
+    #[error("Block header sources disagree at height {height}: {details}")]
+    HeaderSourceMismatch { height: u32, details: String },
+    #[error("Timestamp is for digest {expected}, document digest is {actual}")]
+    DigestMismatch { expected: String, actual: String },
+    #[error("File already exists: {0}")]
+    AlreadyExists(String),
+    #[error("Invalid argument: {0}")]
+    InvalidArgument(String),
     // TODO remove into specific errors
     #[error("Generic error {0}")]
     Generic(String),
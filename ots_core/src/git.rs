@@ -0,0 +1,532 @@
+// Copyright (C) 2024 The OpenTimestamps developers
+
+use crate::error::Error;
+use crate::extensions::TimestampExtension;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use opentimestamps::op::Op;
+use opentimestamps::ser::{Deserializer, DigestType, Serializer};
+use opentimestamps::timestamp::{Step, StepData};
+use opentimestamps::{DetachedTimestampFile, Timestamp};
+use std::io::{Cursor, Read};
+use std::path::{Path, PathBuf};
+
+const BEGIN_TIMESTAMP: &str = "-----BEGIN OPENTIMESTAMPS GIT TIMESTAMP-----\n\n";
+const END_TIMESTAMP: &str = "-----END OPENTIMESTAMPS GIT TIMESTAMP-----\n";
+const GIT_HASH_SIZE: usize = 20;
+/// Suffix of the timestamps extracted from a commit, distinct from the `.ots`
+/// suffix of the timestamp of the file contents.
+pub const EXTRACTED_SUFFIX: &str = ".git.ots";
+/// Minor version of the git timestamp format written, as the Python client
+/// writes it when it does not rehash trees.
+const MINOR_VERSION: usize = 0;
+/// Minor versions read: 1 adds the rehashed trees to the commit timestamp,
+/// which does not change where it starts.
+const MINOR_VERSIONS: [usize; 2] = [0, 1];
+
+/// Digest timestamped for a signed commit or tag, committing to both the
+/// signed payload and its signature.
+pub fn signed_object_digest(payload: &[u8], signature: &[u8]) -> Vec<u8> {
+    let mut data = Op::Sha256.execute(payload);
+    data.extend(signature);
+    Op::Sha256.execute(&data)
+}
+
+/// Append a timestamp of the signature to it, armored as the Python client
+/// does: a varuint minor version followed by the timestamp, base64-encoded
+/// after the complete PGP signature.
+///
+/// The timestamp must start from [`signed_object_digest`] of `signature`.
+pub fn embed_timestamp(signature: &[u8], timestamp: &Timestamp) -> Result<Vec<u8>, Error> {
+    let mut buffer = vec![];
+    let mut ser = Serializer::new(&mut buffer);
+    ser.write_uint(MINOR_VERSION).map_err(Error::InvalidOts)?;
+    timestamp.serialize(&mut ser).map_err(Error::InvalidOts)?;
+    let encoded = STANDARD.encode(buffer);
+
+    let mut embedded = signature.to_vec();
+    embedded.extend(BEGIN_TIMESTAMP.as_bytes());
+    for line in encoded.as_bytes().chunks(64) {
+        embedded.extend(line);
+        embedded.push(b'\n');
+    }
+    embedded.extend(END_TIMESTAMP.as_bytes());
+    Ok(embedded)
+}
+
+/// Split the signature of `payload` into its embedded timestamp, if any, and
+/// the PGP signature preceding it, which the timestamp commits to.
+pub fn extract_timestamp(
+    payload: &[u8],
+    signature: &[u8],
+) -> Result<(Option<Timestamp>, Vec<u8>), Error> {
+    let Some(begin) = find(signature, BEGIN_TIMESTAMP.as_bytes()) else {
+        return Ok((None, signature.to_vec()));
+    };
+    let footer = format!("\n{}", END_TIMESTAMP);
+    let Some(end) = find(signature, footer.as_bytes()) else {
+        return Ok((None, signature.to_vec()));
+    };
+    let pgp = signature[..begin].to_vec();
+    let encoded: Vec<u8> = signature
+        .get(begin + BEGIN_TIMESTAMP.len()..end)
+        .ok_or(Error::Generic("Invalid git timestamp armor".to_string()))?
+        .iter()
+        .filter(|byte| !byte.is_ascii_whitespace())
+        .copied()
+        .collect();
+    let bytes = STANDARD
+        .decode(encoded)
+        .map_err(|_| Error::Generic("Invalid git timestamp encoding".to_string()))?;
+    let mut deser = Deserializer::new(Cursor::new(bytes));
+    let minor_version = deser.read_uint().map_err(Error::InvalidOts)?;
+    if !MINOR_VERSIONS.contains(&minor_version) {
+        return Err(Error::Generic(format!(
+            "Unsupported git timestamp version 1.{}",
+            minor_version
+        )));
+    }
+    let timestamp = Timestamp::deserialize(&mut deser, signed_object_digest(payload, &pgp))
+        .map_err(Error::InvalidOts)?;
+    Ok((Some(timestamp), pgp))
+}
+
+/// Offset of the first occurrence of `needle` in `haystack`.
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+/// Git blob id of `content`, as printed by `git hash-object`.
+pub fn blob_id(content: &[u8]) -> Vec<u8> {
+    let mut blob = format!("blob {}\0", content.len()).into_bytes();
+    blob.extend(content);
+    Op::Sha1.execute(&blob)
+}
+
+/// Check that the timestamp extracted with [`extract`] is of the git blob of
+/// the contents read from `reader`.
+pub fn verify_blob<R: Read>(ots: &DetachedTimestampFile, mut reader: R) -> Result<(), Error> {
+    let mut content = vec![];
+    reader
+        .read_to_end(&mut content)
+        .map_err(|_| Error::IOError)?;
+    crate::client::verify_digest(ots, &blob_id(&content))
+}
+
+/// Path of the file whose timestamp was extracted at `ots_path`: the same
+/// name without [`EXTRACTED_SUFFIX`].
+pub fn extracted_target_path(ots_path: &Path) -> Result<PathBuf, Error> {
+    match ots_path
+        .to_str()
+        .and_then(|path| path.strip_suffix(EXTRACTED_SUFFIX))
+    {
+        Some(target) if !target.is_empty() => Ok(PathBuf::from(target)),
+        _ => Err(Error::Generic(format!(
+            "{} has no {} extension, the target file must be given",
+            ots_path.display(),
+            EXTRACTED_SUFFIX
+        ))),
+    }
+}
+
+/// Split a raw commit into the payload git signs and its `gpgsig` signature.
+pub fn split_signed_commit(commit: &[u8]) -> Result<(Vec<u8>, Vec<u8>), Error> {
+    let mut payload = vec![];
+    let mut signature: Option<Vec<u8>> = None;
+    let mut in_headers = true;
+    let mut in_signature = false;
+    for line in commit.split_inclusive(|byte| *byte == b'\n') {
+        if in_headers && line == b"\n" {
+            in_headers = false;
+        }
+        if in_headers {
+            if let Some(first) = line.strip_prefix(b"gpgsig ") {
+                signature = Some(first.to_vec());
+                in_signature = true;
+                continue;
+            }
+            if in_signature {
+                if let Some(continuation) = line.strip_prefix(b" ") {
+                    signature.as_mut().unwrap().extend(continuation);
+                    continue;
+                }
+                in_signature = false;
+            }
+        }
+        payload.extend(line);
+    }
+    let signature = signature.ok_or(Error::Generic("Commit is not signed".to_string()))?;
+    Ok((payload, signature))
+}
+
+/// Reads objects from a git repository.
+pub trait GitObjects {
+    /// Raw content of the object `id` of type `kind`: `blob`, `tree` or `commit`
+    fn object(&self, kind: &str, id: &str) -> Result<Vec<u8>, Error>;
+}
+
+/// Local git repository, read through the `git` command.
+#[cfg(not(target_arch = "wasm32"))]
+pub struct GitRepository {
+    path: PathBuf,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl GitRepository {
+    pub fn open<P: AsRef<Path>>(path: P) -> Self {
+        GitRepository {
+            path: path.as_ref().to_path_buf(),
+        }
+    }
+
+    fn git(&self, args: &[&str]) -> Result<Vec<u8>, Error> {
+        let output = std::process::Command::new("git")
+            .arg("-C")
+            .arg(&self.path)
+            .args(args)
+            .output()
+            .map_err(|e| Error::Generic(format!("Could not run git: {}", e)))?;
+        if !output.status.success() {
+            return Err(Error::Generic(format!(
+                "git {} failed: {}",
+                args.join(" "),
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+        Ok(output.stdout)
+    }
+
+    /// Id of the commit `revision` points to.
+    pub fn resolve_commit(&self, revision: &str) -> Result<String, Error> {
+        let id = self.git(&["rev-parse", "--verify", &format!("{}^{{commit}}", revision)])?;
+        Ok(String::from_utf8_lossy(&id).trim().to_string())
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl GitObjects for GitRepository {
+    fn object(&self, kind: &str, id: &str) -> Result<Vec<u8>, Error> {
+        self.git(&["cat-file", kind, id])
+    }
+}
+
+/// Position of the id of the entry `name` in a raw tree, and whether the
+/// entry is a subtree.
+fn tree_entry(tree: &[u8], name: &str) -> Option<(usize, bool)> {
+    let mut offset = 0;
+    while offset < tree.len() {
+        let space = offset + tree[offset..].iter().position(|byte| *byte == b' ')?;
+        let nul = space + tree[space..].iter().position(|byte| *byte == 0)?;
+        let id = nul + 1;
+        if &tree[space + 1..nul] == name.as_bytes() {
+            return Some((id, &tree[offset..space] == b"40000"));
+        }
+        offset = id + GIT_HASH_SIZE;
+    }
+    None
+}
+
+/// Operations turning the id of the entry at `id_offset` into the id of `tree`.
+fn tree_ops(tree: &[u8], id_offset: usize) -> Vec<Op> {
+    let mut prefix = format!("tree {}\0", tree.len()).into_bytes();
+    prefix.extend(&tree[..id_offset]);
+    let suffix = &tree[id_offset + GIT_HASH_SIZE..];
+    let mut ops = vec![Op::Prepend(prefix)];
+    if !suffix.is_empty() {
+        ops.push(Op::Append(suffix.to_vec()));
+    }
+    ops.push(Op::Sha1);
+    ops
+}
+
+/// Build a standalone timestamp of the blob at `path` in the tree of the
+/// timestamped commit `commit`, chaining the blob id through the trees and
+/// the signed commit up to the timestamp embedded in its signature.
+///
+/// The proof is for the git blob id, as printed by `git hash-object`: verify
+/// it against the file with [`verify_blob`].
+pub fn extract(
+    objects: &dyn GitObjects,
+    commit: &str,
+    path: &str,
+) -> Result<DetachedTimestampFile, Error> {
+    let raw_commit = objects.object("commit", commit)?;
+    let (payload, signature) = split_signed_commit(&raw_commit)?;
+    let (commit_timestamp, pgp) = extract_timestamp(&payload, &signature)?;
+    let commit_timestamp = commit_timestamp.ok_or(Error::Generic(format!(
+        "Commit {} has no timestamp",
+        commit
+    )))?;
+
+    // The payload starts with the hex id of the root tree
+    let root_hex = payload
+        .strip_prefix(b"tree ")
+        .and_then(|rest| rest.get(..2 * GIT_HASH_SIZE))
+        .ok_or(Error::Generic(format!("Commit {} has no tree", commit)))?;
+    let root = String::from_utf8_lossy(root_hex).to_string();
+    let mut commit_ops = vec![
+        Op::Hexlify,
+        Op::Prepend(b"tree ".to_vec()),
+        Op::Append(payload[5 + 2 * GIT_HASH_SIZE..].to_vec()),
+        Op::Sha256,
+        Op::Append(pgp.clone()),
+        Op::Sha256,
+    ];
+
+    // Walk down to the blob, collecting the ops of each tree bottom-up
+    let mut levels = vec![];
+    let mut tree_id = root;
+    let components: Vec<&str> = path.split('/').filter(|c| !c.is_empty()).collect();
+    let mut blob_id = None;
+    for (depth, component) in components.iter().enumerate() {
+        let tree = objects.object("tree", &tree_id)?;
+        let (id_offset, is_tree) = tree_entry(&tree, component).ok_or(Error::Generic(format!(
+            "{} not found in commit {}",
+            path, commit
+        )))?;
+        let id = tree[id_offset..id_offset + GIT_HASH_SIZE].to_vec();
+        levels.push(tree_ops(&tree, id_offset));
+        let last = depth + 1 == components.len();
+        match (last, is_tree) {
+            (false, true) => tree_id = opentimestamps::hex::Hexed(&id).to_string(),
+            (true, false) => blob_id = Some(id),
+            _ => {
+                return Err(Error::Generic(format!(
+                    "{} is not a file in commit {}",
+                    path, commit
+                )))
+            }
+        }
+    }
+    let blob_id = blob_id.ok_or(Error::Generic(format!("Invalid path {}", path)))?;
+
+    let mut ops: Vec<Op> = levels.into_iter().rev().flatten().collect();
+    ops.append(&mut commit_ops);
+    // Compute outputs forward, then link the steps from the last one
+    let mut outputs = vec![];
+    let mut digest = blob_id.clone();
+    for op in &ops {
+        digest = op.execute(&digest);
+        outputs.push(digest.clone());
+    }
+    let mut next = vec![];
+    for (op, output) in ops.into_iter().zip(outputs).rev() {
+        next = vec![Step {
+            data: StepData::Op(op),
+            output,
+            next,
+        }];
+    }
+    let step = next.remove(0);
+    let mut timestamp = Timestamp {
+        start_digest: blob_id,
+        first_step: step,
+    };
+    timestamp.merge(commit_timestamp);
+    Ok(DetachedTimestampFile {
+        digest_type: DigestType::Sha1,
+        timestamp,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aggregator::serialize_timestamp;
+    use crate::anchor::linear_timestamp;
+    use opentimestamps::attestation::Attestation;
+
+    const PAYLOAD: &[u8] = b"tree 4b825dc642cb6eb9a060e54bf8d69288fbee4904\n\
+author Alice <alice@example.com> 1700000000 +0000\n\
+committer Alice <alice@example.com> 1700000000 +0000\n\
+\n\
+Initial commit\n";
+    const SIGNATURE: &[u8] = b"-----BEGIN PGP SIGNATURE-----\n\
+\n\
+iHUEABYKAB0WIQQAAAAAAAAAAAAAAAAAAAAAAAAAAAUCZVTbgAAKCRAAAAAAAAAA\n\
+=AAAA\n\
+-----END PGP SIGNATURE-----\n";
+    /// Armor written by the Python client for the timestamp of `timestamp()`.
+    const ARMOR: &[u8] = b"-----BEGIN OPENTIMESTAMPS GIT TIMESTAMP-----\n\
+\n\
+APAQAAECAwQFBgcICQoLDA0ODwgABYiWDXPXGQEDgOow\n\
+-----END OPENTIMESTAMPS GIT TIMESTAMP-----\n";
+
+    fn timestamp() -> Timestamp {
+        linear_timestamp(
+            signed_object_digest(PAYLOAD, SIGNATURE),
+            vec![Op::Append((0..16).collect()), Op::Sha256],
+            Attestation::Bitcoin { height: 800_000 },
+        )
+    }
+
+    #[test]
+    fn signed_object_digest_value() {
+        assert_eq!(
+            opentimestamps::hex::Hexed(&signed_object_digest(PAYLOAD, SIGNATURE)).to_string(),
+            "37772256c1ccf5cb8fc6b840fd988d572c61319afe2878a2c2432564ca453447"
+        );
+    }
+
+    #[test]
+    fn embed_after_signature() {
+        let embedded = embed_timestamp(SIGNATURE, &timestamp()).unwrap();
+        assert_eq!(&embedded[..SIGNATURE.len()], SIGNATURE);
+        assert_eq!(&embedded[SIGNATURE.len()..], ARMOR);
+    }
+
+    #[test]
+    fn extract_python_timestamp() {
+        let mut signature = SIGNATURE.to_vec();
+        signature.extend(ARMOR);
+        let (extracted, pgp) = extract_timestamp(PAYLOAD, &signature).unwrap();
+        assert_eq!(pgp, SIGNATURE);
+        let extracted = extracted.unwrap();
+        assert_eq!(
+            extracted.start_digest,
+            signed_object_digest(PAYLOAD, SIGNATURE)
+        );
+        assert_eq!(
+            serialize_timestamp(&extracted).unwrap(),
+            serialize_timestamp(&timestamp()).unwrap()
+        );
+    }
+
+    #[test]
+    fn extract_unstamped_signature() {
+        let (extracted, pgp) = extract_timestamp(PAYLOAD, SIGNATURE).unwrap();
+        assert!(extracted.is_none());
+        assert_eq!(pgp, SIGNATURE);
+    }
+
+    #[test]
+    fn extract_unsupported_version() {
+        let mut signature = SIGNATURE.to_vec();
+        signature.extend(BEGIN_TIMESTAMP.as_bytes());
+        // Minor version 2, then the same timestamp
+        signature.extend(b"AvAQAAECAwQFBgcICQoLDA0ODwgABYiWDXPXGQEDgOow\n");
+        signature.extend(END_TIMESTAMP.as_bytes());
+        assert!(extract_timestamp(PAYLOAD, &signature).is_err());
+    }
+
+    #[test]
+    fn split_commit_signature() {
+        let mut signature = SIGNATURE.to_vec();
+        signature.extend(ARMOR);
+        let text = String::from_utf8(signature.clone()).unwrap();
+        let mut lines = text.trim_end_matches('\n').split('\n');
+        let mut header = format!("gpgsig {}\n", lines.next().unwrap());
+        for line in lines {
+            header.push_str(&format!(" {}\n", line));
+        }
+        // git adds the signature after the last header
+        let mut commit = PAYLOAD[..PAYLOAD.len() - "\nInitial commit\n".len()].to_vec();
+        commit.extend(header.as_bytes());
+        commit.extend(b"\nInitial commit\n");
+
+        let (payload, split_signature) = split_signed_commit(&commit).unwrap();
+        assert_eq!(payload, PAYLOAD);
+        assert_eq!(split_signature, signature);
+    }
+
+    /// Run git in `dir`, feeding it `input`.
+    fn run_git(dir: &Path, args: &[&str], input: &[u8]) -> String {
+        use std::io::Write;
+        use std::process::{Command, Stdio};
+        let mut child = Command::new("git")
+            .arg("-C")
+            .arg(dir)
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        child.stdin.take().unwrap().write_all(input).unwrap();
+        let output = child.wait_with_output().unwrap();
+        assert!(output.status.success(), "git {:?} failed", args);
+        String::from_utf8(output.stdout).unwrap().trim().to_string()
+    }
+
+    #[test]
+    fn blob_id_value() {
+        // git hash-object of an empty file and of "hello\n"
+        assert_eq!(
+            opentimestamps::hex::Hexed(&blob_id(b"")).to_string(),
+            "e69de29bb2d1d6434b8b29ae775ad8c2e48c5391"
+        );
+        assert_eq!(
+            opentimestamps::hex::Hexed(&blob_id(b"hello\n")).to_string(),
+            "ce013625030ba8dba906f756967f9e9ca394464a"
+        );
+    }
+
+    #[test]
+    fn extracted_target() {
+        assert_eq!(
+            extracted_target_path(Path::new("dir/main.rs.git.ots")).unwrap(),
+            PathBuf::from("dir/main.rs")
+        );
+        assert!(extracted_target_path(Path::new("main.rs.ots")).is_err());
+    }
+
+    #[test]
+    fn extract_from_repository() {
+        let dir = std::env::temp_dir().join(format!("ots-git-extract-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("src")).unwrap();
+        run_git(&dir, &["init", "-q"], b"");
+        let content = b"fn main() {}\n";
+        std::fs::write(dir.join("src/main.rs"), content).unwrap();
+        std::fs::write(dir.join("README"), b"readme\n").unwrap();
+        run_git(&dir, &["add", "."], b"");
+        let tree = run_git(&dir, &["write-tree"], b"");
+
+        // Commit signed and timestamped as by the git wrapper
+        let payload = format!(
+            "tree {}\nauthor Alice <alice@example.com> 1700000000 +0000\n\
+committer Alice <alice@example.com> 1700000000 +0000\n",
+            tree
+        );
+        let message = "\nInitial commit\n";
+        let commit_timestamp = linear_timestamp(
+            signed_object_digest(format!("{}{}", payload, message).as_bytes(), SIGNATURE),
+            vec![Op::Append((0..16).collect()), Op::Sha256],
+            Attestation::Bitcoin { height: 800_000 },
+        );
+        let attested: Vec<Vec<u8>> = commit_timestamp.all_attestations().into_keys().collect();
+        let signature = embed_timestamp(SIGNATURE, &commit_timestamp).unwrap();
+        let signature = String::from_utf8(signature).unwrap();
+        let mut lines = signature.trim_end_matches('\n').split('\n');
+        let mut raw = format!("{}gpgsig {}\n", payload, lines.next().unwrap());
+        for line in lines {
+            raw.push_str(&format!(" {}\n", line));
+        }
+        raw.push_str(message);
+        let commit = run_git(
+            &dir,
+            &["hash-object", "-t", "commit", "-w", "--stdin"],
+            raw.as_bytes(),
+        );
+
+        let repository = GitRepository::open(&dir);
+        assert_eq!(repository.resolve_commit(&commit).unwrap(), commit);
+        let ots = extract(&repository, &commit, "src/main.rs").unwrap();
+        let blob = run_git(&dir, &["hash-object", "src/main.rs"], b"");
+        assert_eq!(
+            opentimestamps::hex::Hexed(&ots.timestamp.start_digest).to_string(),
+            blob
+        );
+        verify_blob(&ots, Cursor::new(content)).unwrap();
+        assert!(verify_blob(&ots, Cursor::new(b"fn main() {}")).is_err());
+        // The path from the blob reaches the attestation of the commit
+        let reached: Vec<Vec<u8>> = ots.timestamp.all_attestations().into_keys().collect();
+        assert_eq!(reached, attested);
+
+        assert!(extract(&repository, &commit, "src/missing.rs").is_err());
+        assert!(extract(&repository, &commit, "src").is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
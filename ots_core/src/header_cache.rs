@@ -0,0 +1,374 @@
+// Copyright (C) 2024 The OpenTimestamps developers
+
+use crate::atomic;
+use crate::error::Error;
+use crate::header_chain::{check_scattered, HeadersFile};
+use crate::headers::{serialize_header, BlockHeader, BlockHeaderSource};
+use crate::network::Network;
+
+use log::{debug, warn};
+use opentimestamps::hex::Hexed;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// Headers cached by default, a few hundred kilobytes.
+pub const DEFAULT_CACHE_CAPACITY: usize = 10_000;
+/// Confirmations a header needs to be cached, shallower ones could still be
+/// replaced by a reorganization.
+pub const DEFAULT_REORG_DEPTH: u32 = 6;
+
+/// Least recently used block headers, keyed by height, optionally persisted
+/// to a file.
+///
+/// The file is a text export with one `HEIGHT HEADER_HEX` line per block, as
+/// read by [`HeadersFile`]. Its headers are checked as those of a headers
+/// file, then against the checkpoints of the network and the difficulty of
+/// each other, the whole file being ignored if any is invalid.
+pub struct HeaderCache {
+    path: Option<PathBuf>,
+    capacity: usize,
+    headers: HashMap<u32, (BlockHeader, u64)>,
+    recent: BTreeMap<u64, u32>,
+    clock: u64,
+}
+
+impl HeaderCache {
+    /// In-memory cache of at most `capacity` headers.
+    pub fn new(capacity: usize) -> Self {
+        HeaderCache {
+            path: None,
+            capacity,
+            headers: HashMap::new(),
+            recent: BTreeMap::new(),
+            clock: 0,
+        }
+    }
+
+    /// Cache of headers of `network` persisted at `path`, starting empty if
+    /// the file does not exist yet or its headers are invalid.
+    pub fn open<P: AsRef<Path>>(path: P, capacity: usize, network: Network) -> Self {
+        let path = path.as_ref().to_path_buf();
+        let mut cache = HeaderCache::new(capacity);
+        if let Ok(text) = fs::read_to_string(&path) {
+            let checked = HeadersFile::from_export(&text, network).and_then(|file| {
+                let headers = file.into_headers();
+                check_scattered(network, &headers)?;
+                Ok(headers)
+            });
+            match checked {
+                Ok(headers) => {
+                    for (height, header) in headers {
+                        cache.insert(height, header);
+                    }
+                }
+                Err(e) => warn!("Ignoring header cache {}: {}", path.display(), e),
+            }
+        }
+        debug!("Loaded {} cached headers", cache.len());
+        cache.path = Some(path);
+        cache
+    }
+
+    pub fn len(&self) -> usize {
+        self.headers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.headers.is_empty()
+    }
+
+    /// Header at `height`, marking it as recently used.
+    pub fn get(&mut self, height: u32) -> Option<BlockHeader> {
+        let (header, used) = self.headers.get_mut(&height)?;
+        self.recent.remove(used);
+        self.clock += 1;
+        *used = self.clock;
+        self.recent.insert(self.clock, height);
+        Some(*header)
+    }
+
+    /// Cache the header at `height`, evicting the least recently used one
+    /// when full.
+    pub fn insert(&mut self, height: u32, header: BlockHeader) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.clock += 1;
+        if let Some((_, used)) = self.headers.insert(height, (header, self.clock)) {
+            self.recent.remove(&used);
+        }
+        self.recent.insert(self.clock, height);
+        while self.headers.len() > self.capacity {
+            let Some((_, oldest)) = self.recent.pop_first() else {
+                break;
+            };
+            self.headers.remove(&oldest);
+        }
+    }
+
+    /// Drop the headers at `height` and above.
+    pub fn invalidate_from(&mut self, height: u32) {
+        let stale: Vec<u32> = self
+            .headers
+            .keys()
+            .filter(|cached| **cached >= height)
+            .copied()
+            .collect();
+        for height in stale {
+            if let Some((_, used)) = self.headers.remove(&height) {
+                self.recent.remove(&used);
+            }
+        }
+    }
+
+    /// Persist the cache, if it has a file, replacing the previous content
+    /// atomically.
+    pub fn save(&self) -> Result<(), Error> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let sorted: BTreeMap<u32, &BlockHeader> = self
+            .headers
+            .iter()
+            .map(|(height, (header, _))| (*height, header))
+            .collect();
+        let mut content = String::new();
+        for (height, header) in sorted {
+            content.push_str(&format!(
+                "{} {}\n",
+                height,
+                Hexed(&serialize_header(header))
+            ));
+        }
+        atomic::write_file(path, content.as_bytes())
+    }
+}
+
+/// Headers of `source`, served from a [`HeaderCache`] once they are
+/// `reorg_depth` blocks deep. The cache is for this source only, several
+/// sources are cross-checked above their own caches, but can be shared
+/// across verifications.
+pub struct CachedSource {
+    source: Box<dyn BlockHeaderSource>,
+    cache: Arc<Mutex<HeaderCache>>,
+    tip: Mutex<Option<u32>>,
+    /// Confirmations a header needs to be cached
+    pub reorg_depth: u32,
+}
+
+impl CachedSource {
+    pub fn new(source: Box<dyn BlockHeaderSource>, cache: Arc<Mutex<HeaderCache>>) -> Self {
+        CachedSource {
+            source,
+            cache,
+            tip: Mutex::new(None),
+            reorg_depth: DEFAULT_REORG_DEPTH,
+        }
+    }
+
+    /// Cached headers of the `count` blocks starting at `height`, if all are.
+    fn cached(&self, height: u32, count: u32) -> Option<Vec<BlockHeader>> {
+        let mut cache = self.cache.lock().unwrap();
+        (height..height + count)
+            .map(|height| cache.get(height))
+            .collect()
+    }
+
+    /// Cache the headers starting at `height` deep enough below `tip`.
+    fn store(&self, height: u32, headers: &[BlockHeader], tip: u32) {
+        let mut cache = self.cache.lock().unwrap();
+        for (height, header) in (height..).zip(headers) {
+            if height + self.reorg_depth <= tip {
+                cache.insert(height, *header);
+            }
+        }
+    }
+
+    /// Record the tip, dropping cached headers no longer deep enough below
+    /// it, as after a reorganization to a shorter chain.
+    fn set_tip(&self, tip: u32) {
+        *self.tip.lock().unwrap() = Some(tip);
+        self.cache
+            .lock()
+            .unwrap()
+            .invalidate_from((tip + 1).saturating_sub(self.reorg_depth));
+    }
+}
+
+#[cfg(not(feature = "async"))]
+impl CachedSource {
+    fn known_tip(&self) -> Result<u32, Error> {
+        let tip = *self.tip.lock().unwrap();
+        match tip {
+            Some(tip) => Ok(tip),
+            None => self.tip_height(),
+        }
+    }
+}
+
+#[cfg(not(feature = "async"))]
+impl BlockHeaderSource for CachedSource {
+    fn block_header(&self, height: u32) -> Result<BlockHeader, Error> {
+        self.block_headers(height, 1)?
+            .pop()
+            .ok_or(Error::HeaderSourceError(format!(
+                "No block header at height {}",
+                height
+            )))
+    }
+
+    fn tip_height(&self) -> Result<u32, Error> {
+        let tip = self.source.tip_height()?;
+        self.set_tip(tip);
+        Ok(tip)
+    }
+
+    fn block_headers(&self, height: u32, count: u32) -> Result<Vec<BlockHeader>, Error> {
+        if let Some(headers) = self.cached(height, count) {
+            return Ok(headers);
+        }
+        let headers = self.source.block_headers(height, count)?;
+        self.store(height, &headers, self.known_tip()?);
+        Ok(headers)
+    }
+}
+
+#[cfg(feature = "async")]
+impl CachedSource {
+    async fn known_tip(&self) -> Result<u32, Error> {
+        let tip = *self.tip.lock().unwrap();
+        match tip {
+            Some(tip) => Ok(tip),
+            None => self.tip_height().await,
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+#[cfg_attr(target_arch = "wasm32", async_trait::async_trait(?Send))]
+#[cfg_attr(not(target_arch = "wasm32"), async_trait::async_trait)]
+impl BlockHeaderSource for CachedSource {
+    async fn block_header(&self, height: u32) -> Result<BlockHeader, Error> {
+        self.block_headers(height, 1)
+            .await?
+            .pop()
+            .ok_or(Error::HeaderSourceError(format!(
+                "No block header at height {}",
+                height
+            )))
+    }
+
+    async fn tip_height(&self) -> Result<u32, Error> {
+        let tip = self.source.tip_height().await?;
+        self.set_tip(tip);
+        Ok(tip)
+    }
+
+    async fn block_headers(&self, height: u32, count: u32) -> Result<Vec<BlockHeader>, Error> {
+        if let Some(headers) = self.cached(height, count) {
+            return Ok(headers);
+        }
+        let headers = self.source.block_headers(height, count).await?;
+        self.store(height, &headers, self.known_tip().await?);
+        Ok(headers)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::headers::deserialize_header;
+    use bitcoincore_rpc::bitcoin::blockdata::constants::genesis_block;
+    use bitcoincore_rpc::bitcoin::hex::FromHex;
+
+    const BLOCK_1: &str = "010000006fe28c0ab6f1b372c1a6a246ae63f74f931e8365e15a089c68d6190000000000982051fd1e4ba744bbbe680e1fee14677ba1a3c3540bf7b1cdb606e857233e0e61bc6649ffff001d01e36299";
+
+    /// Distinct headers, only told apart by their nonce.
+    fn header(nonce: u32) -> BlockHeader {
+        BlockHeader {
+            nonce,
+            ..genesis_block(Network::Regtest).header
+        }
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("ots-header-cache-{}-{}", name, std::process::id()))
+    }
+
+    #[test]
+    fn lru_eviction() {
+        let mut cache = HeaderCache::new(2);
+        cache.insert(1, header(1));
+        cache.insert(2, header(2));
+        // Using the first header makes the second one the least recently used
+        assert_eq!(cache.get(1), Some(header(1)));
+        cache.insert(3, header(3));
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get(2), None);
+        assert_eq!(cache.get(1), Some(header(1)));
+        assert_eq!(cache.get(3), Some(header(3)));
+        // Replacing a header does not evict another one
+        cache.insert(3, header(4));
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get(3), Some(header(4)));
+
+        let mut disabled = HeaderCache::new(0);
+        disabled.insert(1, header(1));
+        assert!(disabled.is_empty());
+    }
+
+    #[test]
+    fn invalidate_from() {
+        let mut cache = HeaderCache::new(10);
+        for height in 1..=5 {
+            cache.insert(height, header(height));
+        }
+        cache.invalidate_from(3);
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get(2), Some(header(2)));
+        assert_eq!(cache.get(3), None);
+        assert_eq!(cache.get(5), None);
+        // The evicted entries no longer count against the capacity
+        let mut cache = HeaderCache::new(2);
+        cache.insert(1, header(1));
+        cache.insert(2, header(2));
+        cache.invalidate_from(2);
+        cache.insert(3, header(3));
+        assert_eq!(cache.get(1), Some(header(1)));
+        assert_eq!(cache.get(3), Some(header(3)));
+    }
+
+    #[test]
+    fn save_and_open() {
+        let path = temp_path("valid");
+        let block_1 = deserialize_header(&Vec::<u8>::from_hex(BLOCK_1).unwrap()).unwrap();
+        let mut cache = HeaderCache::open(&path, 10, Network::Bitcoin);
+        assert!(cache.is_empty());
+        cache.insert(0, genesis_block(Network::Bitcoin).header);
+        cache.insert(1, block_1);
+        cache.save().unwrap();
+        let mut reopened = HeaderCache::open(&path, 10, Network::Bitcoin);
+        assert_eq!(reopened.len(), 2);
+        assert_eq!(reopened.get(1), Some(block_1));
+        fs::remove_file(&path).unwrap();
+
+        // A valid header at the height of a checkpoint it is not
+        let path = temp_path("checkpoint");
+        let mut cache = HeaderCache::open(&path, 10, Network::Bitcoin);
+        cache.insert(0, block_1);
+        cache.insert(1, block_1);
+        cache.save().unwrap();
+        assert!(HeaderCache::open(&path, 10, Network::Bitcoin).is_empty());
+        fs::remove_file(&path).unwrap();
+
+        // Headers without proof-of-work
+        let path = temp_path("unmined");
+        let mut cache = HeaderCache::open(&path, 10, Network::Bitcoin);
+        cache.insert(5, header(5));
+        cache.save().unwrap();
+        assert!(HeaderCache::open(&path, 10, Network::Bitcoin).is_empty());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,1156 @@
+// Copyright (C) 2024 The OpenTimestamps developers
+
+use crate::atomic;
+use crate::error::Error;
+use crate::headers::{deserialize_header, serialize_header, BlockHeader, BlockHeaderSource};
+use crate::network::{pow_limit_bits, Network};
+
+use bitcoincore_rpc::bitcoin::hashes::Hash;
+use bitcoincore_rpc::bitcoin::hex::FromHex;
+use bitcoincore_rpc::bitcoin::BlockHash;
+use log::{debug, info, warn};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Read;
+use std::path::Path;
+use std::str::FromStr;
+
+const HEADER_SIZE: usize = 80;
+const RETARGET_INTERVAL: u32 = 2016;
+/// Expected duration of a retarget period, in seconds.
+const TARGET_TIMESPAN: u32 = 14 * 24 * 60 * 60;
+/// Expected time between blocks, in seconds.
+const TARGET_SPACING: u32 = 10 * 60;
+/// Deepest reorganization `sync` follows before giving up.
+const MAX_REORG_DEPTH: u32 = 100;
+
+/// Block trusted without validation, from which a [`HeaderChain`] starts.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Checkpoint {
+    pub height: u32,
+    pub hash: BlockHash,
+}
+
+impl FromStr for Checkpoint {
+    type Err = Error;
+
+    /// Parse a checkpoint formatted as `HEIGHT:HASH`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || Error::Generic(format!("Invalid checkpoint {}, expected HEIGHT:HASH", s));
+        let (height, hash) = s.split_once(':').ok_or_else(invalid)?;
+        Ok(Checkpoint {
+            height: height.parse().map_err(|_| invalid())?,
+            hash: hash.parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+/// Checkpoints a header chain of `network` can be bootstrapped from.
+pub fn default_checkpoints(network: Network) -> Vec<Checkpoint> {
+    if network != Network::Bitcoin {
+        let genesis = bitcoincore_rpc::bitcoin::blockdata::constants::genesis_block(network);
+        return vec![Checkpoint {
+            height: 0,
+            hash: genesis.block_hash(),
+        }];
+    }
+    [
+        (
+            0,
+            "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f",
+        ),
+        (
+            840000,
+            "0000000000000000000320283a032748cef8227873ff4872689bf23f1cda83a5",
+        ),
+    ]
+    .iter()
+    .map(|(height, hash)| Checkpoint {
+        height: *height,
+        hash: hash.parse().unwrap(),
+    })
+    .collect()
+}
+
+/// Local chain of Bitcoin block headers, starting at a checkpoint and
+/// validated for linkage, proof-of-work and difficulty, used to check
+/// attestations offline.
+///
+/// The headers are stored from the first block of the retarget period of the
+/// checkpoint, so the first retarget after it can be validated. Those before
+/// the checkpoint are trusted through their link to it.
+pub struct HeaderChain {
+    network: Network,
+    checkpoint: Checkpoint,
+    headers: Vec<BlockHeader>,
+}
+
+impl HeaderChain {
+    /// An empty chain of `network`, whose header at the checkpoint height
+    /// must be the checkpoint block.
+    pub fn new(checkpoint: Checkpoint, network: Network) -> Self {
+        HeaderChain {
+            network,
+            checkpoint,
+            headers: vec![],
+        }
+    }
+
+    pub fn network(&self) -> Network {
+        self.network
+    }
+
+    pub fn checkpoint(&self) -> Checkpoint {
+        self.checkpoint
+    }
+
+    /// Height of the last header, if the chain reaches the checkpoint.
+    pub fn tip(&self) -> Option<u32> {
+        let next = self.next_height();
+        (next > self.checkpoint.height).then(|| next - 1)
+    }
+
+    /// Header at `height`, if the chain reaches the checkpoint.
+    pub fn get(&self, height: u32) -> Option<&BlockHeader> {
+        self.tip()?;
+        self.stored(height)
+    }
+
+    /// Height of the first stored header: the start of the retarget period
+    /// of the checkpoint.
+    fn base_height(&self) -> u32 {
+        self.checkpoint.height - self.checkpoint.height % RETARGET_INTERVAL
+    }
+
+    fn stored(&self, height: u32) -> Option<&BlockHeader> {
+        let index = height.checked_sub(self.base_height())?;
+        self.headers.get(index as usize)
+    }
+
+    fn next_height(&self) -> u32 {
+        self.base_height() + self.headers.len() as u32
+    }
+
+    /// Append a header after validating it against the chain.
+    pub fn push(&mut self, header: BlockHeader) -> Result<(), Error> {
+        let height = self.next_height();
+        if let Some(prev) = self.headers.last() {
+            if header.prev_blockhash != prev.block_hash() {
+                return Err(Error::InvalidBlockHeader(format!(
+                    "Block {} does not connect to the chain",
+                    height
+                )));
+            }
+        }
+        if height == self.checkpoint.height && header.block_hash() != self.checkpoint.hash {
+            return Err(Error::InvalidBlockHeader(format!(
+                "Block {} does not match checkpoint {}",
+                height, self.checkpoint.hash
+            )));
+        }
+        // Blocks up to the checkpoint are trusted, their difficulty is only
+        // needed to validate the following ones
+        if height > self.checkpoint.height {
+            check_difficulty(self.network, height, &header, |height| {
+                self.stored(height).copied()
+            })?;
+        }
+        check_pow(self.network, height, &header)?;
+        self.headers.push(header);
+        Ok(())
+    }
+
+    /// Drop the headers above `height`.
+    pub fn truncate(&mut self, height: u32) {
+        let len = height.saturating_sub(self.base_height()) + 1;
+        self.headers.truncate(len as usize);
+    }
+
+    /// Import raw 80-byte headers, the first one being at `start_height`.
+    /// Headers already in the chain must match.
+    pub fn import<R: Read>(&mut self, mut reader: R, start_height: u32) -> Result<u32, Error> {
+        if start_height > self.next_height() {
+            return Err(Error::InvalidBlockHeader(format!(
+                "Imported headers start at {}, chain ends before {}",
+                start_height,
+                self.next_height()
+            )));
+        }
+        let mut height = start_height;
+        let mut imported = 0;
+        let mut buffer = [0u8; HEADER_SIZE];
+        loop {
+            match reader.read_exact(&mut buffer) {
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+                Err(_) => return Err(Error::IOError),
+            }
+            let header = deserialize_header(&buffer)?;
+            // Headers before the retarget period of the checkpoint are not needed
+            if height >= self.base_height() {
+                match self.stored(height) {
+                    Some(existing) if *existing != header => {
+                        return Err(Error::InvalidBlockHeader(format!(
+                            "Imported block {} conflicts with the chain",
+                            height
+                        )));
+                    }
+                    Some(_) => {}
+                    None => {
+                        self.push(header)?;
+                        imported += 1;
+                    }
+                }
+            }
+            height += 1;
+        }
+        info!("Imported {} headers, tip at {:?}", imported, self.tip());
+        Ok(imported)
+    }
+
+    /// Load a chain of `network` saved by [`HeaderChain::save`], validating
+    /// every header.
+    pub fn load<P: AsRef<Path>>(path: P, network: Network) -> Result<Self, Error> {
+        let mut file = fs::File::open(path).map_err(|_| Error::InvalidFile)?;
+        let mut height = [0u8; 4];
+        let mut hash = [0u8; 32];
+        file.read_exact(&mut height)
+            .map_err(|_| Error::InvalidFile)?;
+        file.read_exact(&mut hash).map_err(|_| Error::InvalidFile)?;
+        let checkpoint = Checkpoint {
+            height: u32::from_le_bytes(height),
+            hash: BlockHash::from_byte_array(hash),
+        };
+        let mut chain = HeaderChain::new(checkpoint, network);
+        chain.import(file, chain.base_height())?;
+        Ok(chain)
+    }
+
+    /// Save the checkpoint followed by the raw headers, from the start of its
+    /// retarget period.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        let mut buffer = Vec::with_capacity(36 + self.headers.len() * HEADER_SIZE);
+        buffer.extend(self.checkpoint.height.to_le_bytes());
+        buffer.extend(self.checkpoint.hash.to_byte_array());
+        for header in self.headers.iter() {
+            buffer.extend(serialize_header(header));
+        }
+        atomic::write_file(path, &buffer)
+    }
+
+    /// Height of the deepest header to keep when the source disagrees with
+    /// the tip, given the source's header at each height.
+    fn fork_point(
+        &self,
+        source_hash: impl Fn(u32) -> Result<BlockHash, Error>,
+    ) -> Result<u32, Error> {
+        let tip = self.tip().unwrap_or(self.checkpoint.height);
+        let lowest = tip
+            .saturating_sub(MAX_REORG_DEPTH)
+            .max(self.checkpoint.height);
+        for height in (lowest..=tip).rev() {
+            if self.get(height).map(|h| h.block_hash()) == Some(source_hash(height)?) {
+                return Ok(height);
+            }
+        }
+        Err(Error::InvalidBlockHeader(format!(
+            "Source diverges from the chain deeper than {} blocks",
+            MAX_REORG_DEPTH
+        )))
+    }
+}
+
+#[cfg(not(feature = "async"))]
+impl HeaderChain {
+    /// Download and validate the headers up to the source tip, following
+    /// reorganizations, returning the new tip height.
+    pub fn sync(&mut self, source: &dyn BlockHeaderSource) -> Result<u32, Error> {
+        let source_tip = source.tip_height()?;
+        if source_tip < self.checkpoint.height {
+            return Err(Error::HeaderSourceError(format!(
+                "Source tip {} is below the checkpoint",
+                source_tip
+            )));
+        }
+        if let Some(tip) = self.tip() {
+            let header = self.get(tip.min(source_tip)).unwrap();
+            if source.block_header(tip.min(source_tip))? != *header {
+                let fork =
+                    self.fork_point(|height| Ok(source.block_header(height)?.block_hash()))?;
+                warn!("Reorganization detected, rolling back to {}", fork);
+                self.truncate(fork);
+            }
+        }
+        while self.next_height() <= source_tip {
+            let count = (source_tip - self.next_height() + 1).min(RETARGET_INTERVAL);
+            debug!("Fetching {} headers from {}", count, self.next_height());
+            let headers = source.block_headers(self.next_height(), count)?;
+            if headers.is_empty() {
+                break;
+            }
+            for header in headers {
+                self.push(header)?;
+            }
+        }
+        self.tip().ok_or(Error::HeaderSourceError(format!(
+            "Source has no header at checkpoint {}",
+            self.checkpoint.height
+        )))
+    }
+}
+
+#[cfg(feature = "async")]
+impl HeaderChain {
+    /// Download and validate the headers up to the source tip, following
+    /// reorganizations, returning the new tip height.
+    pub async fn sync(&mut self, source: &dyn BlockHeaderSource) -> Result<u32, Error> {
+        let source_tip = source.tip_height().await?;
+        if source_tip < self.checkpoint.height {
+            return Err(Error::HeaderSourceError(format!(
+                "Source tip {} is below the checkpoint",
+                source_tip
+            )));
+        }
+        if let Some(tip) = self.tip() {
+            let header = self.get(tip.min(source_tip)).unwrap();
+            if source.block_header(tip.min(source_tip)).await? != *header {
+                let lowest = tip
+                    .saturating_sub(MAX_REORG_DEPTH)
+                    .max(self.checkpoint.height);
+                let mut hashes = std::collections::HashMap::new();
+                for height in lowest..=tip.min(source_tip) {
+                    hashes.insert(height, source.block_header(height).await?.block_hash());
+                }
+                let fork = self.fork_point(|height| {
+                    hashes
+                        .get(&height)
+                        .copied()
+                        .ok_or(Error::HeaderSourceError(format!(
+                            "Missing block {}",
+                            height
+                        )))
+                })?;
+                warn!("Reorganization detected, rolling back to {}", fork);
+                self.truncate(fork);
+            }
+        }
+        while self.next_height() <= source_tip {
+            let count = (source_tip - self.next_height() + 1).min(RETARGET_INTERVAL);
+            debug!("Fetching {} headers from {}", count, self.next_height());
+            let headers = source.block_headers(self.next_height(), count).await?;
+            if headers.is_empty() {
+                break;
+            }
+            for header in headers {
+                self.push(header)?;
+            }
+        }
+        self.tip().ok_or(Error::HeaderSourceError(format!(
+            "Source has no header at checkpoint {}",
+            self.checkpoint.height
+        )))
+    }
+}
+
+#[cfg(not(feature = "async"))]
+impl BlockHeaderSource for HeaderChain {
+    fn block_header(&self, height: u32) -> Result<BlockHeader, Error> {
+        self.get(height)
+            .copied()
+            .ok_or(Error::HeaderSourceError(format!(
+                "Block {} is not in the local header chain",
+                height
+            )))
+    }
+
+    fn tip_height(&self) -> Result<u32, Error> {
+        self.tip()
+            .ok_or(Error::HeaderSourceError("Empty header chain".to_string()))
+    }
+}
+
+#[cfg(feature = "async")]
+#[cfg_attr(target_arch = "wasm32", async_trait::async_trait(?Send))]
+#[cfg_attr(not(target_arch = "wasm32"), async_trait::async_trait)]
+impl BlockHeaderSource for HeaderChain {
+    async fn block_header(&self, height: u32) -> Result<BlockHeader, Error> {
+        self.get(height)
+            .copied()
+            .ok_or(Error::HeaderSourceError(format!(
+                "Block {} is not in the local header chain",
+                height
+            )))
+    }
+
+    async fn tip_height(&self) -> Result<u32, Error> {
+        self.tip()
+            .ok_or(Error::HeaderSourceError("Empty header chain".to_string()))
+    }
+}
+
+/// Block headers supplied in a file, for verifying on machines that cannot
+/// reach any header source.
+///
+/// The file holds either raw 80-byte headers of consecutive blocks, or a
+/// text export with one `HEIGHT HEADER_HEX` line per block, the separator
+/// being spaces, a comma or a colon. Unlike a [`HeaderChain`], the headers
+/// are not tied to a checkpoint: only their proof-of-work, the linkage of
+/// consecutive ones and their difficulty, when the file has the previous
+/// headers the difficulty rules need, are checked, so the file must come
+/// from a trusted node.
+pub struct HeadersFile {
+    headers: BTreeMap<u32, BlockHeader>,
+}
+
+impl HeadersFile {
+    /// Parse raw headers, the first one being at `start_height`.
+    pub fn from_raw(bytes: &[u8], start_height: u32, network: Network) -> Result<Self, Error> {
+        if !bytes.len().is_multiple_of(HEADER_SIZE) {
+            return Err(Error::InvalidBlockHeader(format!(
+                "Headers file size is not a multiple of {} bytes",
+                HEADER_SIZE
+            )));
+        }
+        let mut headers = BTreeMap::new();
+        for (height, raw) in (start_height..).zip(bytes.chunks(HEADER_SIZE)) {
+            headers.insert(height, deserialize_header(raw)?);
+        }
+        HeadersFile::checked(headers, network)
+    }
+
+    /// Parse a text export of `HEIGHT HEADER_HEX` lines, skipping empty
+    /// lines and `#` comments.
+    pub fn from_export(text: &str, network: Network) -> Result<Self, Error> {
+        let mut headers = BTreeMap::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid =
+                || Error::InvalidBlockHeader(format!("Invalid headers file line {}", number + 1));
+            let (height, header) = line
+                .split_once(|c: char| c.is_whitespace() || c == ',' || c == ':')
+                .ok_or_else(invalid)?;
+            let height: u32 = height.trim().parse().map_err(|_| invalid())?;
+            let raw = Vec::<u8>::from_hex(header.trim()).map_err(|_| invalid())?;
+            headers.insert(height, deserialize_header(&raw)?);
+        }
+        HeadersFile::checked(headers, network)
+    }
+
+    /// Read the file at `path`, telling the text export from raw headers
+    /// starting at `start_height`.
+    pub fn open<P: AsRef<Path>>(
+        path: P,
+        start_height: u32,
+        network: Network,
+    ) -> Result<Self, Error> {
+        let bytes = fs::read(path).map_err(|_| Error::InvalidFile)?;
+        let is_text = bytes
+            .iter()
+            .all(|byte| byte.is_ascii_graphic() || byte.is_ascii_whitespace());
+        match std::str::from_utf8(&bytes) {
+            Ok(text) if is_text => HeadersFile::from_export(text, network),
+            _ => HeadersFile::from_raw(&bytes, start_height, network),
+        }
+    }
+
+    fn checked(headers: BTreeMap<u32, BlockHeader>, network: Network) -> Result<Self, Error> {
+        if headers.is_empty() {
+            return Err(Error::InvalidBlockHeader("Empty headers file".to_string()));
+        }
+        let mut previous: Option<(u32, &BlockHeader)> = None;
+        for (height, header) in headers.iter() {
+            check_pow(network, *height, header)?;
+            if let Some((prev_height, prev)) = previous {
+                if prev_height + 1 == *height && header.prev_blockhash != prev.block_hash() {
+                    return Err(Error::InvalidBlockHeader(format!(
+                        "Block {} does not connect to block {}",
+                        height, prev_height
+                    )));
+                }
+            }
+            if *height > 0 {
+                check_difficulty(network, *height, header, |height| {
+                    headers.get(&height).copied()
+                })
+                .or_else(|e| match e {
+                    Error::HeaderSourceError(_) => Ok(()),
+                    e => Err(e),
+                })?;
+            }
+            previous = Some((*height, header));
+        }
+        info!("Loaded {} headers", headers.len());
+        Ok(HeadersFile { headers })
+    }
+
+    pub fn get(&self, height: u32) -> Option<&BlockHeader> {
+        self.headers.get(&height)
+    }
+
+    pub(crate) fn into_headers(self) -> BTreeMap<u32, BlockHeader> {
+        self.headers
+    }
+
+    fn header(&self, height: u32) -> Result<BlockHeader, Error> {
+        self.get(height)
+            .copied()
+            .ok_or(Error::HeaderSourceError(format!(
+                "Block {} is not in the headers file",
+                height
+            )))
+    }
+}
+
+#[cfg(not(feature = "async"))]
+impl BlockHeaderSource for HeadersFile {
+    fn block_header(&self, height: u32) -> Result<BlockHeader, Error> {
+        self.header(height)
+    }
+
+    fn tip_height(&self) -> Result<u32, Error> {
+        Ok(*self.headers.keys().next_back().unwrap())
+    }
+}
+
+#[cfg(feature = "async")]
+#[cfg_attr(target_arch = "wasm32", async_trait::async_trait(?Send))]
+#[cfg_attr(not(target_arch = "wasm32"), async_trait::async_trait)]
+impl BlockHeaderSource for HeadersFile {
+    async fn block_header(&self, height: u32) -> Result<BlockHeader, Error> {
+        self.header(height)
+    }
+
+    async fn tip_height(&self) -> Result<u32, Error> {
+        Ok(*self.headers.keys().next_back().unwrap())
+    }
+}
+
+/// Check headers that are not all consecutive, as those of a header cache,
+/// beyond what [`HeadersFile`] does: a header at a checkpoint height must be
+/// the checkpoint block and, outside of the networks allowing minimum
+/// difficulty blocks, headers of a retarget period must share its difficulty
+/// and those of later periods have one the retargets in between can reach.
+pub(crate) fn check_scattered(
+    network: Network,
+    headers: &BTreeMap<u32, BlockHeader>,
+) -> Result<(), Error> {
+    for checkpoint in default_checkpoints(network) {
+        if let Some(header) = headers.get(&checkpoint.height) {
+            if header.block_hash() != checkpoint.hash {
+                return Err(Error::InvalidBlockHeader(format!(
+                    "Block {} does not match checkpoint {}",
+                    checkpoint.height, checkpoint.hash
+                )));
+            }
+        }
+    }
+    if matches!(network, Network::Testnet | Network::Regtest) {
+        return Ok(());
+    }
+    for ((height, header), (next_height, next)) in headers.iter().zip(headers.iter().skip(1)) {
+        let invalid_bits = |height: u32, bits: u32| {
+            Error::InvalidBlockHeader(format!("Block {} has invalid bits {:08x}", height, bits))
+        };
+        let (bits, next_bits) = (header.bits.to_consensus(), next.bits.to_consensus());
+        let target = target_from_compact(bits).ok_or_else(|| invalid_bits(*height, bits))?;
+        let next_target =
+            target_from_compact(next_bits).ok_or_else(|| invalid_bits(*next_height, next_bits))?;
+        let periods = next_height / RETARGET_INTERVAL - height / RETARGET_INTERVAL;
+        if !within_retargets(&target, &next_target, periods) {
+            return Err(Error::InvalidBlockHeader(format!(
+                "Block {} difficulty cannot follow the one of block {}",
+                next_height, height
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Whether `next` can follow `target` after `periods` retargets, each one
+/// changing the target by a factor of 4 at most.
+fn within_retargets(target: &[u8; 32], next: &[u8; 32], periods: u32) -> bool {
+    if periods == 0 {
+        return target == next;
+    }
+    // The compact encoding rounds each target down, leave room for it
+    let mut upper = *target;
+    let mut lower = scale_target(next, 2, 1);
+    // 4^128 covers any 256-bit target
+    for _ in 0..periods.min(128) {
+        upper = scale_target(&upper, 4, 1);
+        lower = scale_target(&lower, 4, 1);
+    }
+    *next <= upper && *target <= lower
+}
+
+/// Expand compact `bits` into a big-endian 256-bit target.
+fn target_from_compact(bits: u32) -> Option<[u8; 32]> {
+    let exponent = (bits >> 24) as isize;
+    let mantissa = bits & 0x007f_ffff;
+    if bits & 0x0080_0000 != 0 || mantissa == 0 || exponent > 32 {
+        return None;
+    }
+    let mut target = [0u8; 32];
+    for (i, byte) in mantissa.to_be_bytes()[1..].iter().enumerate() {
+        let position = 32 - exponent + i as isize;
+        if (0..32).contains(&position) {
+            target[position as usize] = *byte;
+        }
+    }
+    Some(target)
+}
+
+/// Compact encoding of a big-endian 256-bit `target`, as Bitcoin Core's
+/// `GetCompact`.
+fn compact_from_target(target: &[u8; 32]) -> u32 {
+    let mut size = 32 - target.iter().take_while(|byte| **byte == 0).count();
+    let start = 32 - size;
+    let mut compact = if size <= 3 {
+        let value = target[start..]
+            .iter()
+            .fold(0u32, |value, byte| value << 8 | *byte as u32);
+        value << (8 * (3 - size))
+    } else {
+        u32::from_be_bytes([0, target[start], target[start + 1], target[start + 2]])
+    };
+    if compact & 0x0080_0000 != 0 {
+        compact >>= 8;
+        size += 1;
+    }
+    compact | (size as u32) << 24
+}
+
+/// Multiply a big-endian 256-bit `target` by `mul / div`, saturating at the
+/// largest value.
+fn scale_target(target: &[u8; 32], mul: u32, div: u32) -> [u8; 32] {
+    // Little-endian 32-bit limbs, with a spare one for the carry
+    let mut limbs = [0u64; 9];
+    for (limb, chunk) in limbs.iter_mut().zip(target.rchunks(4)) {
+        *limb = u32::from_be_bytes(chunk.try_into().unwrap()) as u64;
+    }
+    let mut carry = 0;
+    for limb in limbs.iter_mut() {
+        let value = *limb * mul as u64 + carry;
+        *limb = value & 0xffff_ffff;
+        carry = value >> 32;
+    }
+    let mut remainder = 0;
+    for limb in limbs.iter_mut().rev() {
+        let value = remainder << 32 | *limb;
+        *limb = value / div as u64;
+        remainder = value % div as u64;
+    }
+    if limbs[8] != 0 {
+        return [0xff; 32];
+    }
+    let mut scaled = [0u8; 32];
+    for (chunk, limb) in scaled.rchunks_mut(4).zip(limbs) {
+        chunk.copy_from_slice(&(limb as u32).to_be_bytes());
+    }
+    scaled
+}
+
+/// Compact target following a retarget period whose last block has
+/// `last_bits`, the period spanning from `first_time` to `last_time`, as
+/// Bitcoin Core's `CalculateNextWorkRequired`.
+pub fn next_work_required(
+    network: Network,
+    last_bits: u32,
+    first_time: u32,
+    last_time: u32,
+) -> u32 {
+    let limit = target_from_compact(pow_limit_bits(network)).unwrap();
+    let Some(target) = target_from_compact(last_bits) else {
+        return pow_limit_bits(network);
+    };
+    let timespan = (last_time as i64 - first_time as i64)
+        .clamp(TARGET_TIMESPAN as i64 / 4, TARGET_TIMESPAN as i64 * 4);
+    let target = scale_target(&target, timespan as u32, TARGET_TIMESPAN);
+    compact_from_target(&target.min(limit))
+}
+
+/// Compact target the block at `height` must have, as Bitcoin Core's
+/// `GetNextWorkRequired`, `previous` giving the headers below it. Fails with
+/// a [`Error::HeaderSourceError`] when a header the rules need is missing.
+fn required_bits(
+    network: Network,
+    height: u32,
+    header: &BlockHeader,
+    previous: impl Fn(u32) -> Option<BlockHeader>,
+) -> Result<u32, Error> {
+    let missing = |height| {
+        Error::HeaderSourceError(format!(
+            "Block {} is needed to check the difficulty",
+            height
+        ))
+    };
+    let prev = previous(height - 1).ok_or_else(|| missing(height - 1))?;
+    let prev_bits = prev.bits.to_consensus();
+    let limit = pow_limit_bits(network);
+    // Testnet and regtest allow a minimum difficulty block after 20 minutes
+    // without a block, the following ones going back to the real difficulty
+    let min_difficulty = matches!(network, Network::Testnet | Network::Regtest);
+    if !height.is_multiple_of(RETARGET_INTERVAL) {
+        if !min_difficulty {
+            return Ok(prev_bits);
+        }
+        if header.time > prev.time.saturating_add(TARGET_SPACING * 2) {
+            return Ok(limit);
+        }
+        let (mut height, mut bits) = (height - 1, prev_bits);
+        while !height.is_multiple_of(RETARGET_INTERVAL) && bits == limit {
+            height -= 1;
+            bits = previous(height)
+                .ok_or_else(|| missing(height))?
+                .bits
+                .to_consensus();
+        }
+        return Ok(bits);
+    }
+    // Regtest never retargets
+    if network == Network::Regtest {
+        return Ok(prev_bits);
+    }
+    let first_height = height - RETARGET_INTERVAL;
+    let first = previous(first_height).ok_or_else(|| missing(first_height))?;
+    Ok(next_work_required(
+        network, prev_bits, first.time, prev.time,
+    ))
+}
+
+fn check_pow(network: Network, height: u32, header: &BlockHeader) -> Result<(), Error> {
+    let bits = header.bits.to_consensus();
+    let target = target_from_compact(bits).ok_or(Error::InvalidBlockHeader(format!(
+        "Block {} has invalid bits {:08x}",
+        height, bits
+    )))?;
+    if Some(target) > target_from_compact(pow_limit_bits(network)) {
+        return Err(Error::InvalidBlockHeader(format!(
+            "Block {} target is above the proof-of-work limit",
+            height
+        )));
+    }
+    let mut hash = header.block_hash().to_byte_array();
+    hash.reverse();
+    if hash > target {
+        return Err(Error::InvalidBlockHeader(format!(
+            "Block {} does not meet its proof-of-work target",
+            height
+        )));
+    }
+    Ok(())
+}
+
+fn check_difficulty(
+    network: Network,
+    height: u32,
+    header: &BlockHeader,
+    previous: impl Fn(u32) -> Option<BlockHeader>,
+) -> Result<(), Error> {
+    let bits = header.bits.to_consensus();
+    let required = required_bits(network, height, header, previous)?;
+    if bits != required {
+        return Err(Error::InvalidBlockHeader(format!(
+            "Block {} has difficulty bits {:08x}, expected {:08x}",
+            height, bits, required
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoincore_rpc::bitcoin::block::Version;
+    use bitcoincore_rpc::bitcoin::blockdata::constants::genesis_block;
+    use bitcoincore_rpc::bitcoin::{CompactTarget, TxMerkleNode};
+
+    const BLOCK_1: &str = "010000006fe28c0ab6f1b372c1a6a246ae63f74f931e8365e15a089c68d6190000000000982051fd1e4ba744bbbe680e1fee14677ba1a3c3540bf7b1cdb606e857233e0e61bc6649ffff001d01e36299";
+    const BLOCK_2: &str = "010000004860eb18bf1b1620e37e9490fc8a427514416fd75159ab86688e9a8300000000d5fdcc541e25de1c7a5addedf24858b8bb665c9f36ef744ee42c316022c90f9bb0bc6649ffff001d08d2bd61";
+
+    fn to_hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    fn header(hex: &str) -> BlockHeader {
+        deserialize_header(&Vec::<u8>::from_hex(hex).unwrap()).unwrap()
+    }
+
+    fn genesis(network: Network) -> BlockHeader {
+        genesis_block(network).header
+    }
+
+    fn genesis_checkpoint(network: Network) -> Checkpoint {
+        Checkpoint {
+            height: 0,
+            hash: genesis(network).block_hash(),
+        }
+    }
+
+    /// Header with the given fields, not meeting any proof-of-work.
+    fn unmined(prev: &BlockHeader, time: u32, bits: u32) -> BlockHeader {
+        BlockHeader {
+            version: Version::ONE,
+            prev_blockhash: prev.block_hash(),
+            merkle_root: TxMerkleNode::all_zeros(),
+            time,
+            bits: CompactTarget::from_consensus(bits),
+            nonce: 0,
+        }
+    }
+
+    /// Regtest header meeting its proof-of-work, cheap at regtest difficulty.
+    fn mine(prev: &BlockHeader, time: u32, bits: u32) -> BlockHeader {
+        let mut header = unmined(prev, time, bits);
+        while check_pow(Network::Regtest, 1, &header).is_err() {
+            header.nonce += 1;
+        }
+        header
+    }
+
+    /// Regtest chain of `len` blocks from genesis, the blocks above `fork`
+    /// differing from another chain made with a different `salt`.
+    fn regtest_chain(len: u32, fork: u32, salt: u32) -> Vec<BlockHeader> {
+        let mut headers = vec![genesis(Network::Regtest)];
+        for height in 1..len {
+            let prev = headers.last().unwrap();
+            let time = prev.time + 600 + if height > fork { salt } else { 0 };
+            headers.push(mine(prev, time, 0x207fffff));
+        }
+        headers
+    }
+
+    struct MockSource(Vec<BlockHeader>);
+
+    #[cfg(not(feature = "async"))]
+    impl BlockHeaderSource for MockSource {
+        fn block_header(&self, height: u32) -> Result<BlockHeader, Error> {
+            self.0
+                .get(height as usize)
+                .copied()
+                .ok_or(Error::HeaderSourceError("Missing".to_string()))
+        }
+
+        fn tip_height(&self) -> Result<u32, Error> {
+            Ok(self.0.len() as u32 - 1)
+        }
+    }
+
+    #[test]
+    fn parse_checkpoint() {
+        let checkpoint: Checkpoint =
+            "0:000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f"
+                .parse()
+                .unwrap();
+        assert_eq!(checkpoint, genesis_checkpoint(Network::Bitcoin));
+        assert!(
+            "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f"
+                .parse::<Checkpoint>()
+                .is_err()
+        );
+        assert!("x:00".parse::<Checkpoint>().is_err());
+    }
+
+    #[test]
+    fn compact_encoding() {
+        for bits in [
+            0x1d00ffff, 0x1b0404cb, 0x207fffff, 0x1e0377ae, 0x03123456, 0x02123400,
+        ] {
+            let target = target_from_compact(bits).unwrap();
+            assert_eq!(compact_from_target(&target), bits);
+        }
+        assert_eq!(target_from_compact(0x04923456), None);
+        assert_eq!(target_from_compact(0x21010000), None);
+        assert_eq!(compact_from_target(&[0; 32]), 0);
+    }
+
+    /// Vectors of Bitcoin Core's `pow_tests`.
+    #[test]
+    fn retarget_core_vectors() {
+        let vectors = [
+            (1261130161, 1262152739, 0x1d00ffff, 0x1d00d86a),
+            (1231006505, 1233061996, 0x1d00ffff, 0x1d00ffff),
+            (1279008237, 1279297671, 0x1c05a3f4, 0x1c0168fd),
+            (1263163443, 1269211443, 0x1c387f6f, 0x1d00e1fd),
+        ];
+        for (first_time, last_time, last_bits, expected) in vectors {
+            assert_eq!(
+                next_work_required(Network::Bitcoin, last_bits, first_time, last_time),
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn mainnet_pow() {
+        let genesis = genesis(Network::Bitcoin);
+        assert!(check_pow(Network::Bitcoin, 0, &genesis).is_ok());
+        assert!(check_pow(Network::Bitcoin, 1, &header(BLOCK_1)).is_ok());
+        let mut tampered = genesis;
+        tampered.nonce += 1;
+        assert!(check_pow(Network::Bitcoin, 0, &tampered).is_err());
+        // Regtest difficulty is above the mainnet limit
+        let easy = mine(&genesis, genesis.time + 600, 0x207fffff);
+        assert!(check_pow(Network::Bitcoin, 1, &easy).is_err());
+    }
+
+    #[test]
+    fn mainnet_linkage() {
+        let mut chain = HeaderChain::new(genesis_checkpoint(Network::Bitcoin), Network::Bitcoin);
+        assert_eq!(chain.tip(), None);
+        assert!(chain.push(header(BLOCK_1)).is_err());
+        chain.push(genesis(Network::Bitcoin)).unwrap();
+        assert!(chain.push(header(BLOCK_2)).is_err());
+        chain.push(header(BLOCK_1)).unwrap();
+        chain.push(header(BLOCK_2)).unwrap();
+        assert_eq!(chain.tip(), Some(2));
+        assert_eq!(chain.get(1), Some(&header(BLOCK_1)));
+        assert_eq!(chain.get(3), None);
+    }
+
+    #[test]
+    fn difficulty_outside_retarget() {
+        let genesis = genesis(Network::Bitcoin);
+        let prev = unmined(&genesis, genesis.time + 600, 0x1b0404cb);
+        let previous = |height| (height == 4).then_some(prev);
+        let same = unmined(&prev, prev.time + 600, 0x1b0404cb);
+        assert!(check_difficulty(Network::Bitcoin, 5, &same, previous).is_ok());
+        let easier = unmined(&prev, prev.time + 600, 0x1d00ffff);
+        assert!(matches!(
+            check_difficulty(Network::Bitcoin, 5, &easier, previous),
+            Err(Error::InvalidBlockHeader(_))
+        ));
+        // Mainnet has no minimum difficulty blocks
+        let late = unmined(&prev, prev.time + 3600, 0x1d00ffff);
+        assert!(check_difficulty(Network::Bitcoin, 5, &late, previous).is_err());
+    }
+
+    #[test]
+    fn difficulty_at_retarget() {
+        let genesis = genesis(Network::Bitcoin);
+        let first = unmined(&genesis, 1261130161, 0x1d00ffff);
+        let last = unmined(&genesis, 1262152739, 0x1d00ffff);
+        let previous = |height| match height {
+            30240 => Some(first),
+            32255 => Some(last),
+            _ => None,
+        };
+        let retarget = unmined(&last, last.time + 600, 0x1d00d86a);
+        assert!(check_difficulty(Network::Bitcoin, 32256, &retarget, previous).is_ok());
+        // Within a factor of 4, but not the expected target
+        let wrong = unmined(&last, last.time + 600, 0x1d00d800);
+        assert!(matches!(
+            check_difficulty(Network::Bitcoin, 32256, &wrong, previous),
+            Err(Error::InvalidBlockHeader(_))
+        ));
+        assert!(matches!(
+            check_difficulty(Network::Bitcoin, 32256, &retarget, |height| {
+                (height == 32255).then_some(last)
+            }),
+            Err(Error::HeaderSourceError(_))
+        ));
+    }
+
+    #[test]
+    fn testnet_minimum_difficulty() {
+        let limit = pow_limit_bits(Network::Testnet);
+        let genesis = genesis(Network::Testnet);
+        let real = unmined(&genesis, genesis.time, 0x1b0404cb);
+        let easy = unmined(&real, real.time + 1300, limit);
+        let previous = |height| match height {
+            8 => Some(real),
+            9 => Some(easy),
+            _ => None,
+        };
+        // After 20 minutes without a block, the minimum difficulty is allowed
+        let late = unmined(&easy, easy.time + 1201, limit);
+        assert!(check_difficulty(Network::Testnet, 10, &late, previous).is_ok());
+        // Otherwise, back to the difficulty of the last real block
+        let early = unmined(&easy, easy.time + 600, limit);
+        assert!(check_difficulty(Network::Testnet, 10, &early, previous).is_err());
+        let early = unmined(&easy, easy.time + 600, 0x1b0404cb);
+        assert!(check_difficulty(Network::Testnet, 10, &early, previous).is_ok());
+    }
+
+    #[test]
+    fn regtest_difficulty() {
+        let headers = regtest_chain(3, 0, 0);
+        let mut chain = HeaderChain::new(genesis_checkpoint(Network::Regtest), Network::Regtest);
+        for header in &headers {
+            chain.push(*header).unwrap();
+        }
+        let harder = mine(&headers[2], headers[2].time + 600, 0x2000ffff);
+        assert!(chain.push(harder).is_err());
+    }
+
+    #[test]
+    fn checkpoint_inside_retarget_period() {
+        let headers = regtest_chain(10, 0, 0);
+        let checkpoint = Checkpoint {
+            height: 5,
+            hash: headers[5].block_hash(),
+        };
+        let mut chain = HeaderChain::new(checkpoint, Network::Regtest);
+        for header in &headers[..5] {
+            chain.push(*header).unwrap();
+        }
+        // Headers before the checkpoint are not trusted until it is reached
+        assert_eq!(chain.tip(), None);
+        assert_eq!(chain.get(3), None);
+        let other = mine(&headers[4], headers[4].time + 1, 0x207fffff);
+        assert!(chain.push(other).is_err());
+        for header in &headers[5..] {
+            chain.push(*header).unwrap();
+        }
+        assert_eq!(chain.tip(), Some(9));
+        assert_eq!(chain.get(3), Some(&headers[3]));
+    }
+
+    #[test]
+    fn save_and_load() {
+        let headers = regtest_chain(10, 0, 0);
+        let checkpoint = Checkpoint {
+            height: 3,
+            hash: headers[3].block_hash(),
+        };
+        let mut chain = HeaderChain::new(checkpoint, Network::Regtest);
+        for header in &headers {
+            chain.push(*header).unwrap();
+        }
+        let path = std::env::temp_dir().join(format!("ots-headers-{}.bin", std::process::id()));
+        chain.save(&path).unwrap();
+        let loaded = HeaderChain::load(&path, Network::Regtest).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.checkpoint(), checkpoint);
+        assert_eq!(loaded.tip(), Some(9));
+        assert_eq!(loaded.get(0), Some(&headers[0]));
+        assert_eq!(loaded.get(9), Some(&headers[9]));
+    }
+
+    #[test]
+    fn fork_point() {
+        let ours = regtest_chain(20, 12, 0);
+        let theirs = regtest_chain(20, 12, 1);
+        let mut chain = HeaderChain::new(genesis_checkpoint(Network::Regtest), Network::Regtest);
+        for header in &ours {
+            chain.push(*header).unwrap();
+        }
+        let fork = chain.fork_point(|height| Ok(theirs[height as usize].block_hash()));
+        assert_eq!(fork.unwrap(), 12);
+        assert_eq!(
+            chain
+                .fork_point(|height| Ok(ours[height as usize].block_hash()))
+                .unwrap(),
+            19
+        );
+    }
+
+    #[cfg(not(feature = "async"))]
+    #[test]
+    fn sync_follows_reorg() {
+        let ours = regtest_chain(20, 12, 0);
+        let theirs = regtest_chain(25, 12, 1);
+        let mut chain = HeaderChain::new(genesis_checkpoint(Network::Regtest), Network::Regtest);
+        assert_eq!(chain.sync(&MockSource(ours.clone())).unwrap(), 19);
+        assert_eq!(chain.get(15), Some(&ours[15]));
+        assert_eq!(chain.sync(&MockSource(theirs.clone())).unwrap(), 24);
+        assert_eq!(chain.get(12), Some(&ours[12]));
+        assert_eq!(chain.get(15), Some(&theirs[15]));
+        assert_eq!(chain.get(24), Some(&theirs[24]));
+    }
+
+    #[cfg(not(feature = "async"))]
+    #[test]
+    fn sync_rejects_deep_reorg() {
+        let len = MAX_REORG_DEPTH + 20;
+        let ours = regtest_chain(len, 5, 0);
+        let theirs = regtest_chain(len + 5, 5, 1);
+        let mut chain = HeaderChain::new(genesis_checkpoint(Network::Regtest), Network::Regtest);
+        chain.sync(&MockSource(ours.clone())).unwrap();
+        assert!(matches!(
+            chain.sync(&MockSource(theirs)),
+            Err(Error::InvalidBlockHeader(_))
+        ));
+        assert_eq!(chain.tip(), Some(len - 1));
+        assert_eq!(chain.get(len - 1), Some(&ours[len as usize - 1]));
+    }
+
+    #[test]
+    fn headers_file() {
+        let export = format!(
+            "# height header\n0 {}\n1,{}\n\n2:{}\n",
+            to_hex(&serialize_header(&genesis(Network::Bitcoin))),
+            BLOCK_1,
+            BLOCK_2
+        );
+        let file = HeadersFile::from_export(&export, Network::Bitcoin).unwrap();
+        assert_eq!(file.get(1), Some(&header(BLOCK_1)));
+        assert_eq!(file.get(3), None);
+
+        let swapped = format!("1 {}\n2 {}\n", BLOCK_2, BLOCK_1);
+        assert!(HeadersFile::from_export(&swapped, Network::Bitcoin).is_err());
+        // Headers without their predecessors are only checked for proof-of-work
+        let sparse = format!("1 {}\n7 {}\n", BLOCK_1, BLOCK_2);
+        assert!(HeadersFile::from_export(&sparse, Network::Bitcoin).is_ok());
+        assert!(HeadersFile::from_export("1 00", Network::Bitcoin).is_err());
+        assert!(HeadersFile::from_export("", Network::Bitcoin).is_err());
+
+        let mut raw = serialize_header(&header(BLOCK_1));
+        raw.extend(serialize_header(&header(BLOCK_2)));
+        assert!(HeadersFile::from_raw(&raw, 1, Network::Bitcoin).is_ok());
+        assert!(HeadersFile::from_raw(&raw[1..], 1, Network::Bitcoin).is_err());
+    }
+
+    #[test]
+    fn headers_file_difficulty() {
+        let headers = regtest_chain(3, 0, 0);
+        let harder = mine(&headers[2], headers[2].time + 600, 0x2000ffff);
+        let export: String = headers
+            .iter()
+            .chain([&harder])
+            .enumerate()
+            .map(|(height, header)| format!("{} {}\n", height, to_hex(&serialize_header(header))))
+            .collect();
+        assert!(matches!(
+            HeadersFile::from_export(&export, Network::Regtest),
+            Err(Error::InvalidBlockHeader(_))
+        ));
+    }
+
+    #[test]
+    fn scattered_headers() {
+        let scattered = BTreeMap::from([(1, header(BLOCK_1)), (2, header(BLOCK_2))]);
+        assert!(check_scattered(Network::Bitcoin, &scattered).is_ok());
+        let at_genesis = BTreeMap::from([(0, header(BLOCK_1))]);
+        assert!(matches!(
+            check_scattered(Network::Bitcoin, &at_genesis),
+            Err(Error::InvalidBlockHeader(_))
+        ));
+        // Later retarget periods, all with the same bits as the first one
+        let far = BTreeMap::from([(1, header(BLOCK_1)), (40_001, header(BLOCK_2))]);
+        assert!(check_scattered(Network::Bitcoin, &far).is_ok());
+    }
+
+    #[test]
+    fn retarget_bounds() {
+        let target = target_from_compact(0x1b0404cb).unwrap();
+        // A quarter of the target, rounded down by the compact encoding
+        let quarter =
+            target_from_compact(compact_from_target(&scale_target(&target, 1, 4))).unwrap();
+        let easier = scale_target(&target, 4, 1);
+        let much_easier = scale_target(&target, 16, 1);
+        assert!(within_retargets(&target, &target, 0));
+        assert!(!within_retargets(&target, &easier, 0));
+        assert!(within_retargets(&target, &easier, 1));
+        assert!(!within_retargets(&target, &much_easier, 1));
+        assert!(within_retargets(&target, &much_easier, 2));
+        assert!(within_retargets(&easier, &target, 1));
+        assert!(!within_retargets(&much_easier, &target, 1));
+        assert!(within_retargets(&target, &quarter, 1));
+        assert!(!within_retargets(&target, &scale_target(&quarter, 1, 4), 1));
+        assert!(within_retargets(&target, &[0xff; 32], 200));
+    }
+}
@@ -0,0 +1,705 @@
+// Copyright (C) 2024 The OpenTimestamps developers
+
+use crate::error::Error;
+
+pub use bitcoincore_rpc::bitcoin::block::Header as BlockHeader;
+
+pub const DEFAULT_ELECTRUM: &str = "tcp://electrum.blockstream.info:50001";
+pub const DEFAULT_ESPLORA: &str = "https://blockstream.info/api";
+
+/// Source of the Bitcoin block headers attestations are checked against.
+#[cfg(not(feature = "async"))]
+pub trait BlockHeaderSource {
+    /// Header of the best chain block at `height`
+    fn block_header(&self, height: u32) -> Result<BlockHeader, Error>;
+
+    /// Height of the best chain tip
+    fn tip_height(&self) -> Result<u32, Error>;
+
+    /// Headers of `count` consecutive blocks starting at `height`
+    fn block_headers(&self, height: u32, count: u32) -> Result<Vec<BlockHeader>, Error> {
+        (height..height + count)
+            .map(|height| self.block_header(height))
+            .collect()
+    }
+}
+
+/// Thread-safety required from async sources, except on wasm where futures are not `Send`.
+#[cfg(all(feature = "async", not(target_arch = "wasm32")))]
+pub trait MaybeSendSync: Send + Sync {}
+#[cfg(all(feature = "async", not(target_arch = "wasm32")))]
+impl<T: Send + Sync> MaybeSendSync for T {}
+#[cfg(all(feature = "async", target_arch = "wasm32"))]
+pub trait MaybeSendSync {}
+#[cfg(all(feature = "async", target_arch = "wasm32"))]
+impl<T> MaybeSendSync for T {}
+
+/// Source of the Bitcoin block headers attestations are checked against.
+#[cfg(feature = "async")]
+#[cfg_attr(target_arch = "wasm32", async_trait::async_trait(?Send))]
+#[cfg_attr(not(target_arch = "wasm32"), async_trait::async_trait)]
+pub trait BlockHeaderSource: MaybeSendSync {
+    /// Header of the best chain block at `height`
+    async fn block_header(&self, height: u32) -> Result<BlockHeader, Error>;
+
+    /// Height of the best chain tip
+    async fn tip_height(&self) -> Result<u32, Error>;
+
+    /// Headers of `count` consecutive blocks starting at `height`
+    async fn block_headers(&self, height: u32, count: u32) -> Result<Vec<BlockHeader>, Error> {
+        let mut headers = vec![];
+        for height in height..height + count {
+            headers.push(self.block_header(height).await?);
+        }
+        Ok(headers)
+    }
+}
+
+/// Headers served by an Electrum server.
+///
+/// The Electrum client is blocking: in async mode its requests run on the
+/// blocking thread pool of the tokio runtime.
+#[cfg(feature = "electrum")]
+pub struct ElectrumSource {
+    client: std::sync::Arc<electrum_client::Client>,
+}
+
+#[cfg(feature = "electrum")]
+impl ElectrumSource {
+    pub fn new(url: &str) -> Result<Self, Error> {
+        let client = electrum_client::Client::new(url)
+            .map_err(|e| Error::HeaderSourceError(e.to_string()))?;
+        Ok(ElectrumSource {
+            client: std::sync::Arc::new(client),
+        })
+    }
+}
+
+#[cfg(feature = "electrum")]
+fn electrum_block_header(
+    client: &electrum_client::Client,
+    height: u32,
+) -> Result<BlockHeader, Error> {
+    use electrum_client::ElectrumApi;
+
+    client
+        .block_header(height as usize)
+        .map_err(|e| Error::HeaderSourceError(e.to_string()))
+}
+
+#[cfg(feature = "electrum")]
+fn electrum_tip_height(client: &electrum_client::Client) -> Result<u32, Error> {
+    use electrum_client::ElectrumApi;
+
+    client
+        .block_headers_subscribe()
+        .map(|notification| notification.height as u32)
+        .map_err(|e| Error::HeaderSourceError(e.to_string()))
+}
+
+#[cfg(feature = "electrum")]
+fn electrum_block_headers(
+    client: &electrum_client::Client,
+    height: u32,
+    count: u32,
+) -> Result<Vec<BlockHeader>, Error> {
+    use electrum_client::ElectrumApi;
+
+    // Electrum servers return at most one retarget period per request
+    let mut headers = vec![];
+    while (headers.len() as u32) < count {
+        let start = height + headers.len() as u32;
+        let chunk = (count - headers.len() as u32).min(2016);
+        let res = client
+            .block_headers(start as usize, chunk as usize)
+            .map_err(|e| Error::HeaderSourceError(e.to_string()))?;
+        if res.headers.is_empty() {
+            return Err(Error::HeaderSourceError(format!(
+                "Electrum server returned {} of the {} headers from height {}",
+                headers.len(),
+                count,
+                height
+            )));
+        }
+        headers.extend(res.headers);
+    }
+    headers.truncate(count as usize);
+    Ok(headers)
+}
+
+#[cfg(all(feature = "electrum", not(feature = "async")))]
+impl BlockHeaderSource for ElectrumSource {
+    fn block_header(&self, height: u32) -> Result<BlockHeader, Error> {
+        electrum_block_header(&self.client, height)
+    }
+
+    fn tip_height(&self) -> Result<u32, Error> {
+        electrum_tip_height(&self.client)
+    }
+
+    fn block_headers(&self, height: u32, count: u32) -> Result<Vec<BlockHeader>, Error> {
+        electrum_block_headers(&self.client, height, count)
+    }
+}
+
+#[cfg(all(feature = "electrum", feature = "async"))]
+impl ElectrumSource {
+    /// Run a blocking request without stalling the async executor.
+    async fn spawn<T, F>(&self, request: F) -> Result<T, Error>
+    where
+        T: Send + 'static,
+        F: FnOnce(&electrum_client::Client) -> Result<T, Error> + Send + 'static,
+    {
+        let client = self.client.clone();
+        tokio::task::spawn_blocking(move || request(&client))
+            .await
+            .map_err(|e| Error::HeaderSourceError(e.to_string()))?
+    }
+}
+
+#[cfg(all(feature = "electrum", feature = "async"))]
+#[async_trait::async_trait]
+impl BlockHeaderSource for ElectrumSource {
+    async fn block_header(&self, height: u32) -> Result<BlockHeader, Error> {
+        self.spawn(move |client| electrum_block_header(client, height))
+            .await
+    }
+
+    async fn tip_height(&self) -> Result<u32, Error> {
+        self.spawn(electrum_tip_height).await
+    }
+
+    async fn block_headers(&self, height: u32, count: u32) -> Result<Vec<BlockHeader>, Error> {
+        self.spawn(move |client| electrum_block_headers(client, height, count))
+            .await
+    }
+}
+
+/// Headers served by a Bitcoin Core node over JSON-RPC.
+#[cfg(not(feature = "async"))]
+pub struct BitcoindSource {
+    client: bitcoincore_rpc::Client,
+}
+
+#[cfg(not(feature = "async"))]
+impl BitcoindSource {
+    pub fn new(client: bitcoincore_rpc::Client) -> Self {
+        BitcoindSource { client }
+    }
+}
+
+#[cfg(not(feature = "async"))]
+impl BlockHeaderSource for BitcoindSource {
+    fn block_header(&self, height: u32) -> Result<BlockHeader, Error> {
+        use bitcoincore_rpc::RpcApi;
+
+        let block_hash = self
+            .client
+            .get_block_hash(height as u64)
+            .map_err(|_| Error::BitcoinNodeError)?;
+        self.client
+            .get_block_header(&block_hash)
+            .map_err(|_| Error::BitcoinNodeError)
+    }
+
+    fn tip_height(&self) -> Result<u32, Error> {
+        use bitcoincore_rpc::RpcApi;
+
+        self.client
+            .get_block_count()
+            .map(|count| count as u32)
+            .map_err(|_| Error::BitcoinNodeError)
+    }
+
+    /// Headers requested in two JSON-RPC batches, hashes then headers.
+    fn block_headers(&self, height: u32, count: u32) -> Result<Vec<BlockHeader>, Error> {
+        use bitcoincore_rpc::bitcoin::hex::FromHex;
+
+        let hashes: Vec<String> = self.batch(
+            "getblockhash",
+            (height..height + count).map(|height| vec![serde_json::json!(height)]),
+        )?;
+        let headers: Vec<String> = self.batch(
+            "getblockheader",
+            hashes
+                .into_iter()
+                .map(|hash| vec![serde_json::json!(hash), serde_json::json!(false)]),
+        )?;
+        headers
+            .iter()
+            .map(|header| {
+                let bytes = Vec::<u8>::from_hex(header)
+                    .map_err(|e| Error::InvalidBlockHeader(e.to_string()))?;
+                deserialize_header(&bytes)
+            })
+            .collect()
+    }
+}
+
+#[cfg(not(feature = "async"))]
+impl BitcoindSource {
+    /// Call `method` once per parameters in a single JSON-RPC batch.
+    fn batch<T, I>(&self, method: &str, params: I) -> Result<Vec<T>, Error>
+    where
+        T: serde::de::DeserializeOwned,
+        I: Iterator<Item = Vec<serde_json::Value>>,
+    {
+        let jsonrpc = self.client.get_jsonrpc_client();
+        let params = params
+            .map(|params| {
+                params
+                    .iter()
+                    .map(serde_json::value::to_raw_value)
+                    .collect::<Result<Vec<_>, _>>()
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| Error::BitcoinNodeError)?;
+        let requests: Vec<_> = params
+            .iter()
+            .map(|params| jsonrpc.build_request(method, params))
+            .collect();
+        if requests.is_empty() {
+            return Ok(vec![]);
+        }
+        let responses = jsonrpc
+            .send_batch(&requests)
+            .map_err(|_| Error::BitcoinNodeError)?;
+        responses
+            .into_iter()
+            .map(|response| {
+                response
+                    .ok_or(Error::BitcoinNodeError)?
+                    .result()
+                    .map_err(|_| Error::BitcoinNodeError)
+            })
+            .collect()
+    }
+}
+
+/// Headers served by an Esplora HTTP API.
+pub struct EsploraSource {
+    #[cfg(not(feature = "async"))]
+    client: esplora_client::BlockingClient,
+    #[cfg(feature = "async")]
+    client: esplora_client::AsyncClient,
+}
+
+#[cfg(not(feature = "async"))]
+impl EsploraSource {
+    pub fn new(url: &str) -> Result<Self, Error> {
+        let client = esplora_client::Builder::new(url).build_blocking();
+        Ok(EsploraSource { client })
+    }
+}
+
+#[cfg(not(feature = "async"))]
+impl BlockHeaderSource for EsploraSource {
+    fn block_header(&self, height: u32) -> Result<BlockHeader, Error> {
+        let block_hash = self
+            .client
+            .get_block_hash(height)
+            .map_err(|e| Error::HeaderSourceError(e.to_string()))?;
+        let header = self
+            .client
+            .get_header_by_hash(&block_hash)
+            .map_err(|e| Error::HeaderSourceError(e.to_string()))?;
+        // esplora-client depends on a newer rust-bitcoin, convert through the wire format
+        let bytes = esplora_client::bitcoin::consensus::serialize(&header);
+        deserialize_header(&bytes)
+    }
+
+    fn tip_height(&self) -> Result<u32, Error> {
+        self.client
+            .get_height()
+            .map_err(|e| Error::HeaderSourceError(e.to_string()))
+    }
+}
+
+#[cfg(feature = "async")]
+impl EsploraSource {
+    pub fn new(url: &str) -> Result<Self, Error> {
+        let client = esplora_client::Builder::new(url)
+            .build_async()
+            .map_err(|e| Error::HeaderSourceError(e.to_string()))?;
+        Ok(EsploraSource { client })
+    }
+}
+
+#[cfg(feature = "async")]
+#[cfg_attr(target_arch = "wasm32", async_trait::async_trait(?Send))]
+#[cfg_attr(not(target_arch = "wasm32"), async_trait::async_trait)]
+impl BlockHeaderSource for EsploraSource {
+    async fn block_header(&self, height: u32) -> Result<BlockHeader, Error> {
+        let block_hash = self
+            .client
+            .get_block_hash(height)
+            .await
+            .map_err(|e| Error::HeaderSourceError(e.to_string()))?;
+        let header = self
+            .client
+            .get_header_by_hash(&block_hash)
+            .await
+            .map_err(|e| Error::HeaderSourceError(e.to_string()))?;
+        // esplora-client depends on a newer rust-bitcoin, convert through the wire format
+        let bytes = esplora_client::bitcoin::consensus::serialize(&header);
+        deserialize_header(&bytes)
+    }
+
+    async fn tip_height(&self) -> Result<u32, Error> {
+        self.client
+            .get_height()
+            .await
+            .map_err(|e| Error::HeaderSourceError(e.to_string()))
+    }
+}
+
+/// Headers served by a Bitcoin Core node over asynchronous JSON-RPC.
+#[cfg(feature = "async")]
+pub struct BitcoindRpcSource {
+    url: String,
+    user: Option<String>,
+    password: Option<String>,
+    client: reqwest::Client,
+}
+
+#[cfg(feature = "async")]
+impl BitcoindRpcSource {
+    /// Connect to the node at `url`, authenticating with a user and password
+    /// or with the node cookie file.
+    pub fn new(url: &str, auth: bitcoincore_rpc::Auth) -> Result<Self, Error> {
+        let (user, password) = auth.get_user_pass().map_err(|_| Error::BitcoinNodeError)?;
+        let client = reqwest::Client::builder()
+            .build()
+            .map_err(Error::NetworkError)?;
+        Ok(BitcoindRpcSource {
+            url: url.to_string(),
+            user,
+            password,
+            client,
+        })
+    }
+
+    async fn call(
+        &self,
+        method: &str,
+        params: serde_json::Value,
+    ) -> Result<serde_json::Value, Error> {
+        let body = serde_json::json!({
+            "jsonrpc": "1.0",
+            "id": "ots",
+            "method": method,
+            "params": params,
+        });
+        let mut request = self
+            .client
+            .post(&self.url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(body.to_string());
+        if let Some(user) = &self.user {
+            request = request.basic_auth(user, self.password.as_ref());
+        }
+        let bytes = request
+            .send()
+            .await
+            .map_err(Error::NetworkError)?
+            .bytes()
+            .await
+            .map_err(Error::NetworkError)?;
+        let mut response: serde_json::Value =
+            serde_json::from_slice(&bytes).map_err(|_| Error::BitcoinNodeError)?;
+        if !response["error"].is_null() {
+            return Err(Error::HeaderSourceError(format!(
+                "{} failed: {}",
+                method, response["error"]
+            )));
+        }
+        Ok(response["result"].take())
+    }
+}
+
+#[cfg(feature = "async")]
+#[cfg_attr(target_arch = "wasm32", async_trait::async_trait(?Send))]
+#[cfg_attr(not(target_arch = "wasm32"), async_trait::async_trait)]
+impl BlockHeaderSource for BitcoindRpcSource {
+    async fn block_header(&self, height: u32) -> Result<BlockHeader, Error> {
+        use bitcoincore_rpc::bitcoin::hex::FromHex;
+
+        let block_hash = self
+            .call("getblockhash", serde_json::json!([height]))
+            .await?;
+        let header = self
+            .call("getblockheader", serde_json::json!([block_hash, false]))
+            .await?;
+        let bytes = header
+            .as_str()
+            .and_then(|hex| Vec::<u8>::from_hex(hex).ok())
+            .ok_or(Error::BitcoinNodeError)?;
+        deserialize_header(&bytes)
+    }
+
+    async fn tip_height(&self) -> Result<u32, Error> {
+        self.call("getblockcount", serde_json::json!([]))
+            .await?
+            .as_u64()
+            .map(|count| count as u32)
+            .ok_or(Error::BitcoinNodeError)
+    }
+}
+
+/// Headers cross-checked across several independent sources, so that a
+/// single lying source cannot make a forged header accepted.
+pub struct CrossCheckSource {
+    sources: Vec<(String, Box<dyn BlockHeaderSource>)>,
+}
+
+impl CrossCheckSource {
+    pub fn new() -> Self {
+        CrossCheckSource { sources: vec![] }
+    }
+
+    /// Add a source, named for error reporting.
+    pub fn add(&mut self, name: &str, source: Box<dyn BlockHeaderSource>) {
+        self.sources.push((name.to_string(), source));
+    }
+
+    pub fn len(&self) -> usize {
+        self.sources.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sources.is_empty()
+    }
+
+    /// Require every source to return the same header.
+    fn agree(height: u32, headers: Vec<(&str, BlockHeader)>) -> Result<BlockHeader, Error> {
+        let (first_name, first) = headers.first().copied().ok_or(Error::HeaderSourceError(
+            "No block header source configured".to_string(),
+        ))?;
+        let disagreeing: Vec<String> = headers
+            .iter()
+            .filter(|(_, header)| *header != first)
+            .map(|(name, header)| format!("{} returned {}", name, header.block_hash()))
+            .collect();
+        if !disagreeing.is_empty() {
+            return Err(Error::HeaderSourceMismatch {
+                height,
+                details: format!(
+                    "{} returned {}, {}",
+                    first_name,
+                    first.block_hash(),
+                    disagreeing.join(", ")
+                ),
+            });
+        }
+        Ok(first)
+    }
+
+    /// Require every source to return the same `count` headers from `height`.
+    fn agree_all(
+        height: u32,
+        count: u32,
+        fetched: Vec<(&str, Vec<BlockHeader>)>,
+    ) -> Result<Vec<BlockHeader>, Error> {
+        if let Some((name, headers)) = fetched
+            .iter()
+            .find(|(_, headers)| headers.len() != count as usize)
+        {
+            return Err(Error::HeaderSourceError(format!(
+                "{} returned {} of the {} headers from height {}",
+                name,
+                headers.len(),
+                count,
+                height
+            )));
+        }
+        (0..count as usize)
+            .map(|offset| {
+                let headers = fetched
+                    .iter()
+                    .map(|(name, headers)| (*name, headers[offset]))
+                    .collect();
+                CrossCheckSource::agree(height + offset as u32, headers)
+            })
+            .collect()
+    }
+}
+
+impl Default for CrossCheckSource {
+    fn default() -> Self {
+        CrossCheckSource::new()
+    }
+}
+
+#[cfg(not(feature = "async"))]
+impl BlockHeaderSource for CrossCheckSource {
+    fn block_header(&self, height: u32) -> Result<BlockHeader, Error> {
+        let mut headers = vec![];
+        for (name, source) in self.sources.iter() {
+            headers.push((name.as_str(), source.block_header(height)?));
+        }
+        CrossCheckSource::agree(height, headers)
+    }
+
+    fn block_headers(&self, height: u32, count: u32) -> Result<Vec<BlockHeader>, Error> {
+        let mut fetched = vec![];
+        for (name, source) in self.sources.iter() {
+            fetched.push((name.as_str(), source.block_headers(height, count)?));
+        }
+        CrossCheckSource::agree_all(height, count, fetched)
+    }
+
+    /// The lowest tip, the only height every source can answer for.
+    fn tip_height(&self) -> Result<u32, Error> {
+        let mut tip = None;
+        for (_, source) in self.sources.iter() {
+            let height = source.tip_height()?;
+            tip = Some(tip.map_or(height, |tip: u32| tip.min(height)));
+        }
+        tip.ok_or(Error::HeaderSourceError(
+            "No block header source configured".to_string(),
+        ))
+    }
+}
+
+#[cfg(feature = "async")]
+#[cfg_attr(target_arch = "wasm32", async_trait::async_trait(?Send))]
+#[cfg_attr(not(target_arch = "wasm32"), async_trait::async_trait)]
+impl BlockHeaderSource for CrossCheckSource {
+    async fn block_header(&self, height: u32) -> Result<BlockHeader, Error> {
+        let mut headers = vec![];
+        for (name, source) in self.sources.iter() {
+            headers.push((name.as_str(), source.block_header(height).await?));
+        }
+        CrossCheckSource::agree(height, headers)
+    }
+
+    async fn block_headers(&self, height: u32, count: u32) -> Result<Vec<BlockHeader>, Error> {
+        let mut fetched = vec![];
+        for (name, source) in self.sources.iter() {
+            fetched.push((name.as_str(), source.block_headers(height, count).await?));
+        }
+        CrossCheckSource::agree_all(height, count, fetched)
+    }
+
+    /// The lowest tip, the only height every source can answer for.
+    async fn tip_height(&self) -> Result<u32, Error> {
+        let mut tip = None;
+        for (_, source) in self.sources.iter() {
+            let height = source.tip_height().await?;
+            tip = Some(tip.map_or(height, |tip: u32| tip.min(height)));
+        }
+        tip.ok_or(Error::HeaderSourceError(
+            "No block header source configured".to_string(),
+        ))
+    }
+}
+
+/// Open the header source at `url`, picking the backend from its scheme:
+/// `tcp://` and `ssl://` for Electrum servers, `https://` and `http://` for
+/// Esplora APIs.
+pub fn source_from_url(url: &str) -> Result<Box<dyn BlockHeaderSource>, Error> {
+    match url.split_once("://").map(|(scheme, _)| scheme) {
+        #[cfg(feature = "electrum")]
+        Some("tcp") | Some("ssl") => Ok(Box::new(ElectrumSource::new(url)?)),
+        #[cfg(not(feature = "electrum"))]
+        Some("tcp") | Some("ssl") => Err(Error::HeaderSourceError(format!(
+            "Electrum header source {} needs ots_core built with the `electrum` feature",
+            url
+        ))),
+        Some("https") | Some("http") => Ok(Box::new(EsploraSource::new(url)?)),
+        _ => Err(Error::HeaderSourceError(format!(
+            "Unsupported header source {}",
+            url
+        ))),
+    }
+}
+
+/// Parse an 80-byte consensus-encoded block header.
+pub fn deserialize_header(bytes: &[u8]) -> Result<BlockHeader, Error> {
+    bitcoincore_rpc::bitcoin::consensus::deserialize(bytes)
+        .map_err(|e| Error::InvalidBlockHeader(e.to_string()))
+}
+
+/// Consensus-encode a block header into its 80 bytes.
+pub fn serialize_header(header: &BlockHeader) -> Vec<u8> {
+    bitcoincore_rpc::bitcoin::consensus::serialize(header)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoincore_rpc::bitcoin::blockdata::constants::genesis_block;
+    use bitcoincore_rpc::bitcoin::Network;
+
+    #[test]
+    fn header_encoding() {
+        let genesis = genesis_block(Network::Bitcoin).header;
+        let raw = serialize_header(&genesis);
+        assert_eq!(raw.len(), 80);
+        assert_eq!(deserialize_header(&raw).unwrap(), genesis);
+        assert!(matches!(
+            deserialize_header(&raw[..79]),
+            Err(Error::InvalidBlockHeader(_))
+        ));
+    }
+
+    #[test]
+    fn unsupported_source() {
+        assert!(matches!(
+            source_from_url("ftp://example.com"),
+            Err(Error::HeaderSourceError(_))
+        ));
+        assert!(source_from_url("example.com:50001").is_err());
+    }
+
+    #[cfg(not(feature = "electrum"))]
+    #[test]
+    fn electrum_needs_feature() {
+        match source_from_url("ssl://electrum.blockstream.info:50002") {
+            Err(Error::HeaderSourceError(message)) => assert!(message.contains("`electrum`")),
+            _ => panic!("expected a missing feature error"),
+        }
+    }
+
+    #[test]
+    fn cross_check_agreement() {
+        let genesis = genesis_block(Network::Bitcoin).header;
+        let mut other = genesis;
+        other.nonce += 1;
+        let agreed = CrossCheckSource::agree(0, vec![("a", genesis), ("b", genesis)]);
+        assert_eq!(agreed.unwrap(), genesis);
+        match CrossCheckSource::agree(0, vec![("a", genesis), ("b", other)]) {
+            Err(Error::HeaderSourceMismatch { height, details }) => {
+                assert_eq!(height, 0);
+                assert!(details.contains("b returned"));
+            }
+            _ => panic!("expected a mismatch"),
+        }
+        assert!(CrossCheckSource::agree(0, vec![]).is_err());
+    }
+
+    #[test]
+    fn cross_check_batches() {
+        let genesis = genesis_block(Network::Bitcoin).header;
+        let mut other = genesis;
+        other.nonce += 1;
+        let agreed = CrossCheckSource::agree_all(
+            5,
+            2,
+            vec![("a", vec![genesis, other]), ("b", vec![genesis, other])],
+        );
+        assert_eq!(agreed.unwrap(), vec![genesis, other]);
+        match CrossCheckSource::agree_all(
+            5,
+            2,
+            vec![("a", vec![genesis, other]), ("b", vec![genesis, genesis])],
+        ) {
+            Err(Error::HeaderSourceMismatch { height, .. }) => assert_eq!(height, 6),
+            _ => panic!("expected a mismatch"),
+        }
+        match CrossCheckSource::agree_all(5, 2, vec![("a", vec![genesis]), ("b", vec![])]) {
+            Err(Error::HeaderSourceError(message)) => assert!(message.contains("a returned 1")),
+            _ => panic!("expected missing headers"),
+        }
+    }
+}
@@ -3,17 +3,33 @@
 extern crate bitcoin_hashes;
 extern crate bitcoincore_rpc;
 extern crate chrono;
+extern crate chrono_tz;
 extern crate env_logger;
 extern crate log;
 pub extern crate opentimestamps;
 extern crate rand;
 extern crate reqwest;
 extern crate rs_merkle;
+extern crate serde;
+extern crate serde_json;
 extern crate thiserror;
 
+pub mod aggregator;
+pub mod anchor;
+pub mod atomic;
+pub mod attestation;
 pub mod client;
 pub mod error;
 pub mod extensions;
+pub mod git;
+pub mod header_cache;
+pub mod header_chain;
+pub mod headers;
+pub mod network;
+pub mod pending;
+pub mod receipt;
+pub mod scheduler;
+pub mod whitelist;
 
 #[cfg(not(feature = "async"))]
 extern crate electrum_client;
@@ -21,6 +37,8 @@ extern crate electrum_client;
 #[cfg(not(feature = "async"))]
 pub mod block_calendar;
 
+#[cfg(feature = "async")]
+extern crate async_trait;
 #[cfg(feature = "async")]
 extern crate esplora_client;
 
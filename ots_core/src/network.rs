@@ -0,0 +1,86 @@
+// Copyright (C) 2024 The OpenTimestamps developers
+
+#[cfg(feature = "async")]
+use crate::async_calendar::{APOOL, BPOOL, FINNEY};
+#[cfg(not(feature = "async"))]
+use crate::block_calendar::{APOOL, BPOOL, FINNEY};
+use crate::headers::{DEFAULT_ELECTRUM, DEFAULT_ESPLORA};
+
+pub use bitcoincore_rpc::bitcoin::Network;
+
+/// Calendars stamping submits to when none are given. Public calendars only
+/// run on mainnet, other networks need their own calendar.
+pub fn default_calendars(network: Network) -> Vec<String> {
+    match network {
+        Network::Bitcoin => vec![APOOL.to_string(), BPOOL.to_string(), FINNEY.to_string()],
+        _ => vec![],
+    }
+}
+
+/// Public Electrum server of the network, if any.
+pub fn default_electrum(network: Network) -> Option<&'static str> {
+    match network {
+        Network::Bitcoin => Some(DEFAULT_ELECTRUM),
+        Network::Testnet => Some("tcp://electrum.blockstream.info:60001"),
+        Network::Signet => Some("ssl://mempool.space:60602"),
+        _ => None,
+    }
+}
+
+/// Public Esplora API of the network, if any.
+pub fn default_esplora(network: Network) -> Option<&'static str> {
+    match network {
+        Network::Bitcoin => Some(DEFAULT_ESPLORA),
+        Network::Testnet => Some("https://blockstream.info/testnet/api"),
+        Network::Signet => Some("https://mempool.space/signet/api"),
+        _ => None,
+    }
+}
+
+/// Default JSON-RPC port of Bitcoin Core on the network.
+pub fn default_rpc_port(network: Network) -> u16 {
+    match network {
+        Network::Testnet => 18332,
+        Network::Signet => 38332,
+        Network::Regtest => 18443,
+        _ => 8332,
+    }
+}
+
+/// Highest proof-of-work target of the network, in compact form.
+pub(crate) fn pow_limit_bits(network: Network) -> u32 {
+    match network {
+        Network::Signet => 0x1e0377ae,
+        Network::Regtest => 0x207fffff,
+        _ => 0x1d00ffff,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoincore_rpc::bitcoin::blockdata::constants::genesis_block;
+
+    #[test]
+    fn pow_limit_of_genesis() {
+        for network in [
+            Network::Bitcoin,
+            Network::Testnet,
+            Network::Signet,
+            Network::Regtest,
+        ] {
+            let bits = genesis_block(network).header.bits.to_consensus();
+            assert_eq!(pow_limit_bits(network), bits, "{}", network);
+        }
+    }
+
+    #[test]
+    fn public_services() {
+        assert_eq!(default_calendars(Network::Bitcoin).len(), 3);
+        assert!(default_calendars(Network::Signet).is_empty());
+        assert!(default_electrum(Network::Regtest).is_none());
+        assert!(default_esplora(Network::Regtest).is_none());
+        assert_eq!(default_rpc_port(Network::Bitcoin), 8332);
+        assert_eq!(default_rpc_port(Network::Regtest), 18443);
+    }
+}
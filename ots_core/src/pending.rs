@@ -0,0 +1,252 @@
+// Copyright (C) 2024 The OpenTimestamps developers
+
+use crate::atomic;
+use crate::error::Error;
+
+use bitcoincore_rpc::bitcoin::hex::FromHex;
+use opentimestamps::hex::Hexed;
+use opentimestamps::DetachedTimestampFile;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+
+/// A timestamp tracked by the [`PendingStore`] until it gets a Bitcoin attestation.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PendingEntry {
+    /// Path of the `.ots` file the timestamp was loaded from, if any
+    pub path: Option<String>,
+    /// Hex-encoded serialized timestamp, updated on every successful upgrade
+    pub ots: String,
+    /// Unix time the entry was added
+    pub added: i64,
+    /// Number of upgrade attempts so far
+    pub attempts: u32,
+    /// Unix time of the last upgrade attempt
+    pub last_attempt: Option<i64>,
+    /// Unix time before which the entry should not be retried
+    pub next_attempt: i64,
+    /// Error of the last failed upgrade attempt
+    pub last_error: Option<String>,
+    /// Unix time the timestamp got a Bitcoin attestation
+    pub completed: Option<i64>,
+    /// Height of the attesting Bitcoin block
+    pub height: Option<u32>,
+}
+
+impl PendingEntry {
+    pub fn is_complete(&self) -> bool {
+        self.completed.is_some()
+    }
+
+    pub fn timestamp(&self) -> Result<DetachedTimestampFile, Error> {
+        decode(&self.ots)
+    }
+
+    /// Replace the stored timestamp, returning whether it changed.
+    pub fn set_timestamp(&mut self, ots: &DetachedTimestampFile) -> Result<bool, Error> {
+        let encoded = encode(ots)?;
+        let changed = encoded != self.ots;
+        self.ots = encoded;
+        Ok(changed)
+    }
+}
+
+/// File-based store of pending timestamps, keyed by the hex-encoded start
+/// digest and the path of their `.ots` file, see [`entry_id`].
+pub struct PendingStore {
+    path: PathBuf,
+    entries: BTreeMap<String, PendingEntry>,
+}
+
+impl PendingStore {
+    /// Open the store at `path`, starting empty if the file does not exist yet.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let path = path.as_ref().to_path_buf();
+        let entries = if path.exists() {
+            let content = fs::read_to_string(&path).map_err(|_| Error::IOError)?;
+            serde_json::from_str(&content).map_err(|_| Error::InvalidFile)?
+        } else {
+            BTreeMap::new()
+        };
+        Ok(PendingStore { path, entries })
+    }
+
+    /// Persist the store, replacing the previous content atomically.
+    pub fn save(&self) -> Result<(), Error> {
+        let content = serde_json::to_string_pretty(&self.entries)
+            .map_err(|e| Error::Generic(e.to_string()))?;
+        atomic::write_file(&self.path, content.as_bytes())
+    }
+
+    /// Track a timestamp, returning its id. Adding an already tracked timestamp
+    /// at the same path replaces its proof and reschedules it immediately.
+    pub fn add(
+        &mut self,
+        ots: &DetachedTimestampFile,
+        path: Option<String>,
+        now: i64,
+    ) -> Result<String, Error> {
+        let id = entry_id(&ots.timestamp.start_digest, path.as_deref());
+        let entry = PendingEntry {
+            path,
+            ots: encode(ots)?,
+            added: now,
+            attempts: 0,
+            last_attempt: None,
+            next_attempt: now,
+            last_error: None,
+            completed: None,
+            height: None,
+        };
+        self.entries.insert(id.clone(), entry);
+        Ok(id)
+    }
+
+    pub fn remove(&mut self, id: &str) -> Option<PendingEntry> {
+        self.entries.remove(id)
+    }
+
+    pub fn get(&self, id: &str) -> Option<&PendingEntry> {
+        self.entries.get(id)
+    }
+
+    pub fn get_mut(&mut self, id: &str) -> Option<&mut PendingEntry> {
+        self.entries.get_mut(id)
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = (&String, &PendingEntry)> {
+        self.entries.iter()
+    }
+
+    /// Ids of the entries still waiting for a Bitcoin attestation.
+    pub fn pending(&self) -> Vec<String> {
+        self.entries
+            .iter()
+            .filter(|(_, entry)| !entry.is_complete())
+            .map(|(id, _)| id.clone())
+            .collect()
+    }
+
+    /// Ids of the pending entries whose next attempt is due at `now`.
+    pub fn due(&self, now: i64) -> Vec<String> {
+        self.entries
+            .iter()
+            .filter(|(_, entry)| !entry.is_complete() && entry.next_attempt <= now)
+            .map(|(id, _)| id.clone())
+            .collect()
+    }
+}
+
+/// Id of the timestamp of `digest` written at `path`: the hex digest, followed
+/// by `:` and the path if any, so the timestamps of two files with the same
+/// content are tracked separately.
+pub fn entry_id(digest: &[u8], path: Option<&str>) -> String {
+    match path {
+        Some(path) => format!("{}:{}", Hexed(digest), path),
+        None => Hexed(digest).to_string(),
+    }
+}
+
+pub(crate) fn encode(ots: &DetachedTimestampFile) -> Result<String, Error> {
+    let mut buffer = vec![];
+    ots.to_writer(&mut buffer).map_err(Error::InvalidOts)?;
+    Ok(Hexed(&buffer).to_string())
+}
+
+pub(crate) fn decode(encoded: &str) -> Result<DetachedTimestampFile, Error> {
+    let bytes = Vec::<u8>::from_hex(encoded)
+        .map_err(|_| Error::Generic("Invalid pending timestamp".to_string()))?;
+    DetachedTimestampFile::from_reader(Cursor::new(bytes)).map_err(Error::InvalidOts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::anchor::linear_timestamp;
+    use opentimestamps::attestation::Attestation;
+    use opentimestamps::op::Op;
+    use opentimestamps::ser::DigestType;
+
+    fn pending_ots(digest: u8) -> DetachedTimestampFile {
+        DetachedTimestampFile {
+            digest_type: DigestType::Sha256,
+            timestamp: linear_timestamp(
+                vec![digest; 32],
+                vec![Op::Sha256],
+                Attestation::Pending {
+                    uri: "https://a.pool.opentimestamps.org".to_string(),
+                },
+            ),
+        }
+    }
+
+    fn store_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("ots-pending-{}-{}.json", name, std::process::id()))
+    }
+
+    #[test]
+    fn same_digest_at_different_paths() {
+        let mut store = PendingStore::open(store_path("paths")).unwrap();
+        let ots = pending_ots(1);
+        let a = store.add(&ots, Some("a.ots".to_string()), 0).unwrap();
+        let b = store.add(&ots, Some("b.ots".to_string()), 0).unwrap();
+        let bare = store.add(&ots, None, 0).unwrap();
+        assert_ne!(a, b);
+        assert_eq!(bare, Hexed(&[1u8; 32]).to_string());
+        assert_eq!(store.entries().count(), 3);
+        assert_eq!(store.get(&a).unwrap().path.as_deref(), Some("a.ots"));
+        assert_eq!(store.get(&b).unwrap().path.as_deref(), Some("b.ots"));
+    }
+
+    #[test]
+    fn same_path_replaces_entry() {
+        let mut store = PendingStore::open(store_path("replace")).unwrap();
+        let first = store
+            .add(&pending_ots(1), Some("a.ots".to_string()), 0)
+            .unwrap();
+        store.get_mut(&first).unwrap().attempts = 3;
+        let again = store
+            .add(&pending_ots(1), Some("a.ots".to_string()), 10)
+            .unwrap();
+        assert_eq!(first, again);
+        assert_eq!(store.entries().count(), 1);
+        assert_eq!(store.get(&again).unwrap().attempts, 0);
+        assert_eq!(store.get(&again).unwrap().next_attempt, 10);
+    }
+
+    #[test]
+    fn due_and_pending() {
+        let mut store = PendingStore::open(store_path("due")).unwrap();
+        let now = store.add(&pending_ots(1), None, 100).unwrap();
+        let later = store.add(&pending_ots(2), None, 100).unwrap();
+        let done = store.add(&pending_ots(3), None, 100).unwrap();
+        store.get_mut(&later).unwrap().next_attempt = 200;
+        store.get_mut(&done).unwrap().completed = Some(150);
+        assert_eq!(store.due(100), vec![now.clone()]);
+        let mut due = store.due(200);
+        due.sort();
+        let mut expected = vec![now.clone(), later.clone()];
+        expected.sort();
+        assert_eq!(due, expected);
+        assert_eq!(store.pending().len(), 2);
+        assert!(!store.pending().contains(&done));
+    }
+
+    #[test]
+    fn save_and_reopen() {
+        let path = store_path("reopen");
+        let mut store = PendingStore::open(&path).unwrap();
+        let ots = pending_ots(4);
+        let id = store.add(&ots, Some("x.ots".to_string()), 42).unwrap();
+        store.save().unwrap();
+
+        let store = PendingStore::open(&path).unwrap();
+        let entry = store.get(&id).unwrap();
+        assert_eq!(entry.added, 42);
+        assert_eq!(entry.path.as_deref(), Some("x.ots"));
+        assert_eq!(entry.ots, encode(&ots).unwrap());
+        fs::remove_file(path).unwrap();
+    }
+}
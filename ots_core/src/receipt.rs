@@ -0,0 +1,117 @@
+// Copyright (C) 2024 The OpenTimestamps developers
+
+use crate::atomic;
+use crate::error::Error;
+
+use opentimestamps::ser::DigestType;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+/// Tool recorded in receipts made by the library.
+pub const TOOL: &str = concat!("ots_core ", env!("CARGO_PKG_VERSION"));
+
+/// Size of the random nonce appended to each digest before aggregation.
+pub const NONCE_SIZE: usize = 16;
+
+/// Outcome of the submission of a merkle tip to a calendar.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CalendarSubmission {
+    pub url: String,
+    /// Whether the calendar returned a pending timestamp
+    pub accepted: bool,
+    /// Why the calendar was ignored
+    pub error: Option<String>,
+}
+
+/// Record of how a timestamp was made, written next to the `.ots` file for
+/// auditing the stamping process.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct StampReceipt {
+    /// Hex-encoded digest of the timestamped document
+    pub digest: String,
+    /// Algorithm of the digest: `sha256`, `sha1` or `ripemd160`
+    pub digest_algorithm: String,
+    /// How the digest was blinded before being aggregated
+    pub nonce_policy: String,
+    /// Hex-encoded merkle tip submitted to the calendars
+    pub merkle_tip: String,
+    /// Number of digests aggregated under the merkle tip
+    pub batch_size: usize,
+    /// ISO-8601 UTC time of the submission to the calendars
+    pub submitted: String,
+    /// Calendars contacted, in order
+    pub calendars: Vec<CalendarSubmission>,
+    /// Tool and version which made the timestamp
+    pub tool: String,
+}
+
+impl StampReceipt {
+    /// Path of the receipt of the timestamp at `ots_path`: the same name with
+    /// a `.json` extension appended.
+    pub fn sidecar_path(ots_path: &Path) -> PathBuf {
+        let mut path = ots_path.as_os_str().to_owned();
+        path.push(".json");
+        PathBuf::from(path)
+    }
+
+    /// Write the receipt as JSON, replacing the previous content atomically.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        let path = path.as_ref();
+        let content =
+            serde_json::to_string_pretty(self).map_err(|e| Error::Generic(e.to_string()))?;
+        atomic::write_file(path, content.as_bytes())
+    }
+}
+
+/// Name of a digest algorithm, as recorded in receipts.
+pub fn digest_algorithm(digest_type: DigestType) -> &'static str {
+    match digest_type {
+        DigestType::Sha1 => "sha1",
+        DigestType::Sha256 => "sha256",
+        DigestType::Ripemd160 => "ripemd160",
+    }
+}
+
+/// Nonce policy of [`crate::client::stamps`], as recorded in receipts.
+pub fn nonce_policy() -> String {
+    format!("append {} random bytes, then sha256", NONCE_SIZE)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn save_next_to_timestamp() {
+        let dir = std::env::temp_dir().join(format!("ots-receipt-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let ots_path = dir.join("x.ots");
+        // A file the temporary receipt must not clobber
+        fs::write(dir.join("x.ots.tmp"), b"other").unwrap();
+        let path = StampReceipt::sidecar_path(&ots_path);
+        assert_eq!(path, dir.join("x.ots.json"));
+        let receipt = StampReceipt {
+            digest: "00".repeat(32),
+            digest_algorithm: digest_algorithm(DigestType::Sha256).to_string(),
+            nonce_policy: nonce_policy(),
+            merkle_tip: "11".repeat(32),
+            batch_size: 1,
+            submitted: "2024-01-01T00:00:00Z".to_string(),
+            calendars: vec![CalendarSubmission {
+                url: "https://a.pool.opentimestamps.org".to_string(),
+                accepted: true,
+                error: None,
+            }],
+            tool: TOOL.to_string(),
+        };
+        receipt.save(&path).unwrap();
+        let saved: StampReceipt =
+            serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(saved.merkle_tip, receipt.merkle_tip);
+        assert!(saved.calendars[0].accepted);
+        assert_eq!(fs::read(dir.join("x.ots.tmp")).unwrap(), b"other");
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,370 @@
+// Copyright (C) 2024 The OpenTimestamps developers
+
+use crate::client::{self, UpgradeOutcome};
+use crate::error::Error;
+use crate::pending::{PendingEntry, PendingStore};
+use crate::whitelist::UrlWhitelist;
+
+use log::{debug, error, info, warn};
+use opentimestamps::DetachedTimestampFile;
+use std::time::Duration;
+
+/// Exponential backoff between upgrade attempts of the same timestamp.
+#[derive(Clone, Debug)]
+pub struct Backoff {
+    pub initial: Duration,
+    pub max: Duration,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff {
+            initial: Duration::from_secs(10 * 60),
+            max: Duration::from_secs(6 * 60 * 60),
+        }
+    }
+}
+
+impl Backoff {
+    /// Delay before the next attempt, given the attempts made so far.
+    pub fn delay(&self, attempts: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempts.saturating_sub(1));
+        self.initial.saturating_mul(factor).min(self.max)
+    }
+}
+
+/// Results of upgrade attempts, by entry id.
+pub type UpgradeResults = Vec<(
+    String,
+    Result<(DetachedTimestampFile, UpgradeOutcome), Error>,
+)>;
+
+/// Timestamps of the entries due for an upgrade, taken out of the store so
+/// that the calendars are queried without holding it.
+pub struct DueUpgrades {
+    calendar_urls: Option<Vec<String>>,
+    whitelist: UrlWhitelist,
+    entries: Vec<(String, DetachedTimestampFile)>,
+}
+
+impl DueUpgrades {
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+/// Periodically upgrades the timestamps of a [`PendingStore`] until they
+/// reach a Bitcoin attestation.
+pub struct Scheduler {
+    pub store: PendingStore,
+    pub backoff: Backoff,
+    pub calendar_urls: Option<Vec<String>>,
+    pub whitelist: UrlWhitelist,
+}
+
+impl Scheduler {
+    pub fn new(store: PendingStore) -> Self {
+        Scheduler {
+            store,
+            backoff: Backoff::default(),
+            calendar_urls: None,
+            whitelist: UrlWhitelist::default(),
+        }
+    }
+
+    /// Record the upgrade of `id` attempted at `now`, calling `write` with
+    /// the entry when its timestamp changed. The entry only completes once
+    /// written: a failed write keeps the previous timestamp and the error, so
+    /// that the next attempt writes the upgrade again. Returns whether the
+    /// timestamp changed and was written.
+    fn record<F>(
+        &mut self,
+        id: &str,
+        now: i64,
+        result: Result<(DetachedTimestampFile, UpgradeOutcome), Error>,
+        write: &mut F,
+    ) -> Result<bool, Error>
+    where
+        F: FnMut(&str, &PendingEntry) -> Result<(), Error>,
+    {
+        let backoff = self.backoff.clone();
+        let entry = self
+            .store
+            .get_mut(id)
+            .ok_or(Error::Generic(format!("Unknown pending timestamp {}", id)))?;
+        entry.attempts += 1;
+        entry.last_attempt = Some(now);
+        entry.next_attempt = now + backoff.delay(entry.attempts).as_secs() as i64;
+        let (ots, outcome) = match result {
+            Ok(result) => result,
+            Err(e) => {
+                warn!("Upgrade of {} failed: {}", id, e);
+                entry.last_error = Some(e.to_string());
+                return Ok(false);
+            }
+        };
+        entry.last_error = if outcome.errors.is_empty() {
+            None
+        } else {
+            let errors: Vec<String> = outcome
+                .errors
+                .iter()
+                .map(|(uri, e)| format!("{}: {}", uri, e))
+                .collect();
+            Some(errors.join(", "))
+        };
+        let previous = entry.timestamp()?;
+        let changed = entry.set_timestamp(&ots)?;
+        if changed {
+            if let Err(e) = write(id, entry) {
+                warn!("Could not write the upgrade of {}: {}", id, e);
+                entry.set_timestamp(&previous)?;
+                entry.last_error = Some(format!("Could not write the upgrade: {}", e));
+                return Ok(false);
+            }
+        }
+        if let Some(height) = client::bitcoin_height(&ots) {
+            info!("Timestamp {} attested by Bitcoin block {}", id, height);
+            entry.completed = Some(now);
+            entry.height = Some(height);
+        } else {
+            debug!(
+                "Timestamp {} still pending, next attempt at {}",
+                id, entry.next_attempt
+            );
+        }
+        Ok(changed)
+    }
+
+    /// Timestamps of the entries due at `now`, to be upgraded with
+    /// [`DueUpgrades::run`] and recorded with [`Scheduler::record_all`].
+    pub fn due(&self, now: i64) -> Result<DueUpgrades, Error> {
+        let mut entries = vec![];
+        for id in self.store.due(now) {
+            let ots = self.store.get(&id).unwrap().timestamp()?;
+            entries.push((id, ots));
+        }
+        Ok(DueUpgrades {
+            calendar_urls: self.calendar_urls.clone(),
+            whitelist: self.whitelist.clone(),
+            entries,
+        })
+    }
+
+    /// Record the upgrades attempted at `now`, calling `write` with each
+    /// entry whose timestamp changed before it can complete, and persist the
+    /// store, returning the ids of the entries written.
+    pub fn record_all<F>(
+        &mut self,
+        now: i64,
+        results: UpgradeResults,
+        mut write: F,
+    ) -> Result<Vec<String>, Error>
+    where
+        F: FnMut(&str, &PendingEntry) -> Result<(), Error>,
+    {
+        let mut changed = vec![];
+        for (id, result) in results {
+            if self.record(&id, now, result, &mut write)? {
+                changed.push(id);
+            }
+        }
+        self.store.save()?;
+        Ok(changed)
+    }
+}
+
+#[cfg(not(feature = "async"))]
+impl DueUpgrades {
+    /// Query the calendars for the upgrades of the due timestamps.
+    pub fn run(self) -> UpgradeResults {
+        let mut results = vec![];
+        for (id, mut ots) in self.entries {
+            let result = client::upgrade(&mut ots, self.calendar_urls.clone(), &self.whitelist)
+                .map(|outcome| (ots, outcome));
+            results.push((id, result));
+        }
+        results
+    }
+}
+
+#[cfg(feature = "async")]
+impl DueUpgrades {
+    /// Query the calendars for the upgrades of the due timestamps.
+    pub async fn run(self) -> UpgradeResults {
+        let mut results = vec![];
+        for (id, mut ots) in self.entries {
+            let result = client::upgrade(&mut ots, self.calendar_urls.clone(), &self.whitelist)
+                .await
+                .map(|outcome| (ots, outcome));
+            results.push((id, result));
+        }
+        results
+    }
+}
+
+#[cfg(not(feature = "async"))]
+impl Scheduler {
+    /// Attempt an upgrade of every entry due at `now`, writing the changed
+    /// ones with `write` as [`Scheduler::record_all`], returning their ids.
+    pub fn tick<F>(&mut self, now: i64, write: F) -> Result<Vec<String>, Error>
+    where
+        F: FnMut(&str, &PendingEntry) -> Result<(), Error>,
+    {
+        let results = self.due(now)?.run();
+        self.record_all(now, results, write)
+    }
+
+    /// Run until every entry is complete, writing each entry whose timestamp
+    /// changed with `write`. A round that fails is logged and retried after
+    /// `interval`.
+    pub fn run<F>(&mut self, interval: Duration, mut write: F)
+    where
+        F: FnMut(&str, &PendingEntry) -> Result<(), Error>,
+    {
+        while !self.store.pending().is_empty() {
+            let now = chrono::Utc::now().timestamp();
+            if let Err(e) = self.tick(now, &mut write) {
+                error!("Upgrade round failed: {}", e);
+            }
+            std::thread::sleep(interval);
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+impl Scheduler {
+    /// Attempt an upgrade of every entry due at `now`, writing the changed
+    /// ones with `write` as [`Scheduler::record_all`], returning their ids.
+    pub async fn tick<F>(&mut self, now: i64, write: F) -> Result<Vec<String>, Error>
+    where
+        F: FnMut(&str, &PendingEntry) -> Result<(), Error>,
+    {
+        let results = self.due(now)?.run().await;
+        self.record_all(now, results, write)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::anchor::linear_timestamp;
+    use opentimestamps::attestation::Attestation;
+    use opentimestamps::op::Op;
+    use opentimestamps::ser::DigestType;
+
+    fn scheduler(name: &str) -> (Scheduler, String) {
+        let path = std::env::temp_dir().join(format!(
+            "ots-scheduler-{}-{}.json",
+            name,
+            std::process::id()
+        ));
+        let mut store = PendingStore::open(path).unwrap();
+        let ots = DetachedTimestampFile {
+            digest_type: DigestType::Sha256,
+            timestamp: linear_timestamp(
+                vec![7; 32],
+                vec![Op::Sha256],
+                Attestation::Pending {
+                    uri: "https://a.pool.opentimestamps.org".to_string(),
+                },
+            ),
+        };
+        let id = store.add(&ots, Some("a.ots".to_string()), 0).unwrap();
+        (Scheduler::new(store), id)
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_max() {
+        let backoff = Backoff {
+            initial: Duration::from_secs(10),
+            max: Duration::from_secs(100),
+        };
+        assert_eq!(backoff.delay(0), Duration::from_secs(10));
+        assert_eq!(backoff.delay(1), Duration::from_secs(10));
+        assert_eq!(backoff.delay(2), Duration::from_secs(20));
+        assert_eq!(backoff.delay(3), Duration::from_secs(40));
+        assert_eq!(backoff.delay(4), Duration::from_secs(80));
+        assert_eq!(backoff.delay(5), Duration::from_secs(100));
+        assert_eq!(backoff.delay(u32::MAX), Duration::from_secs(100));
+    }
+
+    #[test]
+    fn failed_attempts_back_off() {
+        let (mut scheduler, id) = scheduler("backoff");
+        let failure = || Err(Error::Generic("unreachable".to_string()));
+        assert!(!scheduler
+            .record(&id, 1000, failure(), &mut |_, _| Ok(()))
+            .unwrap());
+        let entry = scheduler.store.get(&id).unwrap();
+        assert_eq!(entry.attempts, 1);
+        assert_eq!(entry.next_attempt, 1000 + 10 * 60);
+        assert!(entry.last_error.is_some());
+
+        assert!(!scheduler
+            .record(&id, 2000, failure(), &mut |_, _| Ok(()))
+            .unwrap());
+        let entry = scheduler.store.get(&id).unwrap();
+        assert_eq!(entry.attempts, 2);
+        assert_eq!(entry.next_attempt, 2000 + 20 * 60);
+        assert!(scheduler.store.due(2000).is_empty());
+        assert_eq!(scheduler.store.due(2000 + 20 * 60), vec![id]);
+    }
+
+    #[test]
+    fn unknown_entry() {
+        let (mut scheduler, _) = scheduler("unknown");
+        assert!(scheduler
+            .record("missing", 0, Err(Error::IOError), &mut |_, _| Ok(()))
+            .is_err());
+    }
+
+    #[test]
+    fn completes_once_written() {
+        let (mut scheduler, id) = scheduler("write");
+        let pending = scheduler.store.get(&id).unwrap().timestamp().unwrap();
+        let attested = || {
+            let ots = DetachedTimestampFile {
+                digest_type: DigestType::Sha256,
+                timestamp: linear_timestamp(
+                    vec![7; 32],
+                    vec![Op::Sha256],
+                    Attestation::Bitcoin { height: 100 },
+                ),
+            };
+            let outcome = UpgradeOutcome {
+                upgraded: vec![],
+                pending: vec![],
+                skipped: vec![],
+                errors: vec![],
+            };
+            Ok((ots, outcome))
+        };
+        let mut failing = |_: &str, _: &PendingEntry| Err(Error::IOError);
+        assert!(!scheduler
+            .record(&id, 1000, attested(), &mut failing)
+            .unwrap());
+        let entry = scheduler.store.get(&id).unwrap();
+        assert!(entry.height.is_none());
+        assert!(entry.last_error.is_some());
+        assert_eq!(serialize(&entry.timestamp().unwrap()), serialize(&pending));
+
+        let mut written = vec![];
+        let mut write = |id: &str, _: &PendingEntry| {
+            written.push(id.to_string());
+            Ok(())
+        };
+        assert!(scheduler.record(&id, 2000, attested(), &mut write).unwrap());
+        assert_eq!(written, vec![id.clone()]);
+        let entry = scheduler.store.get(&id).unwrap();
+        assert_eq!(entry.height, Some(100));
+        assert_eq!(entry.completed, Some(2000));
+        assert!(entry.last_error.is_none());
+    }
+
+    fn serialize(ots: &DetachedTimestampFile) -> Vec<u8> {
+        let mut bytes = vec![];
+        ots.to_writer(&mut bytes).unwrap();
+        bytes
+    }
+}
@@ -0,0 +1,180 @@
+// Copyright (C) 2024 The OpenTimestamps developers
+
+use reqwest::Url;
+
+/// Calendars trusted by default when upgrading pending attestations.
+pub const DEFAULT_CALENDAR_WHITELIST: [&str; 4] = [
+    "https://*.calendar.opentimestamps.org",
+    "https://*.calendar.eternitywall.com",
+    "https://*.calendar.catallaxy.com",
+    "https://ots.btc.catallaxy.com",
+];
+
+/// Policy deciding which calendar URIs found in a timestamp may be contacted.
+///
+/// Patterns are matched against the host of the URI, either as a glob where
+/// `*` matches any sequence of characters (`https://*.example.com`) or, when
+/// starting with a dot, as a domain suffix (`.example.com`). A pattern with a
+/// scheme only matches URIs with the same scheme.
+#[derive(Clone, Debug)]
+pub struct UrlWhitelist {
+    patterns: Vec<String>,
+    /// Reject calendars not served over HTTPS
+    pub https_only: bool,
+    /// Accept any calendar, whitelisted or not
+    pub allow_unknown: bool,
+}
+
+impl Default for UrlWhitelist {
+    fn default() -> Self {
+        let mut whitelist = UrlWhitelist::new();
+        for pattern in DEFAULT_CALENDAR_WHITELIST {
+            whitelist.add(pattern);
+        }
+        whitelist
+    }
+}
+
+impl UrlWhitelist {
+    /// An empty whitelist, rejecting every calendar.
+    pub fn new() -> Self {
+        UrlWhitelist {
+            patterns: vec![],
+            https_only: true,
+            allow_unknown: false,
+        }
+    }
+
+    pub fn add(&mut self, pattern: &str) {
+        self.patterns
+            .push(pattern.trim_end_matches('/').to_lowercase());
+    }
+
+    pub fn patterns(&self) -> &[String] {
+        &self.patterns
+    }
+
+    /// Whether `uri` is a valid URL meeting the HTTPS requirement, whether
+    /// whitelisted or not.
+    pub fn is_secure(&self, uri: &str) -> bool {
+        match Url::parse(uri) {
+            Ok(url) => !self.https_only || url.scheme() == "https",
+            Err(_) => false,
+        }
+    }
+
+    pub fn contains(&self, uri: &str) -> bool {
+        if !self.is_secure(uri) {
+            return false;
+        }
+        let Ok(url) = Url::parse(uri) else {
+            return false;
+        };
+        if self.allow_unknown {
+            return true;
+        }
+        let Some(host) = url.host_str() else {
+            return false;
+        };
+        self.patterns.iter().any(|pattern| {
+            let host_pattern = match pattern.split_once("://") {
+                Some((scheme, _)) if scheme != url.scheme() => return false,
+                Some((_, host_pattern)) => host_pattern,
+                None => pattern.as_str(),
+            };
+            match host_pattern.strip_prefix('.') {
+                Some(suffix) => host == suffix || host.ends_with(host_pattern),
+                None => glob_match(host_pattern, host),
+            }
+        })
+    }
+}
+
+fn glob_match(pattern: &str, text: &str) -> bool {
+    match pattern.split_once('*') {
+        None => pattern == text,
+        Some((prefix, rest)) => {
+            let Some(text) = text.strip_prefix(prefix) else {
+                return false;
+            };
+            (0..=text.len())
+                .filter(|i| text.is_char_boundary(*i))
+                .any(|i| glob_match(rest, &text[i..]))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn whitelist(patterns: &[&str]) -> UrlWhitelist {
+        let mut whitelist = UrlWhitelist::new();
+        for pattern in patterns {
+            whitelist.add(pattern);
+        }
+        whitelist
+    }
+
+    #[test]
+    fn default_calendars() {
+        let whitelist = UrlWhitelist::default();
+        assert!(whitelist.contains("https://alice.btc.calendar.opentimestamps.org"));
+        assert!(whitelist.contains("https://finney.calendar.eternitywall.com"));
+        assert!(whitelist.contains("https://ots.btc.catallaxy.com"));
+        assert!(!whitelist.contains("https://calendar.opentimestamps.org.evil.com"));
+        assert!(!whitelist.contains("https://evilcalendar.opentimestamps.org"));
+        assert!(!whitelist.contains("http://alice.btc.calendar.opentimestamps.org"));
+    }
+
+    #[test]
+    fn glob_patterns() {
+        let whitelist = whitelist(&["https://*.example.com", "https://a*z.test"]);
+        assert!(whitelist.contains("https://cal.example.com"));
+        assert!(whitelist.contains("https://a.b.example.com/path"));
+        assert!(!whitelist.contains("https://example.com"));
+        assert!(!whitelist.contains("https://cal.example.com.org"));
+        assert!(whitelist.contains("https://az.test"));
+        assert!(whitelist.contains("https://abcz.test"));
+        assert!(!whitelist.contains("https://abc.test"));
+        assert!(glob_match("*", "anything"));
+        assert!(glob_match("a*b*c", "aXbYc"));
+        assert!(!glob_match("a*b*c", "aXcYb"));
+    }
+
+    #[test]
+    fn suffix_patterns() {
+        let whitelist = whitelist(&[".example.com"]);
+        assert!(whitelist.contains("https://example.com"));
+        assert!(whitelist.contains("https://cal.example.com"));
+        assert!(!whitelist.contains("https://badexample.com"));
+        assert!(!whitelist.contains("https://example.com.evil.org"));
+    }
+
+    #[test]
+    fn pattern_scheme_and_case() {
+        let mut whitelist = whitelist(&["HTTP://Cal.Example.com/"]);
+        whitelist.https_only = false;
+        assert!(whitelist.contains("http://cal.example.com"));
+        assert!(!whitelist.contains("https://cal.example.com"));
+    }
+
+    #[test]
+    fn https_only() {
+        let mut whitelist = whitelist(&[".example.com"]);
+        assert!(!whitelist.is_secure("http://cal.example.com"));
+        assert!(!whitelist.is_secure("not a url"));
+        assert!(whitelist.is_secure("https://unknown.org"));
+        whitelist.allow_unknown = true;
+        assert!(whitelist.contains("https://unknown.org"));
+        assert!(!whitelist.contains("http://unknown.org"));
+        whitelist.https_only = false;
+        assert!(whitelist.is_secure("http://cal.example.com"));
+        assert!(whitelist.contains("http://unknown.org"));
+    }
+
+    #[test]
+    fn empty_rejects_everything() {
+        assert!(!UrlWhitelist::new().contains("https://cal.example.com"));
+    }
+}
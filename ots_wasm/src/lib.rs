@@ -8,34 +8,48 @@ extern crate wasm_bindgen_futures;
 pub mod error;
 
 use error::Error;
+use ots_core::attestation::VerifierRegistry;
 use ots_core::client;
+use ots_core::network::Network;
 use ots_core::opentimestamps::{ser::DigestType, DetachedTimestampFile};
+use ots_core::whitelist::UrlWhitelist;
 
 use std::io::BufWriter;
 use std::io::Cursor;
 use std::io::Write;
 use wasm_bindgen::prelude::*;
 
-#[wasm_bindgen]
+#[wasm_bindgen(getter_with_clone)]
 pub struct BitcoinAttestationResult {
     pub height: u32,
     pub time: u32,
+    pub median_time_past: Option<u32>,
+    pub block_hash: String,
+    pub tip_height: u32,
+    pub confirmations: u32,
+    pub provisional: bool,
 }
 
 #[wasm_bindgen]
 pub async fn stamp(digest: String) -> Result<String, Error> {
     let digest = hex::decode(digest).map_err(|_| Error::Generic(String::from("Invalid digest")))?;
     let mut buf = BufWriter::new(Vec::new());
-    client::stamps(vec![digest], DigestType::Sha256, None, None)
-        .await
-        .map_err(|_| Error::Generic(String::from("OTS Stamp error")))
-        .unwrap()
-        .first()
-        .map(|ots| {
-            let _ = ots.to_writer(buf.by_ref());
-            hex::encode(&buf.into_inner().unwrap())
-        })
-        .ok_or(Error::Generic(String::from("OTS error")))
+    client::stamps(
+        vec![digest],
+        DigestType::Sha256,
+        None,
+        None,
+        Network::Bitcoin,
+    )
+    .await
+    .map_err(|_| Error::Generic(String::from("OTS Stamp error")))
+    .unwrap()
+    .first()
+    .map(|ots| {
+        let _ = ots.to_writer(buf.by_ref());
+        hex::encode(&buf.into_inner().unwrap())
+    })
+    .ok_or(Error::Generic(String::from("OTS error")))
 }
 
 #[wasm_bindgen]
@@ -51,7 +65,7 @@ pub async fn upgrade(ots: String) -> Result<String, Error> {
     let bytes = hex::decode(ots).map_err(|_| Error::Generic(String::from("Invalid param")))?;
     let cursor = Cursor::new(bytes);
     let mut ots: DetachedTimestampFile = DetachedTimestampFile::from_reader(cursor).unwrap();
-    let _ = client::upgrade(&mut ots, None)
+    let _ = client::upgrade(&mut ots, None, &UrlWhitelist::default())
         .await
         .map_err(|_| Error::Generic(String::from("OTS upgrade error")));
     let mut buf = BufWriter::new(Vec::new());
@@ -64,11 +78,57 @@ pub async fn verify(ots: String) -> Result<BitcoinAttestationResult, Error> {
     let bytes = hex::decode(ots).map_err(|_| Error::Generic(String::from("Invalid param")))?;
     let cursor = Cursor::new(bytes);
     let ots = DetachedTimestampFile::from_reader(cursor).unwrap();
-    let att = client::verify(ots, None)
+    verify_attestation(ots).await
+}
+
+#[wasm_bindgen]
+pub async fn verify_file(ots: String, file: Vec<u8>) -> Result<BitcoinAttestationResult, Error> {
+    let bytes = hex::decode(ots).map_err(|_| Error::Generic(String::from("Invalid param")))?;
+    let cursor = Cursor::new(bytes);
+    let ots = DetachedTimestampFile::from_reader(cursor)
+        .map_err(|_| Error::Generic(String::from("Invalid timestamp")))?;
+    client::verify_file(&ots, Cursor::new(file)).map_err(|e| Error::Generic(e.to_string()))?;
+    verify_attestation(ots).await
+}
+
+#[wasm_bindgen]
+pub async fn verify_digest(ots: String, digest: String) -> Result<BitcoinAttestationResult, Error> {
+    let bytes = hex::decode(ots).map_err(|_| Error::Generic(String::from("Invalid param")))?;
+    let digest = hex::decode(digest).map_err(|_| Error::Generic(String::from("Invalid digest")))?;
+    let cursor = Cursor::new(bytes);
+    let ots = DetachedTimestampFile::from_reader(cursor)
+        .map_err(|_| Error::Generic(String::from("Invalid timestamp")))?;
+    client::verify_digest(&ots, &digest).map_err(|e| Error::Generic(e.to_string()))?;
+    verify_attestation(ots).await
+}
+
+/// Verify the Bitcoin attestation and the Litecoin ones, against the given
+/// Litecoin header sources, returning the verified attestations as an object.
+#[wasm_bindgen]
+pub async fn verify_chains(ots: String, litecoin_sources: Vec<String>) -> Result<JsValue, Error> {
+    let bytes = hex::decode(ots).map_err(|_| Error::Generic(String::from("Invalid param")))?;
+    let cursor = Cursor::new(bytes);
+    let ots = DetachedTimestampFile::from_reader(cursor)
+        .map_err(|_| Error::Generic(String::from("Invalid timestamp")))?;
+    let registry = VerifierRegistry::with_litecoin_sources(&litecoin_sources)
+        .map_err(|e| Error::Generic(e.to_string()))?;
+    let verification = client::verify_chains(ots, None, Network::Bitcoin, &registry)
+        .await
+        .map_err(|e| Error::Generic(e.to_string()))?;
+    serde_wasm_bindgen::to_value(&verification).map_err(|e| Error::Generic(e.to_string()))
+}
+
+async fn verify_attestation(ots: DetachedTimestampFile) -> Result<BitcoinAttestationResult, Error> {
+    let att = client::verify(ots, None, Network::Bitcoin)
         .await
         .map_err(|_| Error::Generic(String::from("OTS error")))?;
     Ok(BitcoinAttestationResult {
         height: att.height,
         time: att.time,
+        median_time_past: att.median_time_past,
+        block_hash: att.block_hash,
+        tip_height: att.tip_height,
+        confirmations: att.confirmations,
+        provisional: att.provisional,
     })
 }